@@ -1,11 +1,597 @@
-use std::future::Future;
+use std::{future::Future, time::Duration};
 
 pub trait AudioSource {
     fn next(&mut self) -> impl Future<Output = Option<f32>> + Send;
     fn sample_rate(&self) -> u32;
     fn channels(&self) -> u16;
+
+    /// Fills `buffer` with the next samples and returns how many were written. Fewer than
+    /// `buffer.len()` means the source has nothing more.
+    ///
+    /// Sources that produce audio in blocks should override this, since awaiting one
+    /// future per sample is slow.
+    fn fill(&mut self, buffer: &mut [f32]) -> impl Future<Output = usize> + Send
+    where
+        Self: Send,
+    {
+        async move {
+            for (filled, slot) in buffer.iter_mut().enumerate() {
+                match self.next().await {
+                    Some(sample) => *slot = sample,
+                    None => return filled,
+                }
+            }
+            buffer.len()
+        }
+    }
 }
 
 pub trait SyncAudioSource: AudioSource {
     fn next_sync(&mut self) -> Option<f32>;
+
+    /// Like [`AudioSource::fill`], without waiting.
+    fn fill_sync(&mut self, buffer: &mut [f32]) -> usize {
+        for (filled, slot) in buffer.iter_mut().enumerate() {
+            match self.next_sync() {
+                Some(sample) => *slot = sample,
+                None => return filled,
+            }
+        }
+        buffer.len()
+    }
+}
+
+/// Adapters for building pipelines out of sources instead of sample loops.
+pub trait AudioSourceExt: AudioSource + Sized {
+    /// Multiplies every sample by `gain`.
+    fn gain(self, gain: f32) -> Gain<Self> {
+        Gain { source: self, gain }
+    }
+
+    /// Passes every sample through `f`.
+    fn map<F: FnMut(f32) -> f32 + Send>(self, f: F) -> Map<Self, F> {
+        Map { source: self, f }
+    }
+
+    /// Output channel `i` carries input channel `mapping[i]`, or silence for `None`.
+    /// `mapping` must not be empty.
+    fn remap_channels(self, mapping: Vec<Option<u16>>) -> ChannelRemap<Self> {
+        assert!(!mapping.is_empty(), "Cannot remap to no channels.");
+        ChannelRemap {
+            source: self,
+            mapping,
+            frame: Vec::new(),
+            channel: 0,
+        }
+    }
+
+    /// Ends after `duration`, or earlier if the source does.
+    fn take_duration(self, duration: Duration) -> Take<Self> {
+        let frames = (duration.as_secs_f64() * self.sample_rate() as f64).round() as u64;
+        Take {
+            remaining: frames * self.channels() as u64,
+            source: self,
+        }
+    }
+
+    /// Plays `next` once this source ends. Both must have the same rate and channels.
+    fn chain<B: AudioSource>(self, next: B) -> Chain<Self, B> {
+        assert!(
+            self.sample_rate() == next.sample_rate() && self.channels() == next.channels(),
+            "Cannot chain sources of different formats."
+        );
+        Chain {
+            first: self,
+            second: next,
+            first_ended: false,
+        }
+    }
+
+    /// Shows every sample to `f` as it passes through, e.g. to record it.
+    fn inspect<F: FnMut(f32) + Send>(self, f: F) -> Inspect<Self, F> {
+        Inspect { source: self, f }
+    }
+}
+
+impl<S: AudioSource> AudioSourceExt for S {}
+
+/// Sums several sources with the same rate and channels. Sources that end fall silent;
+/// the mix ends once they all have.
+pub struct Mix<S> {
+    sources: Vec<S>,
+}
+
+impl<S: AudioSource> Mix<S> {
+    /// `sources` must not be empty.
+    pub fn new(sources: Vec<S>) -> Mix<S> {
+        assert!(!sources.is_empty(), "Cannot mix no sources.");
+        Mix { sources }
+    }
+}
+
+impl<S: AudioSource + Send> AudioSource for Mix<S> {
+    async fn next(&mut self) -> Option<f32> {
+        let mut mixed = None;
+        for source in self.sources.iter_mut() {
+            if let Some(sample) = source.next().await {
+                *mixed.get_or_insert(0.0) += sample;
+            }
+        }
+        mixed
+    }
+    fn sample_rate(&self) -> u32 {
+        self.sources[0].sample_rate()
+    }
+    fn channels(&self) -> u16 {
+        self.sources[0].channels()
+    }
+}
+
+impl<S: SyncAudioSource + Send> SyncAudioSource for Mix<S> {
+    fn next_sync(&mut self) -> Option<f32> {
+        let mut mixed = None;
+        for source in self.sources.iter_mut() {
+            if let Some(sample) = source.next_sync() {
+                *mixed.get_or_insert(0.0) += sample;
+            }
+        }
+        mixed
+    }
+}
+
+pub struct Gain<S> {
+    source: S,
+    gain: f32,
+}
+
+impl<S: AudioSource + Send> AudioSource for Gain<S> {
+    async fn next(&mut self) -> Option<f32> {
+        Some(self.source.next().await? * self.gain)
+    }
+    async fn fill(&mut self, buffer: &mut [f32]) -> usize {
+        let filled = self.source.fill(buffer).await;
+        for sample in &mut buffer[..filled] {
+            *sample *= self.gain;
+        }
+        filled
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+}
+
+impl<S: SyncAudioSource + Send> SyncAudioSource for Gain<S> {
+    fn next_sync(&mut self) -> Option<f32> {
+        Some(self.source.next_sync()? * self.gain)
+    }
+    fn fill_sync(&mut self, buffer: &mut [f32]) -> usize {
+        let filled = self.source.fill_sync(buffer);
+        for sample in &mut buffer[..filled] {
+            *sample *= self.gain;
+        }
+        filled
+    }
+}
+
+pub struct Map<S, F> {
+    source: S,
+    f: F,
+}
+
+impl<S: AudioSource + Send, F: FnMut(f32) -> f32 + Send> AudioSource for Map<S, F> {
+    async fn next(&mut self) -> Option<f32> {
+        let sample = self.source.next().await?;
+        Some((self.f)(sample))
+    }
+    async fn fill(&mut self, buffer: &mut [f32]) -> usize {
+        let filled = self.source.fill(buffer).await;
+        for sample in &mut buffer[..filled] {
+            *sample = (self.f)(*sample);
+        }
+        filled
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+}
+
+impl<S: SyncAudioSource + Send, F: FnMut(f32) -> f32 + Send> SyncAudioSource for Map<S, F> {
+    fn next_sync(&mut self) -> Option<f32> {
+        let sample = self.source.next_sync()?;
+        Some((self.f)(sample))
+    }
+    fn fill_sync(&mut self, buffer: &mut [f32]) -> usize {
+        let filled = self.source.fill_sync(buffer);
+        for sample in &mut buffer[..filled] {
+            *sample = (self.f)(*sample);
+        }
+        filled
+    }
+}
+
+pub struct ChannelRemap<S> {
+    source: S,
+    mapping: Vec<Option<u16>>,
+    /// The input frame being remapped.
+    frame: Vec<f32>,
+    /// Next output channel to return.
+    channel: usize,
+}
+
+impl<S> ChannelRemap<S> {
+    fn remapped(&mut self) -> f32 {
+        let sample = match self.mapping[self.channel] {
+            Some(input_channel) => self
+                .frame
+                .get(input_channel as usize)
+                .copied()
+                .unwrap_or(0.0),
+            None => 0.0,
+        };
+        self.channel = (self.channel + 1) % self.mapping.len();
+        sample
+    }
+}
+
+impl<S: AudioSource + Send> AudioSource for ChannelRemap<S> {
+    async fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            self.frame.clear();
+            for _ in 0..self.source.channels() {
+                self.frame.push(self.source.next().await?);
+            }
+        }
+        Some(self.remapped())
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn channels(&self) -> u16 {
+        self.mapping.len() as u16
+    }
+}
+
+impl<S: SyncAudioSource + Send> SyncAudioSource for ChannelRemap<S> {
+    fn next_sync(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            self.frame.clear();
+            for _ in 0..self.source.channels() {
+                self.frame.push(self.source.next_sync()?);
+            }
+        }
+        Some(self.remapped())
+    }
+}
+
+pub struct Take<S> {
+    source: S,
+    /// Samples left, across all channels.
+    remaining: u64,
+}
+
+impl<S: AudioSource + Send> AudioSource for Take<S> {
+    async fn next(&mut self) -> Option<f32> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        self.source.next().await
+    }
+    async fn fill(&mut self, buffer: &mut [f32]) -> usize {
+        let wanted = std::cmp::min(buffer.len() as u64, self.remaining) as usize;
+        let filled = self.source.fill(&mut buffer[..wanted]).await;
+        self.remaining -= filled as u64;
+        filled
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+}
+
+impl<S: SyncAudioSource + Send> SyncAudioSource for Take<S> {
+    fn next_sync(&mut self) -> Option<f32> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        self.source.next_sync()
+    }
+    fn fill_sync(&mut self, buffer: &mut [f32]) -> usize {
+        let wanted = std::cmp::min(buffer.len() as u64, self.remaining) as usize;
+        let filled = self.source.fill_sync(&mut buffer[..wanted]);
+        self.remaining -= filled as u64;
+        filled
+    }
+}
+
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+    first_ended: bool,
+}
+
+impl<A: AudioSource + Send, B: AudioSource + Send> AudioSource for Chain<A, B> {
+    async fn next(&mut self) -> Option<f32> {
+        if !self.first_ended {
+            match self.first.next().await {
+                Some(sample) => return Some(sample),
+                None => self.first_ended = true,
+            }
+        }
+        self.second.next().await
+    }
+    async fn fill(&mut self, buffer: &mut [f32]) -> usize {
+        let mut filled = 0;
+        if !self.first_ended {
+            filled = self.first.fill(buffer).await;
+            if filled == buffer.len() {
+                return filled;
+            }
+            self.first_ended = true;
+        }
+        filled + self.second.fill(&mut buffer[filled..]).await
+    }
+    fn sample_rate(&self) -> u32 {
+        self.first.sample_rate()
+    }
+    fn channels(&self) -> u16 {
+        self.first.channels()
+    }
+}
+
+impl<A: SyncAudioSource + Send, B: SyncAudioSource + Send> SyncAudioSource for Chain<A, B> {
+    fn next_sync(&mut self) -> Option<f32> {
+        if !self.first_ended {
+            match self.first.next_sync() {
+                Some(sample) => return Some(sample),
+                None => self.first_ended = true,
+            }
+        }
+        self.second.next_sync()
+    }
+    fn fill_sync(&mut self, buffer: &mut [f32]) -> usize {
+        let mut filled = 0;
+        if !self.first_ended {
+            filled = self.first.fill_sync(buffer);
+            if filled == buffer.len() {
+                return filled;
+            }
+            self.first_ended = true;
+        }
+        filled + self.second.fill_sync(&mut buffer[filled..])
+    }
+}
+
+pub struct Inspect<S, F> {
+    source: S,
+    f: F,
+}
+
+impl<S: AudioSource + Send, F: FnMut(f32) + Send> AudioSource for Inspect<S, F> {
+    async fn next(&mut self) -> Option<f32> {
+        let sample = self.source.next().await?;
+        (self.f)(sample);
+        Some(sample)
+    }
+    async fn fill(&mut self, buffer: &mut [f32]) -> usize {
+        let filled = self.source.fill(buffer).await;
+        for sample in &buffer[..filled] {
+            (self.f)(*sample);
+        }
+        filled
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+}
+
+impl<S: SyncAudioSource + Send, F: FnMut(f32) + Send> SyncAudioSource for Inspect<S, F> {
+    fn next_sync(&mut self) -> Option<f32> {
+        let sample = self.source.next_sync()?;
+        (self.f)(sample);
+        Some(sample)
+    }
+    fn fill_sync(&mut self, buffer: &mut [f32]) -> usize {
+        let filled = self.source.fill_sync(buffer);
+        for sample in &buffer[..filled] {
+            (self.f)(*sample);
+        }
+        filled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        pin::pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll, Waker},
+    };
+
+    use super::*;
+
+    /// A finite source of interleaved samples.
+    struct Samples {
+        samples: VecDeque<f32>,
+        sample_rate: u32,
+        channels: u16,
+    }
+
+    fn samples(samples: &[f32], channels: u16) -> Samples {
+        Samples {
+            samples: samples.iter().copied().collect(),
+            sample_rate: 1000,
+            channels,
+        }
+    }
+
+    impl AudioSource for Samples {
+        async fn next(&mut self) -> Option<f32> {
+            self.samples.pop_front()
+        }
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+        fn channels(&self) -> u16 {
+            self.channels
+        }
+    }
+
+    impl SyncAudioSource for Samples {
+        fn next_sync(&mut self) -> Option<f32> {
+            self.samples.pop_front()
+        }
+    }
+
+    /// Runs a future that never waits, as every source here is.
+    fn ready<T>(future: impl Future<Output = T>) -> T {
+        let mut future = pin!(future);
+        match future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("Source waited."),
+        }
+    }
+
+    /// Reads `source` to the end one sample at a time, waiting and without waiting, and
+    /// in blocks both ways, checking all four agree.
+    fn read_all<S: SyncAudioSource + Send>(make_source: impl Fn() -> S) -> Vec<f32> {
+        let mut source = make_source();
+        let by_sample: Vec<f32> = std::iter::from_fn(|| source.next_sync()).collect();
+
+        let mut source = make_source();
+        let by_async_sample: Vec<f32> = std::iter::from_fn(|| ready(source.next())).collect();
+        assert_eq!(by_async_sample, by_sample);
+
+        for block in [1, 3, 64] {
+            let mut buffer = vec![0.0; block];
+            let mut source = make_source();
+            let mut by_block: Vec<f32> = vec![];
+            loop {
+                let filled = source.fill_sync(&mut buffer);
+                by_block.extend(&buffer[..filled]);
+                if filled < block {
+                    break;
+                }
+            }
+            assert_eq!(by_block, by_sample, "blocks of {block}");
+
+            let mut source = make_source();
+            let mut by_async_block: Vec<f32> = vec![];
+            loop {
+                let filled = ready(source.fill(&mut buffer));
+                by_async_block.extend(&buffer[..filled]);
+                if filled < block {
+                    break;
+                }
+            }
+            assert_eq!(by_async_block, by_sample, "async blocks of {block}");
+        }
+        by_sample
+    }
+
+    #[test]
+    fn mix_sums_until_every_source_ends() {
+        let mixed = read_all(|| {
+            Mix::new(vec![
+                samples(&[1.0, 2.0, 3.0], 1),
+                samples(&[10.0], 1),
+                samples(&[], 1),
+            ])
+        });
+        assert_eq!(mixed, [11.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot mix no sources.")]
+    fn mix_rejects_no_sources() {
+        Mix::<Samples>::new(vec![]);
+    }
+
+    #[test]
+    fn gain_scales_every_sample() {
+        let scaled = read_all(|| samples(&[1.0, -0.5, 0.25], 1).gain(2.0));
+        assert_eq!(scaled, [2.0, -1.0, 0.5]);
+    }
+
+    #[test]
+    fn map_applies_to_every_sample() {
+        let mapped = read_all(|| samples(&[1.0, -0.5, 0.25], 1).map(f32::abs));
+        assert_eq!(mapped, [1.0, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn remap_reorders_duplicates_and_silences_channels() {
+        let make = || {
+            samples(&[1.0, 2.0, 3.0, 4.0, 5.0], 2).remap_channels(vec![
+                Some(1),
+                Some(0),
+                None,
+                Some(1),
+                Some(7),
+            ])
+        };
+        assert_eq!(make().channels(), 5);
+        // The last, partial input frame is dropped.
+        let remapped = read_all(make);
+        assert_eq!(remapped, [2.0, 1.0, 0.0, 2.0, 0.0, 4.0, 3.0, 0.0, 4.0, 0.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot remap to no channels.")]
+    fn remap_rejects_no_channels() {
+        samples(&[1.0], 1).remap_channels(vec![]);
+    }
+
+    #[test]
+    fn take_ends_after_the_duration_or_the_source() {
+        let input: Vec<f32> = (0..10).map(|i| i as f32).collect();
+        // 3 ms of stereo at 1000 Hz is 3 frames.
+        let taken = read_all(|| samples(&input, 2).take_duration(Duration::from_millis(3)));
+        assert_eq!(taken, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+
+        let taken = read_all(|| samples(&input, 2).take_duration(Duration::from_secs(1)));
+        assert_eq!(taken, input);
+    }
+
+    #[test]
+    fn chain_plays_one_source_after_the_other() {
+        let chained = read_all(|| samples(&[1.0, 2.0], 1).chain(samples(&[3.0], 1)));
+        assert_eq!(chained, [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot chain sources of different formats.")]
+    fn chain_rejects_other_channel_counts() {
+        samples(&[1.0], 1).chain(samples(&[1.0, 2.0], 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot chain sources of different formats.")]
+    fn chain_rejects_other_sample_rates() {
+        let mut next = samples(&[1.0], 1);
+        next.sample_rate = 2000;
+        samples(&[1.0], 1).chain(next);
+    }
+
+    #[test]
+    fn inspect_shows_every_sample_once() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let passed = read_all(|| {
+            seen.lock().unwrap().clear();
+            let seen = seen.clone();
+            samples(&[1.0, 2.0, 3.0], 1).inspect(move |sample| seen.lock().unwrap().push(sample))
+        });
+        assert_eq!(passed, [1.0, 2.0, 3.0]);
+        assert_eq!(*seen.lock().unwrap(), passed);
+    }
 }
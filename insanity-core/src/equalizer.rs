@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// Filter chains that can be applied to what a peer sends us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EqualizerPreset {
+    #[default]
+    Off,
+    /// High-pass only, for desk thumps and fan rumble.
+    RumbleCut,
+    /// High-pass, a gentle presence boost and a low-pass for hiss.
+    Voice,
+    /// Aggressive high-pass and presence boost for boomy laptop microphones.
+    Laptop,
+}
+
+impl EqualizerPreset {
+    pub const ALL: [EqualizerPreset; 4] = [
+        EqualizerPreset::Off,
+        EqualizerPreset::RumbleCut,
+        EqualizerPreset::Voice,
+        EqualizerPreset::Laptop,
+    ];
+
+    /// The preset after this one, wrapping around.
+    pub fn next(self) -> EqualizerPreset {
+        let index = Self::ALL.iter().position(|p| *p == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EqualizerPreset::Off => "off",
+            EqualizerPreset::RumbleCut => "rumble",
+            EqualizerPreset::Voice => "voice",
+            EqualizerPreset::Laptop => "laptop",
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Known test signals, for checking the audio path numerically: a sine should keep its
+//! frequency through resampling, and an impulse shows how late audio comes out.
+
+use std::{f32::consts::TAU, time::Duration};
+
+use crate::audio_source::{AudioSource, SyncAudioSource};
+
+/// A mono signal, produced one frame at a time in the range -1.0 to 1.0.
+pub trait Waveform {
+    fn next_frame(&mut self, sample_rate: u32) -> f32;
+}
+
+/// Plays a waveform at any rate and channel count, with every channel carrying the same
+/// signal. Endless unless given a duration.
+pub struct Generator<W> {
+    waveform: W,
+    sample_rate: u32,
+    channels: u16,
+    amplitude: f32,
+    /// Frames left to play, if the signal ends.
+    remaining_frames: Option<u64>,
+    /// The current frame and how many of its channels have been returned.
+    frame: f32,
+    channel: u16,
+}
+
+impl<W: Waveform> Generator<W> {
+    pub fn new(waveform: W, sample_rate: u32, channels: u16) -> Generator<W> {
+        Generator {
+            waveform,
+            sample_rate,
+            channels,
+            amplitude: 1.0,
+            remaining_frames: None,
+            frame: 0.0,
+            channel: channels,
+        }
+    }
+
+    pub fn with_amplitude(self, amplitude: f32) -> Generator<W> {
+        Generator { amplitude, ..self }
+    }
+
+    /// Ends the signal after `duration`.
+    pub fn with_duration(self, duration: Duration) -> Generator<W> {
+        let frames = (duration.as_secs_f64() * self.sample_rate as f64).round() as u64;
+        Generator {
+            remaining_frames: Some(frames),
+            ..self
+        }
+    }
+}
+
+impl<W: Waveform + Send> AudioSource for Generator<W> {
+    async fn next(&mut self) -> Option<f32> {
+        self.next_sync()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+}
+
+impl<W: Waveform + Send> SyncAudioSource for Generator<W> {
+    fn next_sync(&mut self) -> Option<f32> {
+        if self.channel == self.channels {
+            if let Some(remaining_frames) = &mut self.remaining_frames {
+                *remaining_frames = remaining_frames.checked_sub(1)?;
+            }
+            self.frame = self.amplitude * self.waveform.next_frame(self.sample_rate);
+            self.channel = 0;
+        }
+        self.channel += 1;
+        Some(self.frame)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Silence;
+
+impl Waveform for Silence {
+    fn next_frame(&mut self, _sample_rate: u32) -> f32 {
+        0.0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sine {
+    frequency: f32,
+    /// Position in the current cycle, from 0.0 to 1.0.
+    phase: f32,
+}
+
+impl Sine {
+    pub fn new(frequency: f32) -> Sine {
+        Sine {
+            frequency,
+            phase: 0.0,
+        }
+    }
+}
+
+impl Waveform for Sine {
+    fn next_frame(&mut self, sample_rate: u32) -> f32 {
+        let sample = (self.phase * TAU).sin();
+        self.phase = (self.phase + self.frequency / sample_rate as f32).fract();
+        sample
+    }
+}
+
+/// A sine rising exponentially from one frequency to another, starting over once it
+/// reaches the end.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    start_frequency: f32,
+    end_frequency: f32,
+    duration: Duration,
+    elapsed_frames: u64,
+    phase: f32,
+}
+
+impl Sweep {
+    pub fn new(start_frequency: f32, end_frequency: f32, duration: Duration) -> Sweep {
+        Sweep {
+            start_frequency,
+            end_frequency,
+            duration,
+            elapsed_frames: 0,
+            phase: 0.0,
+        }
+    }
+}
+
+impl Waveform for Sweep {
+    fn next_frame(&mut self, sample_rate: u32) -> f32 {
+        let sweep_frames = (self.duration.as_secs_f64() * sample_rate as f64) as u64;
+        if self.elapsed_frames >= sweep_frames {
+            self.elapsed_frames = 0;
+            self.phase = 0.0;
+        }
+        let progress = self.elapsed_frames as f32 / sweep_frames.max(1) as f32;
+        let frequency =
+            self.start_frequency * (self.end_frequency / self.start_frequency).powf(progress);
+
+        let sample = (self.phase * TAU).sin();
+        self.phase = (self.phase + frequency / sample_rate as f32).fract();
+        self.elapsed_frames += 1;
+        sample
+    }
+}
+
+/// xorshift64, so noise is the same for the same seed.
+#[derive(Clone, Debug)]
+struct NoiseRng(u64);
+
+impl NoiseRng {
+    fn new(seed: u64) -> NoiseRng {
+        // xorshift never leaves zero.
+        NoiseRng(seed.max(1))
+    }
+
+    /// Uniform in -1.0 to 1.0.
+    fn next_sample(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        ((self.0 >> 40) as f32 / (1u32 << 24) as f32) * 2.0 - 1.0
+    }
+}
+
+/// Equal power at every frequency.
+#[derive(Clone, Debug)]
+pub struct WhiteNoise {
+    rng: NoiseRng,
+}
+
+impl WhiteNoise {
+    pub fn new(seed: u64) -> WhiteNoise {
+        WhiteNoise {
+            rng: NoiseRng::new(seed),
+        }
+    }
+}
+
+impl Waveform for WhiteNoise {
+    fn next_frame(&mut self, _sample_rate: u32) -> f32 {
+        self.rng.next_sample()
+    }
+}
+
+/// Equal power in every octave, falling 3 dB per octave like most natural sound.
+///
+/// Filters white noise with Paul Kellet's approximation, which holds at the usual rates.
+#[derive(Clone, Debug)]
+pub struct PinkNoise {
+    rng: NoiseRng,
+    state: [f32; 7],
+}
+
+impl PinkNoise {
+    /// Brings the filter's output back to roughly -1.0 to 1.0.
+    const SCALE: f32 = 0.11;
+
+    pub fn new(seed: u64) -> PinkNoise {
+        PinkNoise {
+            rng: NoiseRng::new(seed),
+            state: [0.0; 7],
+        }
+    }
+}
+
+impl Waveform for PinkNoise {
+    fn next_frame(&mut self, _sample_rate: u32) -> f32 {
+        let white = self.rng.next_sample();
+        let b = &mut self.state;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.969 * b[2] + white * 0.153852;
+        b[3] = 0.8665 * b[3] + white * 0.3104856;
+        b[4] = 0.55 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        (pink * Self::SCALE).clamp(-1.0, 1.0)
+    }
+}
+
+/// A single full-scale frame followed by silence, optionally repeating. Where it comes
+/// out of a pipeline shows the pipeline's latency.
+#[derive(Clone, Debug, Default)]
+pub struct Impulse {
+    period: Option<Duration>,
+    frames_until_next: u64,
+}
+
+impl Impulse {
+    /// One impulse, on the first frame.
+    pub fn new() -> Impulse {
+        Impulse::default()
+    }
+
+    /// An impulse on the first frame and then every `period`.
+    pub fn every(period: Duration) -> Impulse {
+        Impulse {
+            period: Some(period),
+            frames_until_next: 0,
+        }
+    }
+}
+
+impl Waveform for Impulse {
+    fn next_frame(&mut self, sample_rate: u32) -> f32 {
+        match self.frames_until_next.checked_sub(1) {
+            Some(frames_until_next) => {
+                self.frames_until_next = frames_until_next;
+                0.0
+            }
+            None => {
+                self.frames_until_next = match self.period {
+                    Some(period) => {
+                        ((period.as_secs_f64() * sample_rate as f64) as u64).saturating_sub(1)
+                    }
+                    None => u64::MAX,
+                };
+                1.0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(mut source: impl SyncAudioSource) -> Vec<f32> {
+        std::iter::from_fn(|| source.next_sync()).collect()
+    }
+
+    #[test]
+    fn duration_ends_the_signal_after_whole_frames() {
+        let samples = collect(
+            Generator::new(Sine::new(440.0), 48000, 3).with_duration(Duration::from_millis(10)),
+        );
+        assert_eq!(samples.len(), 480 * 3);
+        for frame in samples.chunks_exact(3) {
+            assert!(frame.iter().all(|sample| *sample == frame[0]));
+        }
+    }
+
+    #[test]
+    fn sine_has_its_frequency_and_amplitude() {
+        let samples = collect(
+            Generator::new(Sine::new(1000.0), 48000, 1)
+                .with_amplitude(0.5)
+                .with_duration(Duration::from_secs(1)),
+        );
+        let rising_crossings = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        // The first cycle starts at zero rather than crossing it.
+        assert_eq!(rising_crossings, 999);
+        let peak = samples
+            .iter()
+            .fold(0f32, |peak, sample| peak.max(sample.abs()));
+        assert!((peak - 0.5).abs() < 0.001, "peak {peak}");
+    }
+
+    #[test]
+    fn impulses_land_on_their_period() {
+        let samples = collect(
+            Generator::new(Impulse::every(Duration::from_millis(100)), 44100, 1)
+                .with_duration(Duration::from_secs(1)),
+        );
+        let impulses: Vec<usize> = (0..samples.len()).filter(|i| samples[*i] == 1.0).collect();
+        assert_eq!(impulses, (0..10).map(|i| i * 4410).collect::<Vec<_>>());
+        assert_eq!(samples.iter().filter(|sample| **sample != 0.0).count(), 10);
+    }
+
+    #[test]
+    fn noise_is_repeatable_and_in_range() {
+        let white = |seed| {
+            collect(
+                Generator::new(WhiteNoise::new(seed), 48000, 1)
+                    .with_duration(Duration::from_secs(1)),
+            )
+        };
+        assert_eq!(white(7), white(7));
+        assert_ne!(white(7), white(8));
+
+        let pink = collect(
+            Generator::new(PinkNoise::new(7), 48000, 1).with_duration(Duration::from_secs(1)),
+        );
+        for samples in [white(7), pink] {
+            assert!(samples.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+            let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+            assert!(mean.abs() < 0.05, "mean {mean}");
+        }
+    }
+}
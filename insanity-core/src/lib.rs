@@ -1,5 +1,8 @@
 pub mod audio_source;
+pub mod equalizer;
+pub mod generators;
 pub mod loudness;
+pub mod pan;
 pub mod user_input_event;
 
 pub mod built_info {
@@ -0,0 +1,52 @@
+use std::f32::consts::FRAC_PI_4;
+
+/// Furthest a peer can be placed from the center, in either direction.
+pub const MAX_PAN: isize = 100;
+
+/// Furthest automatic placement goes from the center, so nobody ends up in only one ear.
+const AUTO_PAN_WIDTH: isize = 80;
+
+/// Position of a peer in the stereo field, from -MAX_PAN (left) to MAX_PAN (right).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pan {
+    /// Spread evenly alongside the other peers.
+    Auto(isize),
+    /// Placed explicitly by the user.
+    Manual(isize),
+}
+
+impl Default for Pan {
+    fn default() -> Self {
+        Pan::Auto(0)
+    }
+}
+
+impl Pan {
+    pub fn position(&self) -> isize {
+        match self {
+            Pan::Auto(position) | Pan::Manual(position) => *position,
+        }
+    }
+
+    pub fn is_auto(&self) -> bool {
+        matches!(self, Pan::Auto(_))
+    }
+}
+
+/// Evenly spaced positions for `count` peers, from left to right.
+pub fn auto_pan_positions(count: usize) -> Vec<isize> {
+    match count {
+        0 => vec![],
+        1 => vec![0],
+        _ => (0..count)
+            .map(|i| -AUTO_PAN_WIDTH + (2 * AUTO_PAN_WIDTH * i as isize) / (count as isize - 1))
+            .collect(),
+    }
+}
+
+/// Left and right gains for a position, using a constant-power pan law.
+pub fn pan_gains(position: isize) -> (f32, f32) {
+    let position = position.clamp(-MAX_PAN, MAX_PAN) as f32 / MAX_PAN as f32;
+    let angle = (position + 1.0) * FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
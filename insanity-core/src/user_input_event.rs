@@ -1,3 +1,5 @@
+use crate::equalizer::EqualizerPreset;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum UserInputEvent {
     DisablePeer(String),
@@ -5,6 +7,31 @@ pub enum UserInputEvent {
     DisableDenoise(String),
     EnableDenoise(String),
     SetVolume(String, usize),
+    /// Places a peer in the stereo field, or back to automatic placement with `None`.
+    SetPan(String, Option<isize>),
+    SetEqualizer(String, EqualizerPreset),
+    /// Silences a peer for us only; they can still hear us.
+    SetMutePeer(String, bool),
     SendMessage(String),
     SetMuteSelf(bool),
+    /// Silences all peers and our microphone.
+    SetDeafen(bool),
+    /// Starts or stops recording the call to disk.
+    SetRecording(bool),
+    /// Starts or stops playing our microphone back to us as peers would hear it.
+    SetMicTest(bool),
+    /// Sends our microphone to the peers of this room only. Peers we share no other room
+    /// with can still be heard but no longer hear us.
+    SetTalkRoom(String),
+    /// Announces us in another room and connects to the peers there.
+    JoinRoom(String),
+    /// Withdraws from a room and disconnects from peers we share no other room with.
+    LeaveRoom(String),
+    /// Puts a peer on the allowlist of every room we share with it. Once a room has an
+    /// allowlist, only peers on it can connect through the room.
+    AllowPeer(String),
+    /// Puts a peer on the blocklist of every room we share with it and disconnects it.
+    DenyPeer(String),
+    /// Marks a peer as verified after comparing safety numbers, or takes that back.
+    SetPeerVerified(String, bool),
 }
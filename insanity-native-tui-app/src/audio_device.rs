@@ -0,0 +1,246 @@
+//! Where call audio is captured and played. The clerver only talks to an [`AudioBackend`],
+//! so it can run on the sound card through cpal or entirely in software.
+
+use std::{sync::Arc, time::Duration};
+
+use cpal::traits::{HostTrait, StreamTrait};
+use insanity_core::audio_source::{AudioSource, SyncAudioSource};
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+
+use crate::{
+    client::{get_output_config, setup_output_stream},
+    processor::{AudioFormat, PlaybackOutput, AUDIO_CHUNK_SIZE, DEFAULT_CHANNELS},
+    sample_ring::{sample_ring, SampleConsumer, SampleProducer},
+    server::make_audio_receiver,
+};
+
+/// How much captured audio waits to be read before newly captured audio is dropped.
+const CAPTURE_QUEUE: Duration = Duration::from_secs(1);
+
+/// Keeps output playing. Playback stops when it is dropped.
+pub type OutputStream = Box<dyn Send>;
+
+pub trait AudioBackend: Send + Sync {
+    /// Starts capturing microphone audio.
+    fn open_input(&self) -> anyhow::Result<AudioInput>;
+
+    /// The sample rate and channel count output is played at.
+    fn output_format(&self) -> anyhow::Result<AudioFormat>;
+
+    /// Starts playing whatever is queued for `output`.
+    fn start_output(&self, output: PlaybackOutput) -> anyhow::Result<OutputStream>;
+}
+
+/// The queue a capture callback writes into and an [`AudioInput`] reads from.
+pub fn capture_queue(sample_rate: u32, channels: u16) -> (SampleProducer, SampleConsumer) {
+    let samples = CAPTURE_QUEUE.as_secs_f64() * sample_rate as f64 * channels as f64;
+    sample_ring(samples as usize)
+}
+
+/// Captured samples, interleaved, as the capture callback queues them. Never ends; reads
+/// wait for the microphone instead.
+pub struct AudioInput {
+    samples: SampleConsumer,
+    sample_rate: u32,
+    channels: u16,
+    /// Whatever keeps the capture running; capture stops when it is dropped.
+    _capture: Box<dyn Send + Sync>,
+}
+
+impl AudioInput {
+    pub fn new(
+        samples: SampleConsumer,
+        sample_rate: u32,
+        channels: u16,
+        capture: Box<dyn Send + Sync>,
+    ) -> AudioInput {
+        AudioInput {
+            samples,
+            sample_rate,
+            channels,
+            _capture: capture,
+        }
+    }
+}
+
+impl AudioSource for AudioInput {
+    async fn next(&mut self) -> Option<f32> {
+        let mut sample = [0.0];
+        self.fill(&mut sample).await;
+        Some(sample[0])
+    }
+    async fn fill(&mut self, buffer: &mut [f32]) -> usize {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let popped = self.samples.pop_slice(&mut buffer[filled..]);
+            if popped == 0 {
+                self.samples.readable().await;
+            }
+            filled += popped;
+        }
+        filled
+    }
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+}
+
+/// The default input and output devices of the default cpal host.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpalBackend;
+
+impl AudioBackend for CpalBackend {
+    fn open_input(&self) -> anyhow::Result<AudioInput> {
+        make_audio_receiver()
+    }
+
+    fn output_format(&self) -> anyhow::Result<AudioFormat> {
+        let host = cpal::default_host();
+        let Some(output_device) = host.default_output_device() else {
+            anyhow::bail!("No default output device.");
+        };
+        let (_, config) = get_output_config(&output_device);
+        Ok(AudioFormat::new(config.channels, config.sample_rate.0))
+    }
+
+    fn start_output(&self, output: PlaybackOutput) -> anyhow::Result<OutputStream> {
+        let host = cpal::default_host();
+        let Some(output_device) = host.default_output_device() else {
+            anyhow::bail!("No default output device.");
+        };
+        let (sample_format, config) = get_output_config(&output_device);
+        let mut output_stream_wrapper = send_safe::SendWrapperThread::new(move || {
+            setup_output_stream(&sample_format, &config, &output_device, output)
+        });
+        let started = output_stream_wrapper.execute(|output_stream| {
+            if let Err(e) = output_stream.play() {
+                log::error!("Failed to play output stream: {:?}", e);
+            }
+        });
+        if started.is_err() {
+            anyhow::bail!("Failed to start output stream.");
+        }
+        Ok(Box::new(output_stream_wrapper))
+    }
+}
+
+/// Aborts a task when dropped, so a virtual stream stops with its owner.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// How long `AUDIO_CHUNK_SIZE` frames last at `sample_rate`.
+fn chunk_period(sample_rate: u32) -> Duration {
+    Duration::from_secs_f64(AUDIO_CHUNK_SIZE as f64 / sample_rate as f64)
+}
+
+/// Audio without devices, paced by the tokio clock. The microphone is a sample source
+/// played in real time, going silent once it runs out. Output is played on the same
+/// clock and each block is handed to the sink, if there is one.
+///
+/// With the tokio clock paused, a whole call runs deterministically.
+pub struct VirtualBackend<F> {
+    make_input: Arc<F>,
+    output_format: AudioFormat,
+    output_sink: Option<UnboundedSender<Vec<f32>>>,
+}
+
+impl<F> Clone for VirtualBackend<F> {
+    fn clone(&self) -> Self {
+        VirtualBackend {
+            make_input: self.make_input.clone(),
+            output_format: self.output_format.clone(),
+            output_sink: self.output_sink.clone(),
+        }
+    }
+}
+
+impl<F, S> VirtualBackend<F>
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: SyncAudioSource + Send + 'static,
+{
+    /// `make_input` is called every time the microphone is opened.
+    pub fn new(make_input: F) -> VirtualBackend<F> {
+        VirtualBackend {
+            make_input: Arc::new(make_input),
+            output_format: AudioFormat::new(DEFAULT_CHANNELS, 48000),
+            output_sink: None,
+        }
+    }
+
+    pub fn with_output_format(self, output_format: AudioFormat) -> VirtualBackend<F> {
+        VirtualBackend {
+            output_format,
+            ..self
+        }
+    }
+
+    /// Receives every block played by every output, interleaved in the output format.
+    pub fn with_output_sink(self, output_sink: UnboundedSender<Vec<f32>>) -> VirtualBackend<F> {
+        VirtualBackend {
+            output_sink: Some(output_sink),
+            ..self
+        }
+    }
+}
+
+impl<F, S> AudioBackend for VirtualBackend<F>
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: SyncAudioSource + Send + 'static,
+{
+    fn open_input(&self) -> anyhow::Result<AudioInput> {
+        let mut source = (self.make_input)();
+        let sample_rate = source.sample_rate();
+        let channels = source.channels();
+        let (mut producer, consumer) = capture_queue(sample_rate, channels);
+        let capture = tokio::spawn(async move {
+            let mut clock = tokio::time::interval(chunk_period(sample_rate));
+            let mut block = vec![0f32; AUDIO_CHUNK_SIZE * channels as usize];
+            loop {
+                clock.tick().await;
+                // Whatever the source didn't fill stays silent.
+                let filled = source.fill_sync(&mut block);
+                block[filled..].fill(0.0);
+                producer.push_slice(&block);
+            }
+        });
+        Ok(AudioInput::new(
+            consumer,
+            sample_rate,
+            channels,
+            Box::new(AbortOnDrop(capture)),
+        ))
+    }
+
+    fn output_format(&self) -> anyhow::Result<AudioFormat> {
+        Ok(self.output_format.clone())
+    }
+
+    fn start_output(&self, mut output: PlaybackOutput) -> anyhow::Result<OutputStream> {
+        let format = self.output_format.clone();
+        let output_sink = self.output_sink.clone();
+        let playback = tokio::spawn(async move {
+            let mut clock = tokio::time::interval(chunk_period(format.sample_rate));
+            let mut block = vec![0f32; AUDIO_CHUNK_SIZE * format.channel_count as usize];
+            loop {
+                clock.tick().await;
+                output.fill_buffer(&mut block);
+                if let Some(output_sink) = &output_sink
+                    && output_sink.send(block.clone()).is_err()
+                {
+                    log::debug!("Virtual output sink closed.");
+                }
+            }
+        });
+        Ok(Box::new(AbortOnDrop(playback)))
+    }
+}
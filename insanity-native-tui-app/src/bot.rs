@@ -0,0 +1,331 @@
+//! A peer without a terminal or audio devices, for automated call-quality checks and as an
+//! always-on echo test. It talks over a tone, a WAV file or the delayed echo of what it
+//! hears, records the call, and answers chat commands.
+
+use std::{
+    collections::VecDeque,
+    f32::consts::TAU,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use insanity_core::{
+    audio_source::{AudioSource, SyncAudioSource},
+    user_input_event::UserInputEvent,
+};
+use insanity_tui_adapter::AppEvent;
+use rubato_audio_source::{ResampledAudioSource, ResamplerQuality};
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{
+    audio_device::VirtualBackend,
+    connection_manager::ConnectionManagerBuilder,
+    processor::{remix_channels, AUDIO_CHUNK_SIZE, DEFAULT_CHANNELS},
+};
+
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: usize = DEFAULT_CHANNELS as usize;
+const TONE_AMPLITUDE: f32 = 0.2;
+/// How much audio the echo keeps beyond its delay.
+const ECHO_MARGIN_FRAMES: u64 = SAMPLE_RATE as u64;
+const COMMAND_PREFIX: char = '!';
+const HELP: &str = "Commands: !ping, !echo, !tone [hz], !play, !quiet, !status, !help";
+
+/// What the bot says.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BotMode {
+    Tone(f32),
+    Play,
+    Echo,
+    Silence,
+}
+
+pub struct BotOptions {
+    pub mode: BotMode,
+    /// Played by `BotMode::Play`, on a loop.
+    pub wav: Option<std::path::PathBuf>,
+    pub echo_delay: Duration,
+}
+
+/// Everything the bot hears, summed across peers, kept so it can be said again later.
+struct EchoLine {
+    origin: Instant,
+    delay_frames: u64,
+    /// Frame of the first sample in `heard`.
+    start_frame: u64,
+    heard: VecDeque<f32>,
+}
+
+impl EchoLine {
+    fn new(delay: Duration) -> EchoLine {
+        EchoLine {
+            origin: Instant::now(),
+            delay_frames: (delay.as_secs_f64() * SAMPLE_RATE as f64) as u64,
+            start_frame: 0,
+            heard: VecDeque::new(),
+        }
+    }
+
+    fn now_frame(&self) -> u64 {
+        (self.origin.elapsed().as_secs_f64() * SAMPLE_RATE as f64) as u64
+    }
+
+    /// Mixes in a block that has just been played.
+    fn hear(&mut self, block: &[f32]) {
+        let frames = (block.len() / CHANNELS) as u64;
+        let block_start = self.now_frame().saturating_sub(frames);
+
+        // Forget what is too old to be echoed.
+        let keep_from = block_start.saturating_sub(self.delay_frames + ECHO_MARGIN_FRAMES);
+        while self.start_frame < keep_from && !self.heard.is_empty() {
+            self.heard.drain(..CHANNELS);
+            self.start_frame += 1;
+        }
+        if self.heard.is_empty() {
+            self.start_frame = std::cmp::max(self.start_frame, keep_from);
+        }
+
+        let skip = self.start_frame.saturating_sub(block_start) as usize * CHANNELS;
+        if skip >= block.len() {
+            return;
+        }
+        let offset = block_start.saturating_sub(self.start_frame) as usize * CHANNELS;
+        let end = offset + block.len() - skip;
+        if self.heard.len() < end {
+            self.heard.resize(end, 0.0);
+        }
+        for (i, sample) in block[skip..].iter().enumerate() {
+            self.heard[offset + i] += sample;
+        }
+    }
+
+    /// What was heard `delay` before `frame`, or silence.
+    fn echo(&self, frame: u64, channel: usize) -> f32 {
+        let Some(heard_frame) = frame.checked_sub(self.delay_frames) else {
+            return 0.0;
+        };
+        let Some(index) = heard_frame.checked_sub(self.start_frame) else {
+            return 0.0;
+        };
+        self.heard
+            .get(index as usize * CHANNELS + channel)
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+/// The bot's microphone, always 48 kHz stereo. Every connection opens its own.
+struct BotMicrophone {
+    mode: Arc<Mutex<BotMode>>,
+    echo_line: Arc<Mutex<EchoLine>>,
+    wav: Arc<Vec<f32>>,
+    frame: u64,
+    channel: usize,
+    phase: f32,
+}
+
+impl AudioSource for BotMicrophone {
+    async fn next(&mut self) -> Option<f32> {
+        self.next_sync()
+    }
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+    fn channels(&self) -> u16 {
+        DEFAULT_CHANNELS
+    }
+}
+
+impl SyncAudioSource for BotMicrophone {
+    fn next_sync(&mut self) -> Option<f32> {
+        let mode = *self.mode.lock().unwrap();
+        let sample = match mode {
+            BotMode::Tone(_) => TONE_AMPLITUDE * (self.phase * TAU).sin(),
+            BotMode::Play if self.wav.is_empty() => 0.0,
+            BotMode::Play => {
+                let frame = self.frame as usize % (self.wav.len() / CHANNELS);
+                self.wav[frame * CHANNELS + self.channel]
+            }
+            BotMode::Echo => self
+                .echo_line
+                .lock()
+                .unwrap()
+                .echo(self.frame, self.channel),
+            BotMode::Silence => 0.0,
+        };
+
+        self.channel += 1;
+        if self.channel == CHANNELS {
+            self.channel = 0;
+            self.frame += 1;
+            if let BotMode::Tone(frequency) = mode {
+                self.phase = (self.phase + frequency / SAMPLE_RATE as f32).fract();
+            }
+        }
+        Some(sample)
+    }
+}
+
+/// Plays samples from memory, for resampling a whole file at once.
+struct MemorySource {
+    samples: std::vec::IntoIter<f32>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl AudioSource for MemorySource {
+    async fn next(&mut self) -> Option<f32> {
+        self.next_sync()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+}
+
+impl SyncAudioSource for MemorySource {
+    fn next_sync(&mut self) -> Option<f32> {
+        self.samples.next()
+    }
+}
+
+/// Reads a WAV file as 48 kHz stereo samples.
+fn load_wav(path: &Path) -> anyhow::Result<Vec<f32>> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+
+    let source = MemorySource {
+        samples: samples.into_iter(),
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+    };
+    // Resampled once up front, so there's no reason not to do it well.
+    let mut resampled = ResampledAudioSource::new(
+        source,
+        SAMPLE_RATE,
+        AUDIO_CHUNK_SIZE,
+        ResamplerQuality::High,
+    );
+    let mut samples = Vec::new();
+    while let Some(sample) = resampled.next_sync() {
+        samples.push(sample);
+    }
+    resampled.flush();
+    while let Some(sample) = resampled.next_sync() {
+        samples.push(sample);
+    }
+    Ok(remix_channels(&samples, spec.channels, DEFAULT_CHANNELS))
+}
+
+/// Handles a chat message, returning the reply if it was a command.
+fn handle_command(message: &str, mode: &Mutex<BotMode>, has_wav: bool) -> Option<String> {
+    let mut words = message.strip_prefix(COMMAND_PREFIX)?.split_whitespace();
+    let reply = match words.next()? {
+        "ping" => "pong".to_string(),
+        "help" => HELP.to_string(),
+        "status" => format!("Mode: {:?}", *mode.lock().unwrap()),
+        "echo" => {
+            *mode.lock().unwrap() = BotMode::Echo;
+            "Echoing what I hear.".to_string()
+        }
+        "tone" => {
+            let frequency = match words.next().map(str::parse::<f32>) {
+                None => 440.0,
+                Some(Ok(frequency)) if frequency > 0.0 => frequency,
+                Some(_) => return Some("Usage: !tone [hz]".to_string()),
+            };
+            *mode.lock().unwrap() = BotMode::Tone(frequency);
+            format!("Playing a {frequency} Hz tone.")
+        }
+        "play" if has_wav => {
+            *mode.lock().unwrap() = BotMode::Play;
+            "Playing my file.".to_string()
+        }
+        "play" => "I have no file to play.".to_string(),
+        "quiet" => {
+            *mode.lock().unwrap() = BotMode::Silence;
+            "Going quiet.".to_string()
+        }
+        command => format!("Unknown command {command}. {HELP}"),
+    };
+    Some(reply)
+}
+
+/// Runs the bot until interrupted, recording the call to the recordings folder.
+pub async fn run_bot(builder: ConnectionManagerBuilder, options: BotOptions) -> anyhow::Result<()> {
+    let wav = match &options.wav {
+        Some(path) => load_wav(path)?,
+        None => Vec::new(),
+    };
+    let has_wav = !wav.is_empty();
+    let wav = Arc::new(wav);
+    let mode = Arc::new(Mutex::new(options.mode));
+    let echo_line = Arc::new(Mutex::new(EchoLine::new(options.echo_delay)));
+
+    let (heard_tx, mut heard_rx) = mpsc::unbounded_channel();
+    let microphone_mode = mode.clone();
+    let microphone_echo_line = echo_line.clone();
+    let backend = VirtualBackend::new(move || {
+        let frame = microphone_echo_line.lock().unwrap().now_frame();
+        BotMicrophone {
+            mode: microphone_mode.clone(),
+            echo_line: microphone_echo_line.clone(),
+            wav: wav.clone(),
+            frame,
+            channel: 0,
+            phase: 0.0,
+        }
+    })
+    .with_output_sink(heard_tx);
+
+    let (app_event_tx, mut app_event_rx) = mpsc::unbounded_channel();
+    let connection_manager = builder
+        .audio_backend(Arc::new(backend))
+        .app_event_sender(app_event_tx)
+        .record(true)
+        .start()
+        .await?;
+    println!(
+        "Bot started in {:?} mode. Press Ctrl-C to stop.",
+        options.mode
+    );
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        tokio::select! {
+            Some(block) = heard_rx.recv() => {
+                echo_line.lock().unwrap().hear(&block);
+            }
+            Some(event) = app_event_rx.recv() => match event {
+                AppEvent::NewMessage(sender, message) => {
+                    log::debug!("Bot got message from {sender}: {message}");
+                    if let Some(reply) = handle_command(&message, &mode, has_wav) {
+                        connection_manager.send_user_action(UserInputEvent::SendMessage(reply))?;
+                    }
+                }
+                AppEvent::AddPeer(peer) => {
+                    log::debug!("Bot peer update: {:?}", peer);
+                }
+                _ => {}
+            },
+            _ = &mut ctrl_c => break,
+        }
+    }
+
+    connection_manager.shutdown_and_finish_recording().await;
+    Ok(())
+}
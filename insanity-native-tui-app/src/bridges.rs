@@ -0,0 +1,246 @@
+//! The bridge servers rooms are found on. Presence is published to every bridge and peers
+//! are gathered from all of them, so a room keeps working while any one bridge is up. A
+//! bridge that fails is retried with growing delays instead of on every tick.
+
+use std::{
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::{prelude::BASE64_URL_SAFE, Engine};
+use baybridge::{
+    client::Actions,
+    connectors::{connection::Connection, http::HttpConnection},
+};
+use insanity_tui_adapter::{AppEvent, BridgeStatus};
+use tokio::sync::mpsc;
+
+/// Longest a request may take before the bridge counts as down.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait after the first failure, doubled after each one after that.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+struct Health {
+    status: BridgeStatus,
+    consecutive_failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Health {
+    fn new() -> Health {
+        Health {
+            status: BridgeStatus::Connecting,
+            consecutive_failures: 0,
+            retry_at: None,
+        }
+    }
+
+    fn ready(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|retry_at| now >= retry_at)
+    }
+
+    fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.retry_at = None;
+    }
+
+    /// Returns whether the status changed, and with it whether it's worth showing.
+    fn set_status(&mut self, status: BridgeStatus) -> bool {
+        if self.status == status {
+            return false;
+        }
+        self.status = status;
+        true
+    }
+
+    /// Returns how long to wait before trying again.
+    fn record_failure(&mut self, now: Instant) -> Duration {
+        self.consecutive_failures += 1;
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.consecutive_failures - 1))
+            .min(MAX_BACKOFF);
+        self.retry_at = Some(now + backoff);
+        backoff
+    }
+}
+
+/// One bridge server, with its own client so it can fail on its own.
+pub struct Bridge {
+    pub url: String,
+    pub actions: Actions,
+    health: Mutex<Health>,
+    app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
+}
+
+impl Bridge {
+    /// Whether the bridge may be tried now, rather than being waited out after failing.
+    pub fn ready(&self) -> bool {
+        self.health.lock().unwrap().ready(Instant::now())
+    }
+
+    /// Runs a request against the bridge, giving up after a timeout, and updates the
+    /// bridge's health with the outcome.
+    pub async fn call<T>(
+        &self,
+        request: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let result = match tokio::time::timeout(REQUEST_TIMEOUT, request).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("Timed out after {REQUEST_TIMEOUT:?}.")),
+        };
+        match &result {
+            Ok(_) => self.record_success(),
+            Err(e) => self.record_failure(e),
+        }
+        result
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.record_success();
+        self.set_status(&mut health, BridgeStatus::Up);
+    }
+
+    fn record_failure(&self, e: &anyhow::Error) {
+        let mut health = self.health.lock().unwrap();
+        let backoff = health.record_failure(Instant::now());
+        log::debug!(
+            "Bridge {} failed {} times in a row, retrying in {backoff:?}: {:?}",
+            self.url,
+            health.consecutive_failures,
+            e
+        );
+        self.set_status(&mut health, BridgeStatus::Down(e.to_string()));
+    }
+
+    fn set_status(&self, health: &mut Health, status: BridgeStatus) {
+        if !health.set_status(status.clone()) {
+            return;
+        }
+        if let Some(app_event_tx) = &self.app_event_tx
+            && let Err(e) = app_event_tx.send(AppEvent::SetBridgeStatus(self.url.clone(), status))
+        {
+            log::debug!("Failed to send bridge status: {:?}", e);
+        }
+    }
+}
+
+pub struct Bridges {
+    bridges: Vec<Arc<Bridge>>,
+}
+
+impl Bridges {
+    /// Sets up a client for each bridge server, or returns `None` without any, in which
+    /// case rooms can't be joined. Nothing is sent to the bridges yet.
+    pub async fn connect(
+        base_dir: &Path,
+        bridge_servers: &[String],
+        app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
+    ) -> anyhow::Result<Option<Arc<Bridges>>> {
+        if bridge_servers.is_empty() {
+            log::debug!("No bridge server, so rooms can't be joined.");
+            return Ok(None);
+        }
+        log::debug!("Connecting to bridge servers {bridge_servers:?}.");
+
+        // Every client shares the data directory, and with it our baybridge identity.
+        let baybridge_datadir = base_dir.join("baybridge");
+        let mut bridges = vec![];
+        for url in bridge_servers {
+            let connection = Connection::Http(HttpConnection::new(url::Url::parse(url)?));
+            let baybridge_config = baybridge::configuration::Configuration::new(
+                baybridge_datadir.clone(),
+                vec![connection],
+            );
+            let init = baybridge_config.init().await;
+            let bridge = Bridge {
+                url: url.clone(),
+                actions: Actions::new(baybridge_config),
+                health: Mutex::new(Health::new()),
+                app_event_tx: app_event_tx.clone(),
+            };
+            // Shown as down and backed off like any other failure, so the rest still work.
+            if let Err(e) = init {
+                log::error!("Failed to set up bridge {url}: {:?}", e);
+                bridge.record_failure(&e);
+            }
+            bridges.push(Arc::new(bridge));
+        }
+
+        // Query self and add to UI.
+        if let Some(app_event_tx) = app_event_tx {
+            let my_public_key = bridges[0].actions.whoami().await;
+            let my_public_key_base64 = BASE64_URL_SAFE.encode(my_public_key.as_bytes());
+            if let Err(e) = app_event_tx.send(AppEvent::SetOwnPublicKey(my_public_key_base64)) {
+                log::debug!("Failed to write own public key to UI: {e}");
+            }
+        }
+        Ok(Some(Arc::new(Bridges { bridges })))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Bridge>> {
+        self.bridges.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_bridges_are_ready() {
+        assert!(Health::new().ready(Instant::now()));
+    }
+
+    #[test]
+    fn failures_back_off_exponentially_up_to_a_limit() {
+        let mut health = Health::new();
+        let now = Instant::now();
+        let backoffs: Vec<Duration> = (0..9).map(|_| health.record_failure(now)).collect();
+        let secs: Vec<u64> = backoffs.iter().map(Duration::as_secs).collect();
+        assert_eq!(secs, [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(health.consecutive_failures, 9);
+
+        // Many more failures don't overflow.
+        for _ in 0..100 {
+            assert_eq!(health.record_failure(now), MAX_BACKOFF);
+        }
+    }
+
+    #[test]
+    fn failed_bridges_are_waited_out() {
+        let mut health = Health::new();
+        let now = Instant::now();
+        health.record_failure(now);
+        health.record_failure(now);
+        assert!(!health.ready(now));
+        assert!(!health.ready(now + Duration::from_millis(1999)));
+        assert!(health.ready(now + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn success_resets_the_backoff() {
+        let mut health = Health::new();
+        let now = Instant::now();
+        for _ in 0..5 {
+            health.record_failure(now);
+        }
+        health.record_success();
+        assert!(health.ready(now));
+        assert_eq!(health.record_failure(now), INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn only_status_changes_are_reported() {
+        let mut health = Health::new();
+        assert!(!health.set_status(BridgeStatus::Connecting));
+        assert!(health.set_status(BridgeStatus::Up));
+        assert!(!health.set_status(BridgeStatus::Up));
+        assert!(health.set_status(BridgeStatus::Down("timed out".to_string())));
+        assert!(!health.set_status(BridgeStatus::Down("timed out".to_string())));
+        assert!(health.set_status(BridgeStatus::Down("refused".to_string())));
+    }
+}
@@ -1,85 +1,250 @@
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::sync::{atomic::AtomicBool, Arc};
+use std::time::Duration;
 
-use cpal::traits::{HostTrait, StreamTrait};
+use cpal::SampleRate;
+use ed25519_dalek::VerifyingKey;
 
 use insanity_core::audio_source::AudioSource;
 use insanity_tui_adapter::AppEvent;
 use opus::{Application, Channels, Decoder, Encoder};
-use rubato_audio_source::ResampledAudioSource;
+use rubato_audio_source::{ResampledAudioSource, ResamplerQuality};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, mpsc};
-use veq::veq::VeqSessionAlias;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    client::{get_output_config, setup_output_stream},
-    processor::{AudioChunk, AudioFormat, AudioProcessor, AUDIO_CHUNK_SIZE},
+    audio_device::{AudioBackend, OutputStream},
+    dump::FrameDump,
+    processor::{
+        remix_channels, AudioChunk, AudioFormat, AudioProcessor, PlaybackControls,
+        AUDIO_CHUNK_SIZE, MAX_STREAM_CHANNELS,
+    },
     protocol::ProtocolMessage,
-    server::make_audio_receiver,
+    recorder::{Recorder, SELF_TRACK},
+    room_handler::ConfirmedMemberKey,
+    transport::TransportSession,
 };
 
+/// How often we tell the peer our member key, in case it was lost on the way.
+const MEMBER_KEY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An Opus packet along with its sequence number and the number of channels it was encoded with.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct AudioFrame(u128, Vec<u8>);
+pub struct AudioFrame(u128, u16, Vec<u8>);
+
+impl AudioFrame {
+    pub fn new(sequence_number: u128, channel_count: u16, packet: Vec<u8>) -> AudioFrame {
+        AudioFrame(sequence_number, channel_count, packet)
+    }
+
+    pub fn sequence_number(&self) -> u128 {
+        self.0
+    }
+
+    pub fn channel_count(&self) -> u16 {
+        self.1
+    }
+
+    pub fn packet(&self) -> &[u8] {
+        &self.2
+    }
+}
+
+/// Our side of a call with one peer: the audio devices, whether we're muted, and how
+/// the peer is played back.
+#[derive(Clone)]
+pub struct CallAudio {
+    pub backend: Arc<dyn AudioBackend>,
+    pub sender_is_muted: Arc<AtomicBool>,
+    /// Set while the peer isn't in the room we talk to, so it doesn't hear us.
+    pub listen_only: Arc<AtomicBool>,
+    pub controls: PlaybackControls,
+    pub resampler_quality: ResamplerQuality,
+}
 
 // A clerver is a CLient + sERVER.
 
-async fn run_audio_sender<R: AudioSource + Send + Sync + 'static>(
-    mut conn: VeqSessionAlias,
+async fn run_audio_sender<S: TransportSession>(
+    mut conn: S,
     sender_is_muted: Arc<AtomicBool>,
-    make_receiver: impl (FnOnce() -> R) + Send + Clone + 'static,
+    listen_only: Arc<AtomicBool>,
+    deafened: Arc<AtomicBool>,
+    backend: Arc<dyn AudioBackend>,
+    resampler_quality: ResamplerQuality,
 ) {
-    let audio_receiver = make_receiver();
-    let _sample_rate = audio_receiver.sample_rate();
-    let channels_count = audio_receiver.channels();
-    let channels = u16_to_channels(channels_count);
-
-    let mut audio_receiver = ResampledAudioSource::new(audio_receiver, 48000, AUDIO_CHUNK_SIZE);
-    let mut encoder = Encoder::new(48000, channels, Application::Audio).unwrap();
-    let mut sequence_number = 0;
+    let audio_receiver = match backend.open_input() {
+        Ok(audio_receiver) => audio_receiver,
+        Err(e) => {
+            // Stay in the call so we can still listen.
+            log::error!("Failed to open audio input: {:?}", e);
+            std::future::pending::<()>().await;
+            return;
+        }
+    };
+    let device_channels = audio_receiver.channels();
+    let mut encoder = match FrameEncoder::new(device_channels) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            log::error!("Failed to create audio encoder: {:?}", e);
+            return;
+        }
+    };
+    let mut audio_receiver =
+        ResampledAudioSource::new(audio_receiver, 48000, AUDIO_CHUNK_SIZE, resampler_quality);
 
     loop {
-        let mut samples = Vec::new();
-        let sample_count = AUDIO_CHUNK_SIZE * channels_count as usize;
-        for _ in 0..sample_count {
-            // .unwrap() is ok here because this type of audio receiver is "infinite"
-            // and will delay on await instead of return None
-            samples.push(audio_receiver.next().await.unwrap());
-        }
+        let samples = next_chunk(&mut audio_receiver).await;
 
-        if sender_is_muted.load(Ordering::Relaxed) {
+        // Deafening also stops us being heard.
+        if sender_is_muted.load(Ordering::Relaxed)
+            || listen_only.load(Ordering::Relaxed)
+            || deafened.load(Ordering::Relaxed)
+        {
             continue; // skip encoding and sending
         }
 
-        // let samples: Vec<f32> = receiver.iter().take(AUDIO_CHUNK_SIZE * 2).collect();
-        let opus_frame = encoder.encode_vec_float(&samples[..], 65535).unwrap();
-        // let opus_frame = bincode::serialize(&samples).unwrap();
-        let frame = AudioFrame(sequence_number, opus_frame);
+        let frame = match encoder.encode(samples) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::error!("Failed to encode audio: {:?}", e);
+                break;
+            }
+        };
 
         let mut buf = Vec::new();
         let protocol_message = ProtocolMessage::AudioFrame(frame);
-        let write_result = protocol_message.write_to_stream(&mut buf).await;
-        if conn.send(buf).await.is_err() {
+        if protocol_message.write_to_stream(&mut buf).await.is_err()
+            || conn.send(buf).await.is_err()
+        {
             break;
         }
-        sequence_number = match write_result {
-            Ok(_) => sequence_number + 1,
-            Err(_) => {
-                break;
-            }
+    }
+}
+
+/// Records our own microphone into `recorder`'s self track until `stopped`, as the call
+/// hears it: silent while we're muted or deafened.
+pub async fn record_own_audio(
+    backend: Arc<dyn AudioBackend>,
+    resampler_quality: ResamplerQuality,
+    sender_is_muted: Arc<AtomicBool>,
+    deafened: Arc<AtomicBool>,
+    recorder: Recorder,
+    stopped: CancellationToken,
+) {
+    let audio_receiver = match backend.open_input() {
+        Ok(audio_receiver) => audio_receiver,
+        Err(e) => {
+            log::error!("Failed to open audio input for recording: {:?}", e);
+            return;
+        }
+    };
+    let audio_format = AudioFormat::new(audio_receiver.channels(), 48000);
+    let mut audio_receiver =
+        ResampledAudioSource::new(audio_receiver, 48000, AUDIO_CHUNK_SIZE, resampler_quality);
+
+    let mut sequence_number = 0;
+    loop {
+        let mut samples = tokio::select! {
+            samples = next_chunk(&mut audio_receiver) => samples,
+            _ = stopped.cancelled() => break,
+        };
+        if sender_is_muted.load(Ordering::Relaxed) || deafened.load(Ordering::Relaxed) {
+            samples.fill(0.0);
         }
+        let chunk = AudioChunk::new(sequence_number, audio_format.clone(), samples);
+        recorder.record(SELF_TRACK, &chunk);
+        sequence_number += 1;
     }
 }
 
-fn u16_to_channels(n: u16) -> Channels {
+/// Reads one chunk of `AUDIO_CHUNK_SIZE` frames from the microphone.
+pub async fn next_chunk<A: AudioSource + Send>(audio_receiver: &mut A) -> Vec<f32> {
+    let mut samples = vec![0f32; AUDIO_CHUNK_SIZE * audio_receiver.channels() as usize];
+    // The microphone is "infinite" and waits for samples instead of ending,
+    // so the chunk is always filled.
+    audio_receiver.fill(&mut samples).await;
+    samples
+}
+
+/// Encodes microphone chunks into numbered frames, folding devices with more channels
+/// than Opus supports down to stereo.
+pub struct FrameEncoder {
+    encoder: Encoder,
+    device_channels: u16,
+    stream_channels: u16,
+    sequence_number: u128,
+}
+
+impl FrameEncoder {
+    pub fn new(device_channels: u16) -> anyhow::Result<FrameEncoder> {
+        let stream_channels = std::cmp::min(device_channels, MAX_STREAM_CHANNELS);
+        let Some(channels) = u16_to_channels(stream_channels) else {
+            anyhow::bail!("Cannot send audio from a device with {device_channels} channels.");
+        };
+        Ok(FrameEncoder {
+            encoder: Encoder::new(48000, channels, Application::Audio)?,
+            device_channels,
+            stream_channels,
+            sequence_number: 0,
+        })
+    }
+
+    /// Encodes one chunk of 48 kHz samples in the device's channel layout.
+    pub fn encode(&mut self, mut samples: Vec<f32>) -> anyhow::Result<AudioFrame> {
+        if self.device_channels != self.stream_channels {
+            samples = remix_channels(&samples, self.device_channels, self.stream_channels);
+        }
+        let opus_frame = self.encoder.encode_vec_float(&samples[..], 65535)?;
+        let frame = AudioFrame(self.sequence_number, self.stream_channels, opus_frame);
+        self.sequence_number += 1;
+        Ok(frame)
+    }
+}
+
+fn u16_to_channels(n: u16) -> Option<Channels> {
     match n {
-        1 => Channels::Mono,
-        2 => Channels::Stereo,
-        _ => Channels::Stereo,
+        1 => Some(Channels::Mono),
+        2 => Some(Channels::Stereo),
+        _ => None,
+    }
+}
+
+/// Decodes incoming frames with the layout they were encoded in, recreating the
+/// Opus decoder whenever the sender's channel count changes.
+#[derive(Default)]
+pub struct FrameDecoder {
+    decoder: Option<(u16, Decoder)>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+
+    pub fn decode(&mut self, frame: AudioFrame) -> anyhow::Result<AudioChunk> {
+        let AudioFrame(sequence_number, channel_count, packet) = frame;
+        let Some(channels) = u16_to_channels(channel_count) else {
+            anyhow::bail!("Received audio frame with unsupported channel count {channel_count}.");
+        };
+        if !matches!(&self.decoder, Some((count, _)) if *count == channel_count) {
+            self.decoder = Some((channel_count, Decoder::new(48000, channels)?));
+        }
+        let Some((_, decoder)) = self.decoder.as_mut() else {
+            unreachable!("decoder was just initialized");
+        };
+
+        let len = decoder.get_nb_samples(&packet[..])? * (channel_count as usize);
+        let mut buf = vec![0f32; len];
+        decoder.decode_float(&packet[..], &mut buf[..], false)?;
+        let audio_format = AudioFormat::new(channel_count, 48000);
+        Ok(AudioChunk::new(sequence_number, audio_format, buf))
     }
 }
 
-async fn run_peer_message_sender(
-    mut conn: VeqSessionAlias,
+async fn run_peer_message_sender<S: TransportSession>(
+    mut conn: S,
     mut peer_message_receiver: broadcast::Receiver<ProtocolMessage>,
 ) {
     while let Ok(message) = peer_message_receiver.recv().await {
@@ -90,56 +255,80 @@ async fn run_peer_message_sender(
     }
 }
 
-async fn run_receiver(
-    mut conn: VeqSessionAlias,
+async fn run_member_key_sender<S: TransportSession>(mut conn: S, member_key: VerifyingKey) {
+    let mut interval = tokio::time::interval(MEMBER_KEY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut buf = Vec::new();
+        let message = ProtocolMessage::MemberKey(member_key);
+        if message.write_to_stream(&mut buf).await.is_ok() && conn.send(buf).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Starts playing on the backend's output whatever is given to the returned processor.
+/// Playback stops when the returned stream is dropped.
+pub fn start_playback(
+    backend: &dyn AudioBackend,
+    controls: PlaybackControls,
+    resampler_quality: ResamplerQuality,
     app_event_sender: Option<mpsc::UnboundedSender<AppEvent>>,
-    enable_denoise: Arc<AtomicBool>,
-    volume: Arc<Mutex<usize>>,
+    id: String,
+) -> anyhow::Result<(Arc<AudioProcessor<'static>>, OutputStream)> {
+    let format = backend.output_format()?;
+    let (processor, output) = AudioProcessor::new(
+        controls,
+        SampleRate(format.sample_rate),
+        format.channel_count,
+        resampler_quality,
+        app_event_sender,
+        id,
+    );
+    let output_stream = backend.start_output(output)?;
+    Ok((Arc::new(processor), output_stream))
+}
+
+async fn run_receiver<S: TransportSession>(
+    mut conn: S,
+    app_event_sender: Option<mpsc::UnboundedSender<AppEvent>>,
+    audio: CallAudio,
+    peer_member_key: ConfirmedMemberKey,
+    dump_dir: Option<PathBuf>,
     id: uuid::Uuid,
 ) {
     let id = id.to_string();
-    let host = cpal::default_host();
-    let output_device = host.default_output_device().unwrap();
-    let (sample_format, config) = get_output_config(&output_device);
-    let processor = Arc::new(AudioProcessor::new(
-        enable_denoise,
-        volume,
-        config.sample_rate,
+    // Without playback we still receive, for chat and dumps.
+    let playback = match start_playback(
+        audio.backend.as_ref(),
+        audio.controls,
+        audio.resampler_quality,
         app_event_sender.clone(),
         id.clone(),
-    ));
-    let processor_clone = processor.clone();
-    let config_clone = config.clone();
-    let mut output_stream_wrapper = send_safe::SendWrapperThread::new(move || {
-        setup_output_stream(
-            &sample_format,
-            &config_clone,
-            &output_device,
-            processor_clone,
-        )
-    });
-    output_stream_wrapper
-        .execute(|output_stream| {
-            output_stream.play().unwrap();
-        })
-        .unwrap();
-
-    let mut decoder = Decoder::new(48000, u16_to_channels(config.channels)).unwrap();
+    ) {
+        Ok(playback) => Some(playback),
+        Err(e) => {
+            log::error!("Failed to start playback for {id}: {:?}", e);
+            None
+        }
+    };
+    let mut decoder = FrameDecoder::new();
+    let mut dump: Option<FrameDump> = None;
 
     while let Ok(packet) = conn.recv().await {
         if let Ok(message) = ProtocolMessage::read_from_stream(&mut &packet[..]).await {
             match message {
                 ProtocolMessage::AudioFrame(frame) => {
-                    let packet = frame.1;
-                    let len =
-                        decoder.get_nb_samples(&packet[..]).unwrap() * (config.channels as usize);
-                    let mut buf = vec![0f32; len];
-                    decoder
-                        .decode_float(&packet[..], &mut buf[..], false)
-                        .unwrap();
-                    let audio_format = AudioFormat::new(config.channels, config.sample_rate.0);
-                    let chunk = AudioChunk::new(frame.0, audio_format, buf);
-                    processor.handle_incoming(chunk);
+                    if let Some(dump_dir) = &dump_dir {
+                        write_dump(&mut dump, dump_dir, &id, &frame);
+                    }
+                    let Some((processor, _)) = &playback else {
+                        continue;
+                    };
+                    match decoder.decode(frame) {
+                        Ok(chunk) => processor.handle_incoming(chunk),
+                        Err(e) => log::debug!("Failed to decode audio frame from {id}: {:?}", e),
+                    }
                 }
                 ProtocolMessage::IdentityDeclaration(_) => {}
                 ProtocolMessage::PeerDiscovery(_) => {}
@@ -150,37 +339,86 @@ async fn run_receiver(
                             .unwrap();
                     }
                 }
+                ProtocolMessage::RecordingNotice(is_recording) => {
+                    if let Some(app_event_sender) = &app_event_sender
+                        && let Err(e) = app_event_sender
+                            .send(AppEvent::SetPeerRecording(id.clone(), is_recording))
+                    {
+                        log::debug!("Failed to send recording notice to UI: {:?}", e);
+                    }
+                }
+                ProtocolMessage::MemberKey(member_key) => peer_member_key.confirm(member_key),
             }
         }
     }
 }
 
-pub async fn run_clerver(
-    conn: VeqSessionAlias,
+/// Adds a frame to this connection's dump, creating the dump on the first frame.
+fn write_dump(dump: &mut Option<FrameDump>, dump_dir: &Path, id: &str, frame: &AudioFrame) {
+    if dump.is_none() {
+        let file_name = format!(
+            "{}_{id}.opus",
+            chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
+        );
+        let created = std::fs::create_dir_all(dump_dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| FrameDump::create(&dump_dir.join(file_name)));
+        match created {
+            Ok(created) => *dump = Some(created),
+            Err(e) => log::debug!("Failed to create frame dump for {id}: {:?}", e),
+        }
+    }
+    if let Some(dump) = dump
+        && let Err(e) = dump.write(frame)
+    {
+        log::debug!("Failed to write frame dump for {id}: {:?}", e);
+    }
+}
+
+/// Runs the call with one peer over `conn`. `member_keys` holds our member key, which
+/// is sent to the peer, and where to put the one the peer sends us.
+pub async fn run_clerver<S: TransportSession>(
+    mut conn: S,
+    audio: CallAudio,
+    (own_member_key, peer_member_key): (VerifyingKey, ConfirmedMemberKey),
     app_event_sender: Option<mpsc::UnboundedSender<AppEvent>>,
-    sender_is_muted: Arc<AtomicBool>,
     peer_message_receiver: broadcast::Receiver<ProtocolMessage>,
-    enable_denoise: Arc<AtomicBool>,
-    volume: Arc<Mutex<usize>>,
+    dump_dir: Option<PathBuf>,
     id: uuid::Uuid,
 ) {
+    // Peers that connect mid-recording still need to be told about it.
+    if audio.controls.recorder.is_recording() {
+        let mut buf = Vec::new();
+        let notice = ProtocolMessage::RecordingNotice(true);
+        if notice.write_to_stream(&mut buf).await.is_err() || conn.send(buf).await.is_err() {
+            log::debug!("Failed to send recording notice to {id}.");
+        }
+    }
+
     tokio::select! {
         _ = run_audio_sender(
             conn.clone(),
-            sender_is_muted,
-            make_audio_receiver,
+            audio.sender_is_muted.clone(),
+            audio.listen_only.clone(),
+            audio.controls.deafened.clone(),
+            audio.backend.clone(),
+            audio.resampler_quality,
         ) => {
             log::debug!("Audio sender for {id} ended early.");
         },
         _ = run_receiver(
             conn.clone(),
             app_event_sender,
-            enable_denoise,
-            volume,
+            audio,
+            peer_member_key,
+            dump_dir,
             id,
         ) => {
             log::debug!("Receiver for {id} ended early.");
         },
+        _ = run_member_key_sender(conn.clone(), own_member_key) => {
+            log::debug!("Member key sender for {id} ended early.");
+        },
         _ = run_peer_message_sender(
             conn,
             peer_message_receiver,
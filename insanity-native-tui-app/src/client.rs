@@ -1,52 +1,58 @@
-use std::sync::Arc;
-
 use cpal::traits::DeviceTrait;
 use cpal::{BufferSize, Device, Sample, SampleFormat, SampleRate, Stream, StreamConfig};
 use itertools::Itertools;
 use log::debug;
 
-use crate::processor::AudioProcessor;
-use crate::processor::AUDIO_CHANNELS;
+use crate::processor::PlaybackOutput;
 
 fn run_output<T: Sample>(
     config: &cpal::StreamConfig,
     device: &Device,
-    processor: Arc<AudioProcessor<'static>>,
+    mut output: PlaybackOutput,
 ) -> Stream {
     let err_fn = |err| eprintln!("an error occurred in the output audio stream: {err}");
     device
         .build_output_stream(
             config,
             move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                processor.fill_buffer(data);
+                output.fill_buffer(data);
             },
             err_fn,
         )
         .unwrap()
 }
 
-fn find_stereo(range: cpal::SupportedOutputConfigs) -> Option<cpal::SupportedStreamConfigRange> {
+/// Prefers a config with the device's default channel count. Any count works, since
+/// playback is remixed to the device's layout.
+fn find_output(
+    range: cpal::SupportedOutputConfigs,
+    channels: Option<u16>,
+) -> Option<cpal::SupportedStreamConfigRange> {
     range
         .into_iter()
-        .find_or_last(|x| x.channels() == AUDIO_CHANNELS)
+        .find_or_last(|x| Some(x.channels()) == channels)
 }
 
 pub fn setup_output_stream(
     sample_format: &SampleFormat,
     config: &StreamConfig,
     device: &Device,
-    processor: Arc<AudioProcessor<'static>>,
+    output: PlaybackOutput,
 ) -> Stream {
     match sample_format {
-        SampleFormat::F32 => run_output::<f32>(config, device, processor),
-        SampleFormat::I16 => run_output::<i16>(config, device, processor),
-        SampleFormat::U16 => run_output::<u16>(config, device, processor),
+        SampleFormat::F32 => run_output::<f32>(config, device, output),
+        SampleFormat::I16 => run_output::<i16>(config, device, output),
+        SampleFormat::U16 => run_output::<u16>(config, device, output),
     }
 }
 
 pub fn get_output_config(device: &Device) -> (SampleFormat, StreamConfig) {
     let supported_configs_range = device.supported_output_configs().unwrap();
-    let supported_config_range = find_stereo(supported_configs_range).unwrap();
+    let default_channels = device
+        .default_output_config()
+        .ok()
+        .map(|config| config.channels());
+    let supported_config_range = find_output(supported_configs_range, default_channels).unwrap();
     let max_sample_rate = supported_config_range.max_sample_rate();
 
     let channels = supported_config_range.channels();
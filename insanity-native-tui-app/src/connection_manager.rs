@@ -1,16 +1,17 @@
 use std::{
     collections::HashMap,
     net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
 };
 
-use base64::{prelude::BASE64_URL_SAFE, Engine};
-use insanity_core::user_input_event::UserInputEvent;
-use insanity_tui_adapter::AppEvent;
+use ed25519_dalek::VerifyingKey;
+use insanity_core::{pan::auto_pan_positions, user_input_event::UserInputEvent};
+use insanity_tui_adapter::{AppEvent, PeerTrust};
+use rubato_audio_source::ResamplerQuality;
 
 use sha2::{Digest, Sha256};
 use veq::{snow_types::SnowKeypair, veq::VeqSocket};
@@ -19,27 +20,48 @@ use std::str::FromStr;
 use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 
-use crate::managed_peer::{ConnectionStatus, ManagedPeer};
-use veq::snow_types::SnowPublicKey;
-
-use baybridge::{
-    client::Actions,
-    connectors::{connection::Connection, http::HttpConnection},
+use crate::{
+    audio_device::{AudioBackend, CpalBackend},
+    bridges::Bridges,
+    clerver::record_own_audio,
+    invite::Invite,
+    managed_peer::{ConnectionStatus, ManagedPeer},
+    mic_test::{MicTest, MicTestOptions},
+    peer_settings::PeerSettingsStore,
+    recorder::Recorder,
+    room_access::RoomAccessStore,
+    room_handler::Presence,
+    room_secrets::RoomSecretStore,
+    rooms::JoinedRooms,
+    transport::{Transport, TransportInfo},
 };
 
 use crate::room_handler;
 
 const DB_KEY_PRIVATE_KEY: &str = "private_key";
+const RECORDINGS_DIR: &str = "recordings";
+const DUMPS_DIR: &str = "dumps";
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
-pub struct AugmentedInfo {
-    pub connection_info: veq::veq::ConnectionInfo,
+pub struct AugmentedInfo<I = veq::veq::ConnectionInfo> {
+    pub connection_info: I,
     pub display_name: String,
+    /// Key the peer signs its room entries with. Peers added directly have none.
+    pub member_key: Option<VerifyingKey>,
+}
+
+/// A peer to connect to, and the room it was found in if it wasn't added directly.
+#[derive(Clone, Debug)]
+pub struct DiscoveredPeer<I = veq::veq::ConnectionInfo> {
+    pub info: AugmentedInfo<I>,
+    pub room: Option<String>,
 }
 
-pub struct ConnectionManager {
-    socket: VeqSocket,
+pub struct ConnectionManager<T: Transport = VeqSocket> {
+    socket: T,
+    self_audio: SelfAudioState,
     cancellation_token: CancellationToken,
+    conn_info_tx: mpsc::UnboundedSender<DiscoveredPeer<T::Info>>,
     user_action_tx: mpsc::UnboundedSender<UserInputEvent>,
 }
 
@@ -52,83 +74,39 @@ impl ConnectionManager {
     ) -> ConnectionManagerBuilder {
         ConnectionManagerBuilder::new(base_dir, listen_port, bridge_servers, ip_version)
     }
+}
 
+impl<T: Transport> ConnectionManager<T> {
     pub fn shutdown(&self) {
         self.cancellation_token.cancel();
     }
 
+    /// Shuts down and waits until the recording, if there is one, is written out.
+    pub async fn shutdown_and_finish_recording(&self) {
+        self.shutdown();
+        self.self_audio.recorder.stop();
+        self.self_audio.recorder.finished().await;
+    }
+
     pub fn send_user_action(&self, action: UserInputEvent) -> anyhow::Result<()> {
         self.user_action_tx.send(action)?;
         Ok(())
     }
 
-    async fn start(
-        &mut self,
-        bridge_servers: Vec<String>,
-        room_name: Option<String>,
-        base_dir: PathBuf,
-        display_name: Option<String>,
-        app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
-        user_action_rx: mpsc::UnboundedReceiver<UserInputEvent>,
-    ) -> anyhow::Result<()> {
-        let connection_info = self.socket.connection_info();
-        log::debug!("Connection info: {:?}", connection_info);
-
-        let conn_info_tx = manage_peers(
-            self.socket.clone(),
-            app_event_tx.clone(),
-            user_action_rx,
-            self.cancellation_token.clone(),
-        );
-
-        if let Some(room_name) = &room_name {
-            log::debug!("Attempting to join room {room_name} on server {bridge_servers:?}.");
-
-            // Start up baybridge connection.
-            let baybridge_datadir = base_dir.join("baybridge");
-            let bridge_server_urls = bridge_servers
-                .iter()
-                .map(|s| url::Url::parse(s))
-                .collect::<Result<Vec<_>, _>>()?;
-            let connections = bridge_server_urls
-                .into_iter()
-                .map(|url| Connection::Http(HttpConnection::new(url)))
-                .collect();
-            let baybridge_config = baybridge::configuration::Configuration::new(
-                baybridge_datadir.clone(),
-                connections,
-            );
-            baybridge_config.init().await?;
-            let action = Actions::new(baybridge_config);
-
-            // Query self and add to UI.
-            if let Some(app_event_tx) = app_event_tx.clone() {
-                let my_public_key = action.whoami().await;
-                let my_public_key_base64 = BASE64_URL_SAFE.encode(my_public_key.as_bytes());
-                if let Err(e) = app_event_tx.send(AppEvent::SetOwnPublicKey(my_public_key_base64)) {
-                    log::debug!("Failed to write own public key to UI: {e}");
-                }
-            }
-
-            // Start connection to room on baybridge.
-            room_handler::start_room_connection(
-                action,
-                room_name,
-                connection_info,
-                display_name,
-                conn_info_tx,
-                app_event_tx.clone(),
-                self.cancellation_token.clone(),
-            )
-            .await?;
-        } else {
-            log::debug!("Not joining any room.");
-        }
-
+    /// Connects to a peer directly, without finding it through a room.
+    pub fn add_peer(&self, info: AugmentedInfo<T::Info>) -> anyhow::Result<()> {
+        self.conn_info_tx
+            .send(DiscoveredPeer { info, room: None })?;
         Ok(())
     }
 }
 
+/// Creates or opens the connection manager database. Only one process can have it open.
+fn open_db(base_dir: &Path) -> anyhow::Result<sled::Db> {
+    let sled_path = base_dir.join("connection_manager_data.sled");
+    Ok(sled::open(sled_path)?)
+}
+
 #[derive(clap::ValueEnum, Clone, Debug, serde::Deserialize)]
 pub enum IpVersion {
     Ipv4,
@@ -141,8 +119,13 @@ pub struct ConnectionManagerBuilder {
     listen_port: u16,
     bridge_servers: Vec<String>,
     ip_version: IpVersion,
-    room_name: Option<String>,
+    room_names: Vec<String>,
+    invites: Vec<Invite>,
     display_name: Option<String>,
+    record: bool,
+    dump_received: bool,
+    audio_backend: Arc<dyn AudioBackend>,
+    resampler_quality: ResamplerQuality,
     cancellation_token: Option<CancellationToken>,
     app_event_sender: Option<mpsc::UnboundedSender<AppEvent>>,
 }
@@ -159,18 +142,46 @@ impl ConnectionManagerBuilder {
             listen_port,
             bridge_servers,
             ip_version,
-            room_name: None,
+            room_names: vec![],
+            invites: vec![],
             display_name: None,
+            record: false,
+            dump_received: false,
+            audio_backend: Arc::new(CpalBackend),
+            resampler_quality: ResamplerQuality::default(),
             cancellation_token: None,
             app_event_sender: None,
         }
     }
 
+    /// Joins a room at startup. Can be called again to join several; the first one joined
+    /// is where the microphone goes until switched with `UserInputEvent::SetTalkRoom`.
+    /// Rooms can also be joined and left later with `UserInputEvent::JoinRoom` and
+    /// `UserInputEvent::LeaveRoom`.
     pub fn room(self, room_name: String) -> ConnectionManagerBuilder {
+        let mut room_names = self.room_names;
+        if !room_names.contains(&room_name) {
+            room_names.push(room_name);
+        }
+        ConnectionManagerBuilder { room_names, ..self }
+    }
+
+    /// Joins the invite's room at startup like `room`, using the invite's bridge server and
+    /// remembering the room's secret.
+    pub fn join(self, invite: Invite) -> ConnectionManagerBuilder {
+        let mut bridge_servers = self.bridge_servers;
+        if !bridge_servers.contains(&invite.bridge) {
+            bridge_servers.push(invite.bridge.clone());
+        }
+        let mut invites = self.invites;
+        let room_name = invite.room.name.clone();
+        invites.push(invite);
         ConnectionManagerBuilder {
-            room_name: Some(room_name),
+            bridge_servers,
+            invites,
             ..self
         }
+        .room(room_name)
     }
 
     pub fn display_name(self, display_name: String) -> ConnectionManagerBuilder {
@@ -180,6 +191,38 @@ impl ConnectionManagerBuilder {
         }
     }
 
+    /// Starts recording the call as soon as the connection manager starts.
+    pub fn record(self, record: bool) -> ConnectionManagerBuilder {
+        ConnectionManagerBuilder { record, ..self }
+    }
+
+    /// Dumps the audio frames received from each peer, for debugging.
+    pub fn dump_received(self, dump_received: bool) -> ConnectionManagerBuilder {
+        ConnectionManagerBuilder {
+            dump_received,
+            ..self
+        }
+    }
+
+    /// Where call audio is captured and played. Defaults to the cpal devices.
+    pub fn audio_backend(self, audio_backend: Arc<dyn AudioBackend>) -> ConnectionManagerBuilder {
+        ConnectionManagerBuilder {
+            audio_backend,
+            ..self
+        }
+    }
+
+    /// How carefully audio is resampled between the devices' rates and the call's.
+    pub fn resampler_quality(
+        self,
+        resampler_quality: ResamplerQuality,
+    ) -> ConnectionManagerBuilder {
+        ConnectionManagerBuilder {
+            resampler_quality,
+            ..self
+        }
+    }
+
     pub fn cancellation_token(
         self,
         cancellation_token: CancellationToken,
@@ -202,15 +245,10 @@ impl ConnectionManagerBuilder {
 
     /// Creates the local socket, uploads connection info, and begins searching for connections.
     pub async fn start(self) -> anyhow::Result<ConnectionManager> {
-        let cancellation_token = self.cancellation_token.unwrap_or_default();
-
-        // Create or open connection manager database.
-        let sled_path = self.base_dir.join("connection_manager_data.sled");
-        let db = sled::open(sled_path)?;
+        let db = open_db(&self.base_dir)?;
 
         // Create local socket.
         let keypair: SnowKeypair = get_or_make_keypair(&db)?;
-
         let socket = match self.ip_version {
             IpVersion::Ipv4 => {
                 let v4_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.listen_port);
@@ -233,63 +271,203 @@ impl ConnectionManagerBuilder {
             }
         };
 
+        self.start_on(db, socket).await
+    }
+
+    /// Like `start`, but over the given transport instead of a veq socket. The listen port
+    /// and IP version are ignored.
+    pub async fn start_with_transport<T: Transport>(
+        self,
+        transport: T,
+    ) -> anyhow::Result<ConnectionManager<T>> {
+        let db = open_db(&self.base_dir)?;
+        self.start_on(db, transport).await
+    }
+
+    async fn start_on<T: Transport>(
+        self,
+        db: sled::Db,
+        socket: T,
+    ) -> anyhow::Result<ConnectionManager<T>> {
+        let cancellation_token = self.cancellation_token.unwrap_or_default();
+        let peer_settings = PeerSettingsStore::open(&db)?;
+        let room_secrets = RoomSecretStore::open(&self.base_dir);
+        let room_access = RoomAccessStore::open(&self.base_dir)?;
+        for invite in self.invites.iter() {
+            room_secrets.save(&invite.room)?;
+        }
+        let self_audio = SelfAudioState::new(
+            self.audio_backend.clone(),
+            self.resampler_quality,
+            self.base_dir.join(RECORDINGS_DIR),
+            self.dump_received.then(|| self.base_dir.join(DUMPS_DIR)),
+        );
+        // Calls still work without rooms, so a bad bridge setup isn't fatal.
+        let bridges = match Bridges::connect(
+            &self.base_dir,
+            &self.bridge_servers,
+            self.app_event_sender.clone(),
+        )
+        .await
+        {
+            Ok(bridges) => bridges,
+            Err(e) => {
+                log::error!(
+                    "Failed to set up bridge servers, so rooms can't be joined: {:?}",
+                    e
+                );
+                None
+            }
+        };
+        log::debug!("Connection info: {:?}", socket.connection_info());
+        let presence = Presence {
+            connection_info: socket.connection_info(),
+            display_name: self.display_name,
+            signing_key: room_handler::member_signing_key(&get_or_make_keypair(&db)?)?,
+        };
+        let rooms = JoinedRooms::new(
+            bridges,
+            presence,
+            self.app_event_sender.clone(),
+            cancellation_token.clone(),
+            room_secrets,
+            room_access,
+        );
+
         let (user_action_tx, user_action_rx) = mpsc::unbounded_channel();
-        let mut connection_manager = ConnectionManager {
+        let conn_info_tx = manage_peers(
+            socket.clone(),
+            peer_settings,
+            self_audio.clone(),
+            rooms,
+            self.app_event_sender.clone(),
+            user_action_rx,
+            cancellation_token.clone(),
+        );
+        let connection_manager = ConnectionManager {
             socket,
+            self_audio,
             cancellation_token,
+            conn_info_tx,
             user_action_tx,
         };
-        connection_manager
-            .start(
-                self.bridge_servers,
-                self.room_name,
-                self.base_dir,
-                self.display_name,
-                self.app_event_sender,
-                user_action_rx,
-            )
-            .await?;
+        for room_name in self.room_names {
+            connection_manager.send_user_action(UserInputEvent::JoinRoom(room_name))?;
+        }
+        if self.record {
+            connection_manager.send_user_action(UserInputEvent::SetRecording(true))?;
+        }
         Ok(connection_manager)
     }
 }
 
+/// Our own audio devices and mute, deafen and recording state, shared with every
+/// peer's connection.
+#[derive(Clone)]
+struct SelfAudioState {
+    backend: Arc<dyn AudioBackend>,
+    resampler_quality: ResamplerQuality,
+    sender_is_muted: Arc<AtomicBool>,
+    deafened: Arc<AtomicBool>,
+    recorder: Recorder,
+    recordings_dir: PathBuf,
+    /// Where received frames are dumped, if they are.
+    dump_dir: Option<PathBuf>,
+    mic_test: Arc<Mutex<Option<MicTest>>>,
+}
+
+impl SelfAudioState {
+    fn new(
+        backend: Arc<dyn AudioBackend>,
+        resampler_quality: ResamplerQuality,
+        recordings_dir: PathBuf,
+        dump_dir: Option<PathBuf>,
+    ) -> SelfAudioState {
+        SelfAudioState {
+            backend,
+            resampler_quality,
+            sender_is_muted: Arc::new(AtomicBool::new(false)),
+            deafened: Arc::new(AtomicBool::new(false)),
+            recorder: Recorder::new(),
+            recordings_dir,
+            dump_dir,
+            mic_test: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
 /// Receive peer augmented info over channel and connect to peer.
-fn manage_peers(
-    socket: veq::veq::VeqSocket,
+fn manage_peers<T: Transport>(
+    socket: T,
+    peer_settings: PeerSettingsStore,
+    self_audio: SelfAudioState,
+    mut rooms: JoinedRooms<T::Info>,
     app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
     mut user_action_rx: mpsc::UnboundedReceiver<UserInputEvent>,
     cancellation_token: CancellationToken,
-) -> mpsc::UnboundedSender<AugmentedInfo> {
+) -> mpsc::UnboundedSender<DiscoveredPeer<T::Info>> {
     // Channel for the manage_peers task to receive updated peers info.
-    let (conn_info_tx, mut conn_info_rx) = mpsc::unbounded_channel::<AugmentedInfo>();
-    let sender_is_muted = Arc::new(AtomicBool::new(false));
+    let (conn_info_tx, mut conn_info_rx) = mpsc::unbounded_channel::<DiscoveredPeer<T::Info>>();
+    let peer_conn_info_tx = conn_info_tx.clone();
     tokio::spawn(async move {
-        let mut managed_peers: HashMap<uuid::Uuid, ManagedPeer> = HashMap::new();
+        let mut managed_peers: HashMap<uuid::Uuid, ManagedPeer<T>> = HashMap::new();
         loop {
             tokio::select! {
-                Some(augmented_info) = conn_info_rx.recv() => {
-                    if socket.connection_info().public_key == augmented_info.connection_info.public_key {
+                Some(DiscoveredPeer { info: augmented_info, room }) = conn_info_rx.recv() => {
+                    if room.as_ref().is_some_and(|room| !rooms.contains(room)) {
+                        // Found just before we left the room.
+                        continue;
+                    }
+                    let own_identity = socket.connection_info().identity();
+                    let peer_identity = augmented_info.connection_info.identity();
+                    if own_identity == peer_identity {
                         // Don't try to connect to self.
                         continue;
                     }
-                    let id = snow_public_keys_to_uuid(&socket.connection_info().public_key, &augmented_info.connection_info.public_key);
-                    if let Some(managed_peer) = update_peer_info(
+                    if let Some(room) = &room
+                        && !rooms.permits(room, &peer_identity)
+                    {
+                        log::debug!("Refusing {peer_identity} in room {room}.");
+                        continue;
+                    }
+                    let id = identities_to_uuid(&own_identity, &peer_identity);
+                    if let Some(managed_peer) = managed_peers.get(&id)
+                        && !managed_peer.admits_info(&augmented_info)
+                    {
+                        log::warn!(
+                            "Ignoring entry for {peer_identity} signed by a key other than its own."
+                        );
+                        continue;
+                    }
+                    let updated_peer = update_peer_info(
                         id, augmented_info,
                         socket.clone(),
+                        (&peer_settings, &rooms),
                         app_event_tx.clone(),
                         &mut managed_peers,
-                        sender_is_muted.clone()) {
+                        &self_audio);
+                    // Before connecting, since the room is what lets the session in.
+                    if let Some(room) = room
+                        && let Some(managed_peer) = managed_peers.get(&id)
+                    {
+                        managed_peer.add_room(room, rooms.talk_room());
+                    }
+                    if let Some(managed_peer) = updated_peer {
                         log::debug!("Updated peer info for {id} to: {:?}", managed_peer.info());
                         log::debug!("(Re)Connecting to peer {id}.");
                         reconnect(managed_peer);
                     }
                 },
                 Some(user_action) = user_action_rx.recv() => {
-                    if let Err(e) = handle_user_action(user_action, sender_is_muted.clone(), app_event_tx.clone(), &mut managed_peers) {
+                    let rooms = (&mut rooms, &peer_conn_info_tx);
+                    if let Err(e) = handle_user_action(user_action, &self_audio, rooms, &peer_settings, app_event_tx.clone(), &mut managed_peers) {
                         log::debug!("Failed to handle user action: {:?}", e);
                     }
                 }
                 _ = cancellation_token.cancelled() => {
+                    // Finalize the recording files before exiting.
+                    self_audio.recorder.stop();
+                    self_audio.recorder.finished().await;
                     log::debug!("Peer connector shutdown.");
                     break;
                 }
@@ -299,7 +477,7 @@ fn manage_peers(
     conn_info_tx
 }
 
-fn reconnect(managed_peer: ManagedPeer) {
+fn reconnect<T: Transport>(managed_peer: ManagedPeer<T>) {
     match managed_peer.connection_status() {
         ConnectionStatus::Disabled => {
             managed_peer.enable();
@@ -318,18 +496,29 @@ fn reconnect(managed_peer: ManagedPeer) {
 }
 
 /// Returns Some containing the old peer and the updated peer, or None if no peer updated.
-fn update_peer_info(
+fn update_peer_info<T: Transport>(
     id: uuid::Uuid,
-    new_info: AugmentedInfo,
-    socket: veq::veq::VeqSocket,
+    new_info: AugmentedInfo<T::Info>,
+    socket: T,
+    (peer_settings, rooms): (&PeerSettingsStore, &JoinedRooms<T::Info>),
     app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
-    managed_peers: &mut HashMap<uuid::Uuid, ManagedPeer>,
-    sender_is_muted: Arc<AtomicBool>,
-) -> Option<ManagedPeer> {
+    managed_peers: &mut HashMap<uuid::Uuid, ManagedPeer<T>>,
+    self_audio: &SelfAudioState,
+) -> Option<ManagedPeer<T>> {
     match managed_peers.get_mut(&id) {
         Some(current_managed_peer) => {
             // If already have this peer, update the managed peer as necessary.
             if current_managed_peer.info() != new_info {
+                let trust = current_managed_peer.trust();
+                if new_info.display_name != current_managed_peer.info().display_name
+                    && trust != PeerTrust::Verified
+                {
+                    let identity = new_info.connection_info.identity();
+                    let new_trust = name_trust(peer_settings, &new_info.display_name, &identity);
+                    if let Err(e) = current_managed_peer.set_trust(new_trust) {
+                        log::debug!("Failed to update peer trust: {:?}", e);
+                    }
+                }
                 current_managed_peer.set_info(new_info);
                 Some(current_managed_peer.clone())
             } else {
@@ -337,40 +526,105 @@ fn update_peer_info(
             }
         }
         None => {
-            // If new peer, add to managed peers.
+            // If new peer, add to managed peers with whatever we remember about them.
+            let identity = new_info.connection_info.identity();
+            let settings = peer_settings.load(&identity);
+            let trust = if settings.verified {
+                PeerTrust::Verified
+            } else {
+                name_trust(peer_settings, &new_info.display_name, &identity)
+            };
             let managed_peer = ManagedPeer::builder()
                 .id(id)
                 .connection_info(new_info.connection_info)
                 .socket(socket)
+                .room_access(rooms.access().clone())
                 .maybe_app_event_tx(app_event_tx)
                 .display_name(new_info.display_name)
-                .denoise(true)
-                .volume(100)
-                .sender_is_muted(sender_is_muted)
+                .maybe_member_key(new_info.member_key)
+                .own_member_key(rooms.member_key())
+                .denoise(settings.denoise)
+                .volume(settings.volume)
+                .pan(settings.initial_pan())
+                .equalizer(settings.equalizer)
+                .muted(settings.muted)
+                .trust(trust)
+                .sender_is_muted(self_audio.sender_is_muted.clone())
+                .deafened(self_audio.deafened.clone())
+                .recorder(self_audio.recorder.clone())
+                .audio_backend(self_audio.backend.clone())
+                .resampler_quality(self_audio.resampler_quality)
+                .maybe_dump_dir(self_audio.dump_dir.clone())
                 .build();
             managed_peers.insert(id, managed_peer.clone());
+            spread_peers(managed_peers);
             Some(managed_peer)
         }
     }
 }
 
-fn handle_user_action(
+/// Trust on first use: a peer is as trusted as anyone else until its name turns up with a
+/// key other than the one it was first seen with. A name we can't check isn't trusted.
+fn name_trust(peer_settings: &PeerSettingsStore, display_name: &str, identity: &str) -> PeerTrust {
+    match peer_settings.check_name(display_name, identity) {
+        Ok(true) => PeerTrust::Unverified,
+        Ok(false) => {
+            log::warn!("{display_name} has a different key than the first time they were seen.");
+            PeerTrust::KeyChanged
+        }
+        Err(e) => {
+            log::error!(
+                "Failed to check the key {display_name} was first seen with: {:?}",
+                e
+            );
+            PeerTrust::KeyChanged
+        }
+    }
+}
+
+/// Spreads peers evenly across the stereo field in a stable order.
+fn spread_peers<T: Transport>(managed_peers: &HashMap<uuid::Uuid, ManagedPeer<T>>) {
+    let mut ids: Vec<&uuid::Uuid> = managed_peers.keys().collect();
+    ids.sort();
+    for (id, position) in ids.iter().zip(auto_pan_positions(ids.len())) {
+        if let Err(e) = managed_peers[*id].set_auto_pan(position) {
+            log::debug!("Failed to update automatic pan for {id}: {:?}", e);
+        }
+    }
+}
+
+/// Remembers the peer's current settings for future sessions.
+fn save_peer_settings<T: Transport>(peer_settings: &PeerSettingsStore, peer: &ManagedPeer<T>) {
+    let identity = peer.info().connection_info.identity();
+    if let Err(e) = peer_settings.save(&identity, &peer.settings()) {
+        log::debug!("Failed to save peer settings: {:?}", e);
+    }
+}
+
+fn handle_user_action<T: Transport>(
     user_action: UserInputEvent,
-    sender_is_muted: Arc<AtomicBool>,
+    self_audio: &SelfAudioState,
+    (rooms, conn_info_tx): (
+        &mut JoinedRooms<T::Info>,
+        &mpsc::UnboundedSender<DiscoveredPeer<T::Info>>,
+    ),
+    peer_settings: &PeerSettingsStore,
     app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
-    managed_peers: &mut HashMap<uuid::Uuid, ManagedPeer>,
+    managed_peers: &mut HashMap<uuid::Uuid, ManagedPeer<T>>,
 ) -> anyhow::Result<()> {
     match user_action {
         UserInputEvent::DisableDenoise(id) => {
             let id = uuid::Uuid::from_str(&id)?;
             if let Some(peer) = managed_peers.get(&id) {
                 peer.set_denoise(false)?;
+                save_peer_settings(peer_settings, peer);
             }
         }
         UserInputEvent::EnableDenoise(id) => {
             let id = uuid::Uuid::from_str(&id)?;
             if let Some(peer) = managed_peers.get(&id) {
                 peer.set_denoise(true)?;
+                save_peer_settings(peer_settings, peer);
             }
         }
         UserInputEvent::DisablePeer(id) => {
@@ -389,6 +643,29 @@ fn handle_user_action(
             let id = uuid::Uuid::from_str(&id)?;
             if let Some(peer) = managed_peers.get(&id) {
                 peer.set_volume(volume)?;
+                save_peer_settings(peer_settings, peer);
+            }
+        }
+        UserInputEvent::SetPan(id, pan) => {
+            let id = uuid::Uuid::from_str(&id)?;
+            if let Some(peer) = managed_peers.get(&id) {
+                peer.set_pan(pan)?;
+                save_peer_settings(peer_settings, peer);
+            }
+            spread_peers(managed_peers);
+        }
+        UserInputEvent::SetEqualizer(id, equalizer) => {
+            let id = uuid::Uuid::from_str(&id)?;
+            if let Some(peer) = managed_peers.get(&id) {
+                peer.set_equalizer(equalizer)?;
+                save_peer_settings(peer_settings, peer);
+            }
+        }
+        UserInputEvent::SetMutePeer(id, muted) => {
+            let id = uuid::Uuid::from_str(&id)?;
+            if let Some(peer) = managed_peers.get(&id) {
+                peer.set_muted(muted)?;
+                save_peer_settings(peer_settings, peer);
             }
         }
         UserInputEvent::SendMessage(message) => {
@@ -399,25 +676,180 @@ fn handle_user_action(
             }
         }
         UserInputEvent::SetMuteSelf(is_muted) => {
-            sender_is_muted.store(is_muted, Ordering::Relaxed);
+            self_audio
+                .sender_is_muted
+                .store(is_muted, Ordering::Relaxed);
             if let Some(app_event_tx) = app_event_tx
                 && let Err(e) = app_event_tx.send(AppEvent::MuteSelf(is_muted)) {
                     log::debug!("Failed to send mute self event: {:?}", e);
                 }
         }
+        UserInputEvent::SetDeafen(is_deafened) => {
+            self_audio.deafened.store(is_deafened, Ordering::Relaxed);
+            if let Some(app_event_tx) = app_event_tx
+                && let Err(e) = app_event_tx.send(AppEvent::Deafen(is_deafened))
+            {
+                log::debug!("Failed to send deafen event: {:?}", e);
+            }
+        }
+        UserInputEvent::SetRecording(is_recording) => {
+            if is_recording {
+                if let Some(recording) = self_audio.recorder.start(&self_audio.recordings_dir)? {
+                    tokio::spawn(record_own_audio(
+                        self_audio.backend.clone(),
+                        self_audio.resampler_quality,
+                        self_audio.sender_is_muted.clone(),
+                        self_audio.deafened.clone(),
+                        self_audio.recorder.clone(),
+                        recording.stopped,
+                    ));
+                }
+            } else {
+                self_audio.recorder.stop();
+            }
+            for peer in managed_peers.values() {
+                if let Err(e) = peer.send_recording_notice(is_recording) {
+                    log::debug!("Failed to send recording notice to a peer: {:?}", e);
+                }
+            }
+            if let Some(app_event_tx) = app_event_tx
+                && let Err(e) = app_event_tx.send(AppEvent::Recording(is_recording))
+            {
+                log::debug!("Failed to send recording event: {:?}", e);
+            }
+        }
+        UserInputEvent::SetMicTest(is_testing) => {
+            // Sent first so that a test failing right away turns the indicator back off.
+            if let Some(app_event_tx) = &app_event_tx
+                && let Err(e) = app_event_tx.send(AppEvent::MicTest(is_testing))
+            {
+                log::debug!("Failed to send mic test event: {:?}", e);
+            }
+            let mic_test = is_testing.then(|| {
+                MicTest::start(
+                    self_audio.backend.clone(),
+                    MicTestOptions {
+                        resampler_quality: self_audio.resampler_quality,
+                        ..MicTestOptions::default()
+                    },
+                    app_event_tx,
+                )
+            });
+            *self_audio.mic_test.lock().unwrap() = mic_test;
+        }
+        UserInputEvent::SetTalkRoom(room) => {
+            rooms.set_talk_room(room)?;
+            for peer in managed_peers.values() {
+                peer.set_talk_room(rooms.talk_room());
+            }
+        }
+        UserInputEvent::JoinRoom(room) => {
+            rooms.join(room, conn_info_tx)?;
+            for peer in managed_peers.values() {
+                peer.set_talk_room(rooms.talk_room());
+            }
+        }
+        UserInputEvent::LeaveRoom(room) => {
+            rooms.leave(&room)?;
+            remove_peers_from_rooms(managed_peers, app_event_tx, |peer| peer.remove_room(&room))?;
+            for peer in managed_peers.values() {
+                peer.set_talk_room(rooms.talk_room());
+            }
+        }
+        UserInputEvent::SetPeerVerified(id, verified) => {
+            let id = uuid::Uuid::from_str(&id)?;
+            if let Some(peer) = managed_peers.get(&id) {
+                let info = peer.info();
+                let identity = info.connection_info.identity();
+                if verified {
+                    // The name now belongs to this key, whoever had it first.
+                    peer_settings.pin_name(&info.display_name, &identity)?;
+                    peer.set_trust(PeerTrust::Verified)?;
+                } else {
+                    peer.set_trust(name_trust(peer_settings, &info.display_name, &identity))?;
+                }
+                save_peer_settings(peer_settings, peer);
+            }
+        }
+        UserInputEvent::AllowPeer(id) => {
+            set_peer_access(&id, true, rooms, app_event_tx, managed_peers)?;
+        }
+        UserInputEvent::DenyPeer(id) => {
+            set_peer_access(&id, false, rooms, app_event_tx, managed_peers)?;
+        }
+    }
+    Ok(())
+}
+
+/// Allows or denies the peer in every room we share with it.
+fn set_peer_access<T: Transport>(
+    id: &str,
+    allow: bool,
+    rooms: &JoinedRooms<T::Info>,
+    app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
+    managed_peers: &mut HashMap<uuid::Uuid, ManagedPeer<T>>,
+) -> anyhow::Result<()> {
+    let id = uuid::Uuid::from_str(id)?;
+    let Some(peer) = managed_peers.get(&id) else {
+        anyhow::bail!("No peer {id}.");
+    };
+    let key = peer.info().connection_info.identity();
+    let peer_rooms = peer.rooms();
+    if peer_rooms.is_empty() {
+        anyhow::bail!("Peer {id} was added directly, not through a room.");
+    }
+    for room in peer_rooms {
+        rooms.update_access(&room, |access| {
+            if allow {
+                access.allow(key.clone());
+            } else {
+                access.deny(key.clone());
+            }
+        })?;
+    }
+
+    // Allowing one peer can shut out the rest of the room.
+    remove_peers_from_rooms(managed_peers, app_event_tx, |peer| {
+        let key = peer.info().connection_info.identity();
+        let mut left_behind = false;
+        for room in peer.rooms() {
+            if !rooms.permits(&room, &key) {
+                left_behind |= peer.remove_room(&room);
+            }
+        }
+        left_behind
+    })
+}
+
+/// Disconnects the peers `leave_rooms` reports as sharing no room with us any more.
+fn remove_peers_from_rooms<T: Transport>(
+    managed_peers: &mut HashMap<uuid::Uuid, ManagedPeer<T>>,
+    app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
+    mut leave_rooms: impl FnMut(&ManagedPeer<T>) -> bool,
+) -> anyhow::Result<()> {
+    let left_behind: Vec<uuid::Uuid> = managed_peers
+        .iter()
+        .filter(|(_, peer)| leave_rooms(peer))
+        .map(|(id, _)| *id)
+        .collect();
+    for id in left_behind {
+        if let Some(peer) = managed_peers.remove(&id) {
+            peer.disable()?;
+            if let Some(app_event_tx) = &app_event_tx {
+                app_event_tx.send(AppEvent::RemovePeer(id.to_string()))?;
+            }
+        }
     }
+    spread_peers(managed_peers);
     Ok(())
 }
 
-// This converts snow public keys to strings and then does what
-// onion_addresses_to_uuid from the old code did.
+// This does what onion_addresses_to_uuid from the old code did,
+// on the transport identities (base64 public keys for veq).
 // Absolutely no clue what this is for.
 // TODO: why
-fn snow_public_keys_to_uuid(key1: &SnowPublicKey, key2: &SnowPublicKey) -> uuid::Uuid {
-    let key1_str = key1.clone().base64().to_string();
-    let key2_str = key2.clone().base64().to_string();
-
-    let lower_str = std::cmp::min(key1_str.clone(), key2_str.clone());
+fn identities_to_uuid(key1_str: &str, key2_str: &str) -> uuid::Uuid {
+    let lower_str = std::cmp::min(key1_str, key2_str);
     let higher_str = std::cmp::max(key1_str, key2_str);
 
     let mut hasher = Sha256::new();
@@ -449,3 +881,158 @@ fn get_or_make_keypair(db: &sled::Db) -> anyhow::Result<SnowKeypair> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use insanity_core::generators::{Generator, Sine};
+
+    use super::*;
+    use crate::{
+        audio_device::VirtualBackend,
+        transport::{LinkConditions, MemoryConnectionInfo, MemoryEndpoint, MemoryNetwork},
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// A whole client on a memory network, with a tone for a microphone.
+    struct Client {
+        manager: ConnectionManager<MemoryEndpoint>,
+        events: mpsc::UnboundedReceiver<AppEvent>,
+        heard: mpsc::UnboundedReceiver<Vec<f32>>,
+        _base_dir: tempfile::TempDir,
+    }
+
+    impl Client {
+        async fn start(network: &MemoryNetwork, name: &str) -> Client {
+            let base_dir = tempfile::tempdir().unwrap();
+            let (heard_tx, heard) = mpsc::unbounded_channel();
+            let backend = VirtualBackend::new(|| {
+                Generator::new(Sine::new(440.0), 48000, 1).with_amplitude(0.5)
+            })
+            .with_output_sink(heard_tx);
+            let (app_event_tx, events) = mpsc::unbounded_channel();
+            let manager = ConnectionManager::builder(
+                base_dir.path().to_path_buf(),
+                0,
+                vec![],
+                IpVersion::Ipv4,
+            )
+            .display_name(name.to_string())
+            .audio_backend(Arc::new(backend))
+            .app_event_sender(app_event_tx)
+            .start_with_transport(network.endpoint(name))
+            .await
+            .unwrap();
+            Client {
+                manager,
+                events,
+                heard,
+                _base_dir: base_dir,
+            }
+        }
+
+        fn info(&self) -> AugmentedInfo<MemoryConnectionInfo> {
+            let connection_info = self.manager.socket.connection_info();
+            AugmentedInfo {
+                display_name: connection_info.name.clone(),
+                connection_info,
+                member_key: None,
+            }
+        }
+
+        async fn next_event(&mut self) -> AppEvent {
+            self.events.recv().await.expect("Client stopped.")
+        }
+
+        /// Waits until playback carries more than silence.
+        async fn hear_audio(&mut self) {
+            while let Some(block) = self.heard.recv().await {
+                if block.iter().any(|sample| sample.abs() > 0.1) {
+                    return;
+                }
+            }
+            panic!("Playback stopped.");
+        }
+    }
+
+    /// Connects the two clients directly and turns off denoising, which takes out tones.
+    async fn connect(a: &mut Client, b: &mut Client) {
+        a.manager.add_peer(b.info()).unwrap();
+        b.manager.add_peer(a.info()).unwrap();
+        let id = identities_to_uuid(
+            &a.info().connection_info.identity(),
+            &b.info().connection_info.identity(),
+        );
+        for client in [a, b] {
+            while !matches!(client.next_event().await, AppEvent::AddPeer(_)) {}
+            client
+                .manager
+                .send_user_action(UserInputEvent::DisableDenoise(id.to_string()))
+                .unwrap();
+        }
+    }
+
+    /// Sends `message` from `from` until `to` receives it, since messages sent before
+    /// the connection is up are lost.
+    async fn deliver(from: &Client, to: &mut Client, message: &str) {
+        loop {
+            from.manager
+                .send_user_action(UserInputEvent::SendMessage(message.to_string()))
+                .unwrap();
+            let received = tokio::time::timeout(Duration::from_millis(200), async {
+                loop {
+                    if let AppEvent::NewMessage(_, received) = to.next_event().await
+                        && received == message
+                    {
+                        return;
+                    }
+                }
+            });
+            if received.await.is_ok() {
+                return;
+            }
+        }
+    }
+
+    /// Connects two clients over a network with `conditions` and has them message and
+    /// hear each other.
+    async fn call_over(conditions: LinkConditions) {
+        let network = MemoryNetwork::new(1, conditions);
+        let mut alice = Client::start(&network, "alice").await;
+        let mut bob = Client::start(&network, "bob").await;
+
+        let call = async {
+            connect(&mut alice, &mut bob).await;
+            deliver(&alice, &mut bob, "hello bob").await;
+            deliver(&bob, &mut alice, "hello alice").await;
+            alice.hear_audio().await;
+            bob.hear_audio().await;
+        };
+        tokio::time::timeout(TIMEOUT, call)
+            .await
+            .expect("Clients didn't connect and hear each other in time.");
+
+        alice.manager.shutdown_and_finish_recording().await;
+        bob.manager.shutdown_and_finish_recording().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn two_clients_talk_over_a_memory_network() {
+        call_over(LinkConditions::default()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn two_clients_talk_over_a_lossy_reordering_network() {
+        call_over(LinkConditions {
+            latency: Duration::from_millis(30),
+            jitter: Duration::from_millis(10),
+            loss: 0.05,
+            reordering: 0.1,
+            duplication: 0.02,
+            ..LinkConditions::default()
+        })
+        .await;
+    }
+}
@@ -0,0 +1,166 @@
+//! Following the sender's clock. Two sound cards never run at exactly the same rate, so
+//! a peer's packets arrive a little faster or slower than we play them and the jitter
+//! buffer slowly fills up or runs dry. Watching how full it stays tells us by how much,
+//! and playing back at a slightly adjusted rate holds it steady.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Jitter buffer depth to hold, in chunks.
+const TARGET_DEPTH: f64 = 2.0;
+/// How long the depth is averaged over, so single packets arriving don't count.
+const SMOOTHING: Duration = Duration::from_secs(2);
+/// How often the playback rate is adjusted.
+const ADJUST_PERIOD: Duration = Duration::from_millis(100);
+/// An empty buffer for this long means the peer stopped sending, not that it's slow.
+const STALL_TIMEOUT: Duration = Duration::from_millis(200);
+/// Rate change per chunk away from the target depth. At this gain an extra chunk drains
+/// in about ten seconds, slow enough not to be heard.
+const PROPORTIONAL_GAIN: f64 = 0.001;
+/// Rate change per chunk-second away from the target depth; what builds up here is the
+/// estimated drift.
+const INTEGRAL_GAIN: f64 = PROPORTIONAL_GAIN / 60.0;
+/// Furthest the playback rate is ever moved. Real clocks are within a few hundred ppm.
+const MAX_CORRECTION: f64 = 0.005;
+
+/// Turns jitter buffer depth over time into a relative playback ratio. Above 1.0 the
+/// buffer is played slower, letting it fill, and below 1.0 faster.
+pub struct DriftCompensator {
+    smoothed_depth: Option<f64>,
+    /// Accumulated depth error, in chunk-seconds.
+    integral: f64,
+    last_sample: Option<Instant>,
+    last_adjustment: Option<Instant>,
+    last_filled: Option<Instant>,
+}
+
+impl Default for DriftCompensator {
+    fn default() -> Self {
+        DriftCompensator::new()
+    }
+}
+
+impl DriftCompensator {
+    pub fn new() -> DriftCompensator {
+        DriftCompensator {
+            smoothed_depth: None,
+            integral: 0.0,
+            last_sample: None,
+            last_adjustment: None,
+            last_filled: None,
+        }
+    }
+
+    /// Estimated clock drift between sender and receiver, in parts per million. Positive
+    /// when the sender runs fast.
+    pub fn drift_ppm(&self) -> f64 {
+        INTEGRAL_GAIN * self.integral * 1e6
+    }
+
+    /// Takes the current buffer depth in chunks. Returns the ratio to play at when it's
+    /// time to adjust.
+    pub fn update(&mut self, depth: usize, now: Instant) -> Option<f64> {
+        let elapsed = self
+            .last_sample
+            .map_or(Duration::ZERO, |last_sample| now - last_sample);
+        self.last_sample = Some(now);
+        if depth > 0 {
+            self.last_filled = Some(now);
+        }
+        let stalled = self
+            .last_filled
+            .is_none_or(|last_filled| now - last_filled > STALL_TIMEOUT);
+        if stalled {
+            // Start over when audio comes back, rather than remember the silence.
+            self.smoothed_depth = None;
+            return None;
+        }
+
+        let weight = (elapsed.as_secs_f64() / SMOOTHING.as_secs_f64()).min(1.0);
+        let smoothed_depth = match self.smoothed_depth {
+            Some(smoothed_depth) => smoothed_depth + weight * (depth as f64 - smoothed_depth),
+            None => depth as f64,
+        };
+        self.smoothed_depth = Some(smoothed_depth);
+
+        let error = smoothed_depth - TARGET_DEPTH;
+        // Limited so a long stretch away from the target can't wind it past what it could
+        // ever correct.
+        let max_integral = MAX_CORRECTION / INTEGRAL_GAIN;
+        self.integral =
+            (self.integral + error * elapsed.as_secs_f64()).clamp(-max_integral, max_integral);
+
+        if self
+            .last_adjustment
+            .is_some_and(|last_adjustment| now - last_adjustment < ADJUST_PERIOD)
+        {
+            return None;
+        }
+        self.last_adjustment = Some(now);
+        let correction = PROPORTIONAL_GAIN * error + INTEGRAL_GAIN * self.integral;
+        Some(1.0 - correction.clamp(-MAX_CORRECTION, MAX_CORRECTION))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time each chunk in the jitter buffer covers.
+    const CHUNK: Duration = Duration::from_millis(10);
+    const STEP: Duration = Duration::from_millis(2);
+
+    /// Plays a sender whose clock runs `skew_ppm` fast for `duration`, returning the
+    /// compensator and the shallowest and deepest the buffer got once it settled.
+    fn simulate(skew_ppm: f64, duration: Duration) -> (DriftCompensator, f64, f64) {
+        let mut drift = DriftCompensator::new();
+        let start = Instant::now();
+        let settle = Duration::from_secs(120);
+        let steps = duration.as_nanos() / STEP.as_nanos();
+        let chunks_per_step = STEP.as_secs_f64() / CHUNK.as_secs_f64();
+
+        let mut depth = TARGET_DEPTH;
+        let mut ratio = 1.0;
+        let (mut shallowest, mut deepest) = (f64::MAX, f64::MIN);
+        for step in 0..steps {
+            depth += chunks_per_step * (1.0 + skew_ppm / 1e6);
+            // Playing at `ratio` stretches every chunk by it.
+            depth = (depth - chunks_per_step / ratio).max(0.0);
+            let now = start + STEP * step as u32;
+            if let Some(new_ratio) = drift.update(depth as usize, now) {
+                ratio = new_ratio;
+            }
+            if now - start > settle {
+                shallowest = shallowest.min(depth);
+                deepest = deepest.max(depth);
+            }
+        }
+        (drift, shallowest, deepest)
+    }
+
+    #[test]
+    fn depth_stays_bounded_over_an_hour_of_skew() {
+        for skew_ppm in [-300.0, -50.0, 0.0, 50.0, 300.0] {
+            let (drift, shallowest, deepest) = simulate(skew_ppm, Duration::from_secs(3600));
+            assert!(
+                shallowest >= 1.0 && deepest <= 4.0,
+                "{skew_ppm} ppm: depth went from {shallowest} to {deepest}"
+            );
+            assert!(
+                (drift.drift_ppm() - skew_ppm).abs() < 20.0,
+                "{skew_ppm} ppm: estimated {}",
+                drift.drift_ppm()
+            );
+        }
+    }
+
+    #[test]
+    fn an_empty_buffer_pauses_adjustment() {
+        let mut drift = DriftCompensator::new();
+        let start = Instant::now();
+        assert!(drift.update(2, start).is_some());
+        assert!(drift.update(0, start + ADJUST_PERIOD).is_some());
+        assert_eq!(drift.update(0, start + ADJUST_PERIOD + STALL_TIMEOUT * 2), None);
+    }
+}
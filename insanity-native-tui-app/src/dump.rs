@@ -0,0 +1,342 @@
+//! Dumps of the audio frames received from a peer, for debugging bad calls.
+//!
+//! A dump is an Ogg file with two logical streams. The Opus stream holds the packets
+//! exactly as received, so the file plays in ordinary Ogg Opus players, with granule
+//! positions counting decoded samples as RFC 7845 asks. Opus decodes any packet to
+//! stereo, so the header always says two channels. Alongside it, a metadata stream
+//! holds each packet's sequence number and channel count, and uses the arrival time, in
+//! 48 kHz samples since the dump started, as granule position. That keeps the jitter,
+//! reordering and loss of the original call for replaying.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clerver::AudioFrame,
+    ogg::{OggReader, OggStream, HEADER_TYPE_BOS, HEADER_TYPE_EOS},
+};
+
+const SAMPLE_RATE: u64 = 48000;
+const OPUS_SERIAL: u32 = 1;
+const META_SERIAL: u32 = 2;
+const OPUS_HEAD_MAGIC: &[u8; 8] = b"OpusHead";
+const OPUS_TAGS_MAGIC: &[u8; 8] = b"OpusTags";
+const OPUS_HEAD_CHANNELS: u8 = 2;
+const META_MAGIC: &[u8; 14] = b"InsanityFrames";
+const VENDOR: &str = "insanity";
+/// Sequence number (u128) followed by channel count (u16), both little endian.
+const META_PACKET_LEN: usize = 18;
+
+/// A logical stream that holds its latest packet back until the next one, so the end
+/// of stream flag can go on the page of the last real packet.
+struct HeldStream {
+    stream: OggStream,
+    held: Option<(Vec<u8>, u64, u8)>,
+}
+
+impl HeldStream {
+    fn new(serial: u32) -> HeldStream {
+        HeldStream {
+            stream: OggStream::new(serial),
+            held: None,
+        }
+    }
+
+    fn write_packet<W: Write>(
+        &mut self,
+        writer: &mut W,
+        packet: Vec<u8>,
+        granule_position: u64,
+        header_type: u8,
+    ) -> io::Result<()> {
+        match self.held.replace((packet, granule_position, header_type)) {
+            Some((packet, granule_position, header_type)) => {
+                self.stream
+                    .write_packet(writer, &packet, granule_position, header_type)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn finish<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        match self.held.take() {
+            Some((packet, granule_position, header_type)) => self.stream.write_packet(
+                writer,
+                &packet,
+                granule_position,
+                header_type | HEADER_TYPE_EOS,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes the frames received from one peer to an Ogg file as they arrive.
+pub struct FrameDump {
+    writer: BufWriter<File>,
+    started: Instant,
+    opus: HeldStream,
+    meta: HeldStream,
+    /// Samples decoded from every Opus packet so far.
+    decoded_samples: u64,
+    last_arrival: u64,
+}
+
+impl FrameDump {
+    pub fn create(path: &Path) -> anyhow::Result<FrameDump> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut opus = HeldStream::new(OPUS_SERIAL);
+        let mut meta = HeldStream::new(META_SERIAL);
+
+        let mut opus_head = OPUS_HEAD_MAGIC.to_vec();
+        opus_head.push(1); // Version
+        opus_head.push(OPUS_HEAD_CHANNELS);
+        opus_head.extend_from_slice(&0u16.to_le_bytes()); // Pre-skip
+        opus_head.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+        opus_head.extend_from_slice(&0i16.to_le_bytes()); // Output gain
+        opus_head.push(0); // Channel mapping family
+        opus.write_packet(&mut writer, opus_head, 0, HEADER_TYPE_BOS)?;
+
+        let mut meta_head = META_MAGIC.to_vec();
+        meta_head.push(1); // Version
+        meta.write_packet(&mut writer, meta_head, 0, HEADER_TYPE_BOS)?;
+
+        let mut opus_tags = OPUS_TAGS_MAGIC.to_vec();
+        opus_tags.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+        opus_tags.extend_from_slice(VENDOR.as_bytes());
+        opus_tags.extend_from_slice(&0u32.to_le_bytes()); // No user comments
+        opus.write_packet(&mut writer, opus_tags, 0, 0)?;
+
+        Ok(FrameDump {
+            writer,
+            started: Instant::now(),
+            opus,
+            meta,
+            decoded_samples: 0,
+            last_arrival: 0,
+        })
+    }
+
+    pub fn write(&mut self, frame: &AudioFrame) -> anyhow::Result<()> {
+        // Granule positions must never go backwards within a stream.
+        let arrival = (self.started.elapsed().as_secs_f64() * SAMPLE_RATE as f64) as u64;
+        let arrival = std::cmp::max(arrival, self.last_arrival);
+        self.last_arrival = arrival;
+
+        let samples = opus::packet::get_nb_samples(frame.packet(), SAMPLE_RATE as u32)
+            .unwrap_or_else(|e| {
+                log::debug!("Dumping a packet Opus can't read: {:?}", e);
+                0
+            });
+        self.decoded_samples += samples as u64;
+
+        let mut meta_packet = Vec::with_capacity(META_PACKET_LEN);
+        meta_packet.extend_from_slice(&frame.sequence_number().to_le_bytes());
+        meta_packet.extend_from_slice(&frame.channel_count().to_le_bytes());
+        self.meta
+            .write_packet(&mut self.writer, meta_packet, arrival, 0)?;
+        self.opus.write_packet(
+            &mut self.writer,
+            frame.packet().to_vec(),
+            self.decoded_samples,
+            0,
+        )?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.meta.finish(&mut self.writer)?;
+        self.opus.finish(&mut self.writer)?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for FrameDump {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::debug!("Failed to finish frame dump: {:?}", e);
+        }
+    }
+}
+
+/// A frame read back from a dump, with when it arrived relative to the start of the dump.
+pub struct DumpedFrame {
+    pub arrival: Duration,
+    pub frame: AudioFrame,
+}
+
+/// Reads every frame of a dump, in the order they arrived.
+pub fn read_dump(path: &Path) -> anyhow::Result<Vec<DumpedFrame>> {
+    let mut reader = OggReader::new(BufReader::new(File::open(path)?));
+    let mut frames = Vec::new();
+    let mut opus_headers_seen = 0;
+    let mut meta_header_seen = false;
+    let mut pending_meta: Option<(Duration, u128, u16)> = None;
+
+    while let Some(packet) = reader.next_packet()? {
+        // Empty packets carry nothing to replay.
+        if packet.data.is_empty() {
+            continue;
+        }
+        match packet.serial {
+            META_SERIAL if !meta_header_seen => {
+                if !packet.data.starts_with(META_MAGIC) {
+                    anyhow::bail!("Not an insanity frame dump.");
+                }
+                meta_header_seen = true;
+            }
+            META_SERIAL => {
+                if packet.data.len() != META_PACKET_LEN {
+                    anyhow::bail!("Malformed frame metadata.");
+                }
+                let arrival =
+                    Duration::from_secs_f64(packet.granule_position as f64 / SAMPLE_RATE as f64);
+                let sequence_number = u128::from_le_bytes(packet.data[0..16].try_into()?);
+                let channel_count = u16::from_le_bytes(packet.data[16..18].try_into()?);
+                pending_meta = Some((arrival, sequence_number, channel_count));
+            }
+            // OpusHead and OpusTags.
+            OPUS_SERIAL if opus_headers_seen < 2 => {
+                opus_headers_seen += 1;
+            }
+            OPUS_SERIAL => {
+                let Some((arrival, sequence_number, channel_count)) = pending_meta.take() else {
+                    anyhow::bail!("Opus packet without frame metadata.");
+                };
+                frames.push(DumpedFrame {
+                    arrival,
+                    frame: AudioFrame::new(sequence_number, channel_count, packet.data),
+                });
+            }
+            serial => {
+                log::debug!("Skipping packet from unknown stream {serial}.");
+            }
+        }
+    }
+
+    if !meta_header_seen {
+        anyhow::bail!("Not an insanity frame dump.");
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clerver::FrameEncoder, processor::AUDIO_CHUNK_SIZE};
+
+    #[test]
+    fn frames_read_back_as_dumped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.opus");
+        let frames = [
+            AudioFrame::new(0, 2, vec![1, 2, 3]),
+            AudioFrame::new(2, 2, vec![0; 600]),
+            AudioFrame::new(1, 1, vec![9]),
+            AudioFrame::new(u128::MAX, 2, vec![255; 255]),
+        ];
+        {
+            let mut dump = FrameDump::create(&path).unwrap();
+            for frame in &frames {
+                dump.write(frame).unwrap();
+            }
+        }
+
+        let dumped = read_dump(&path).unwrap();
+        assert_eq!(dumped.len(), frames.len());
+        for (dumped, frame) in dumped.iter().zip(&frames) {
+            assert_eq!(dumped.frame.sequence_number(), frame.sequence_number());
+            assert_eq!(dumped.frame.channel_count(), frame.channel_count());
+            assert_eq!(dumped.frame.packet(), frame.packet());
+        }
+        assert!(dumped
+            .windows(2)
+            .all(|pair| pair[0].arrival <= pair[1].arrival));
+    }
+
+    #[test]
+    fn opus_stream_is_ogg_opus() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.opus");
+        let mut mono = FrameEncoder::new(1).unwrap();
+        let mut stereo = FrameEncoder::new(2).unwrap();
+        {
+            let mut dump = FrameDump::create(&path).unwrap();
+            for i in 0..10 {
+                let frame = if i % 2 == 0 {
+                    mono.encode(vec![0.0; AUDIO_CHUNK_SIZE]).unwrap()
+                } else {
+                    stereo.encode(vec![0.0; AUDIO_CHUNK_SIZE * 2]).unwrap()
+                };
+                dump.write(&frame).unwrap();
+            }
+        }
+
+        let mut reader = OggReader::new(BufReader::new(File::open(&path).unwrap()));
+        let mut opus_packets = Vec::new();
+        let mut meta_packets = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            assert!(!packet.data.is_empty());
+            match packet.serial {
+                OPUS_SERIAL => opus_packets.push(packet),
+                META_SERIAL => meta_packets.push(packet),
+                serial => panic!("Unexpected stream {serial}."),
+            }
+        }
+
+        let head = &opus_packets[0];
+        assert!(head.data.starts_with(OPUS_HEAD_MAGIC));
+        assert_eq!(head.data[9], 2);
+        assert_eq!(head.header_type, HEADER_TYPE_BOS);
+        assert!(opus_packets[1].data.starts_with(OPUS_TAGS_MAGIC));
+        // Granule positions count the samples decoded so far.
+        let audio = &opus_packets[2..];
+        assert_eq!(audio.len(), 10);
+        for (i, packet) in audio.iter().enumerate() {
+            assert_eq!(packet.granule_position, ((i + 1) * AUDIO_CHUNK_SIZE) as u64);
+        }
+        // Only the last real packet of each stream ends it.
+        for packets in [&opus_packets, &meta_packets] {
+            let (last, rest) = packets.split_last().unwrap();
+            assert_ne!(last.header_type & HEADER_TYPE_EOS, 0);
+            assert!(rest
+                .iter()
+                .all(|packet| packet.header_type & HEADER_TYPE_EOS == 0));
+        }
+    }
+
+    #[test]
+    fn empty_dumps_still_end_their_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.opus");
+        drop(FrameDump::create(&path).unwrap());
+
+        assert!(read_dump(&path).unwrap().is_empty());
+        let mut reader = OggReader::new(BufReader::new(File::open(&path).unwrap()));
+        let mut ended = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            if packet.header_type & HEADER_TYPE_EOS != 0 {
+                ended.push(packet.serial);
+            }
+        }
+        ended.sort();
+        assert_eq!(ended, [OPUS_SERIAL, META_SERIAL]);
+    }
+
+    #[test]
+    fn other_ogg_files_are_not_dumps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("other.ogg");
+        let mut file = Vec::new();
+        OggStream::new(OPUS_SERIAL)
+            .write_packet(&mut file, OPUS_HEAD_MAGIC, 0, HEADER_TYPE_BOS)
+            .unwrap();
+        std::fs::write(&path, file).unwrap();
+
+        assert!(read_dump(&path).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+use insanity_core::equalizer::EqualizerPreset;
+
+/// Normalized coefficients for one second-order section, from the RBJ audio EQ cookbook.
+#[derive(Debug, Clone, Copy)]
+struct BiquadCoefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl BiquadCoefficients {
+    fn normalized(b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) -> Self {
+        BiquadCoefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    fn high_pass(sample_rate: u32, frequency: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::intermediates(sample_rate, frequency, q);
+        Self::normalized(
+            (1.0 + cos) / 2.0,
+            -(1.0 + cos),
+            (1.0 + cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        )
+    }
+
+    fn low_pass(sample_rate: u32, frequency: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::intermediates(sample_rate, frequency, q);
+        Self::normalized(
+            (1.0 - cos) / 2.0,
+            1.0 - cos,
+            (1.0 - cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        )
+    }
+
+    fn peaking(sample_rate: u32, frequency: f32, q: f32, gain_db: f32) -> Self {
+        let (cos, alpha) = Self::intermediates(sample_rate, frequency, q);
+        let a = 10f32.powf(gain_db / 40.0);
+        Self::normalized(
+            1.0 + alpha * a,
+            -2.0 * cos,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos,
+            1.0 - alpha / a,
+        )
+    }
+
+    fn intermediates(sample_rate: u32, frequency: f32, q: f32) -> (f32, f32) {
+        let w0 = 2.0 * PI * frequency / sample_rate as f32;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+}
+
+/// A single biquad section in direct form I, holding the history for one channel.
+#[derive(Debug, Clone)]
+struct Biquad {
+    coefficients: BiquadCoefficients,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    fn new(coefficients: BiquadCoefficients) -> Self {
+        Biquad {
+            coefficients,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let c = &self.coefficients;
+        let y = c.b0 * x + c.b1 * self.x1 + c.b2 * self.x2 - c.a1 * self.y1 - c.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+fn preset_sections(preset: EqualizerPreset, sample_rate: u32) -> Vec<BiquadCoefficients> {
+    match preset {
+        EqualizerPreset::Off => vec![],
+        EqualizerPreset::RumbleCut => {
+            vec![BiquadCoefficients::high_pass(
+                sample_rate,
+                100.0,
+                FRAC_1_SQRT_2,
+            )]
+        }
+        EqualizerPreset::Voice => vec![
+            BiquadCoefficients::high_pass(sample_rate, 100.0, FRAC_1_SQRT_2),
+            BiquadCoefficients::peaking(sample_rate, 3000.0, 1.0, 4.0),
+            BiquadCoefficients::low_pass(sample_rate, 12000.0, FRAC_1_SQRT_2),
+        ],
+        EqualizerPreset::Laptop => vec![
+            BiquadCoefficients::high_pass(sample_rate, 200.0, FRAC_1_SQRT_2),
+            BiquadCoefficients::peaking(sample_rate, 3500.0, 1.0, 6.0),
+            BiquadCoefficients::low_pass(sample_rate, 10000.0, FRAC_1_SQRT_2),
+        ],
+    }
+}
+
+/// Cascade of biquad sections for a preset, run independently on each interleaved channel.
+pub struct FilterChain {
+    preset: EqualizerPreset,
+    sample_rate: u32,
+    channel_count: u16,
+    channels: Vec<Vec<Biquad>>,
+}
+
+impl FilterChain {
+    pub fn new(preset: EqualizerPreset, sample_rate: u32, channel_count: u16) -> FilterChain {
+        let sections = preset_sections(preset, sample_rate);
+        let channels = (0..channel_count)
+            .map(|_| sections.iter().copied().map(Biquad::new).collect())
+            .collect();
+        FilterChain {
+            preset,
+            sample_rate,
+            channel_count,
+            channels,
+        }
+    }
+
+    /// Whether this chain can keep filtering a stream with these settings without losing state.
+    pub fn matches(&self, preset: EqualizerPreset, sample_rate: u32, channel_count: u16) -> bool {
+        self.preset == preset
+            && self.sample_rate == sample_rate
+            && self.channel_count == channel_count
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(self.channel_count as usize) {
+            for (sample, sections) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                for section in sections.iter_mut() {
+                    *sample = section.process(*sample);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How much `preset` scales a sine at `frequency`, once the filters have settled.
+    fn gain(preset: EqualizerPreset, sample_rate: u32, frequency: f32) -> f32 {
+        let mut chain = FilterChain::new(preset, sample_rate, 1);
+        let mut samples: Vec<f32> = (0..sample_rate)
+            .map(|i| (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect();
+        chain.process(&mut samples);
+        let settled = &samples[samples.len() / 2..];
+        let rms = (settled.iter().map(|sample| sample * sample).sum::<f32>()
+            / settled.len() as f32)
+            .sqrt();
+        rms * std::f32::consts::SQRT_2
+    }
+
+    #[test]
+    fn off_passes_everything() {
+        assert!((gain(EqualizerPreset::Off, 48000, 30.0) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn voice_cuts_rumble_and_passes_speech() {
+        for sample_rate in [44100, 48000] {
+            let rumble = gain(EqualizerPreset::Voice, sample_rate, 30.0);
+            let speech = gain(EqualizerPreset::Voice, sample_rate, 1000.0);
+            assert!(rumble < 0.15, "30 Hz at {sample_rate} Hz kept {rumble}");
+            assert!(
+                (0.9..1.3).contains(&speech),
+                "1 kHz at {sample_rate} Hz became {speech}"
+            );
+        }
+    }
+
+    #[test]
+    fn chains_are_rebuilt_for_other_streams() {
+        let chain = FilterChain::new(EqualizerPreset::Voice, 48000, 2);
+        assert!(chain.matches(EqualizerPreset::Voice, 48000, 2));
+        assert!(!chain.matches(EqualizerPreset::Laptop, 48000, 2));
+        assert!(!chain.matches(EqualizerPreset::Voice, 44100, 2));
+        assert!(!chain.matches(EqualizerPreset::Voice, 48000, 1));
+    }
+}
@@ -0,0 +1,102 @@
+//! Invite links, carrying everything needed to join a room:
+//! `insanity://join?bridge=<url>&room=<name>&secret=<secret>`.
+
+use std::{fmt, str::FromStr};
+
+use crate::room_secrets::RoomCredentials;
+
+const SCHEME: &str = "insanity";
+const HOST: &str = "join";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invite {
+    /// Bridge server the room is found on.
+    pub bridge: String,
+    pub room: RoomCredentials,
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut link = url::Url::parse(&format!("{SCHEME}://{HOST}")).map_err(|_| fmt::Error)?;
+        link.query_pairs_mut()
+            .append_pair("bridge", &self.bridge)
+            .append_pair("room", &self.room.name)
+            .append_pair("secret", &self.room.secret);
+        write!(f, "{link}")
+    }
+}
+
+impl FromStr for Invite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let link = url::Url::parse(s.trim())?;
+        if link.scheme() != SCHEME || link.host_str() != Some(HOST) {
+            anyhow::bail!("Not an insanity invite link: {s}");
+        }
+        let (mut bridge, mut name, mut secret) = (None, None, None);
+        for (key, value) in link.query_pairs() {
+            match key.as_ref() {
+                "bridge" => bridge = Some(value.into_owned()),
+                "room" => name = Some(value.into_owned()),
+                "secret" => secret = Some(value.into_owned()),
+                _ => log::debug!("Ignoring unknown invite field {key}."),
+            }
+        }
+        let (Some(bridge), Some(name), Some(secret)) = (bridge, name, secret) else {
+            anyhow::bail!("Invite link needs a bridge, room and secret: {s}");
+        };
+        url::Url::parse(&bridge)?;
+        Ok(Invite {
+            bridge,
+            room: RoomCredentials { name, secret },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_secrets::generate_secret;
+
+    fn invite(name: &str) -> Invite {
+        Invite {
+            bridge: "https://bridge.example.com:8080/path".to_string(),
+            room: RoomCredentials {
+                name: name.to_string(),
+                secret: generate_secret(),
+            },
+        }
+    }
+
+    #[test]
+    fn links_round_trip() {
+        for name in ["lobby", "two words", "a&b=c?d#e", "ünïcødé 🎧", "100%"] {
+            let invite = invite(name);
+            let link = invite.to_string();
+            assert!(link.starts_with("insanity://join?"), "{link}");
+            assert_eq!(link.parse::<Invite>().unwrap(), invite);
+        }
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        let invite = invite("lobby");
+        assert_eq!(format!("  {invite}\n").parse::<Invite>().unwrap(), invite);
+    }
+
+    #[test]
+    fn incomplete_or_foreign_links_are_rejected() {
+        let secret = generate_secret();
+        for link in [
+            format!("https://join?bridge=https://b&room=r&secret={secret}"),
+            format!("insanity://leave?bridge=https://b&room=r&secret={secret}"),
+            format!("insanity://join?room=r&secret={secret}"),
+            "insanity://join?bridge=https://b&room=r".to_string(),
+            format!("insanity://join?bridge=not%20a%20url&room=r&secret={secret}"),
+            "not a link".to_string(),
+        ] {
+            assert!(link.parse::<Invite>().is_err(), "{link}");
+        }
+    }
+}
@@ -1,10 +1,28 @@
+pub mod audio_device;
+pub mod bot;
+pub mod bridges;
 pub mod clerver;
 pub mod client;
 pub mod connection_manager;
+pub mod drift;
+pub mod dump;
+pub mod filter;
+pub mod invite;
 pub mod managed_peer;
+pub mod mic_test;
+pub mod ogg;
+pub mod peer_settings;
 pub mod processor;
 pub mod protocol;
 pub mod realtime_buffer;
+pub mod recorder;
+pub mod replay;
+pub mod room_access;
 pub mod room_handler;
+pub mod room_secrets;
+pub mod rooms;
+pub mod safety_number;
+pub mod sample_ring;
 pub mod server;
+pub mod transport;
 pub mod update;
@@ -3,9 +3,17 @@ use std::{path::PathBuf, str::FromStr, time::Duration};
 use clap::{ArgMatches, CommandFactory, Parser, Subcommand, parser::ValueSource};
 use insanity_core::built_info;
 use insanity_native_tui_app::{
-    connection_manager::ConnectionManager, connection_manager::IpVersion, update,
+    bot::{self, BotMode, BotOptions},
+    connection_manager::{ConnectionManager, IpVersion},
+    invite::Invite,
+    mic_test::{self, MicTestOptions},
+    replay,
+    room_access::RoomAccessStore,
+    room_secrets::RoomSecretStore,
+    update,
 };
 use insanity_tui_adapter::AppEvent;
+use rubato_audio_source::ResamplerQuality;
 use serde::Deserialize;
 use tokio_util::sync::CancellationToken;
 
@@ -42,13 +50,29 @@ struct Cli {
     #[clap(long)]
     bridge: Vec<String>,
 
-    /// Room name to join.
+    /// Room name to join. Repeat to join several; the first is where your microphone goes.
     #[clap(long)]
-    room: Option<String>,
+    room: Vec<String>,
+
+    /// Invite link to join, from `insanity invite`. Repeat to join several.
+    #[clap(long)]
+    join: Vec<Invite>,
 
     /// ipv4, ipv6, or dualstack
     #[clap(long, value_enum, default_value_t = IpVersion::Dualstack)]
     ip_version: IpVersion,
+
+    /// Record the call to the recordings folder in the data directory.
+    #[clap(long)]
+    record: bool,
+
+    /// Dump the audio received from each peer to the dumps folder in the data directory.
+    #[clap(long)]
+    dump_received: bool,
+
+    /// Resampling between device and call sample rates: fast, balanced or high.
+    #[clap(long, default_value_t = ResamplerQuality::default())]
+    resampler_quality: ResamplerQuality,
 }
 
 #[derive(Subcommand, Debug)]
@@ -65,6 +89,71 @@ enum Commands {
     },
     PrintConfig,
     PrintConfigPath,
+    /// Manage who may connect through a room.
+    Room {
+        #[command(subcommand)]
+        command: RoomCommand,
+    },
+    /// Print an invite link to a room.
+    Invite {
+        /// Room to invite to. Defaults to the first room given with --room or in the config.
+        room: Option<String>,
+
+        /// Start the room by creating its secret. Anyone meeting there by name alone
+        /// won't find you until they join with the invite.
+        #[clap(long, default_value_t = false)]
+        new: bool,
+    },
+    /// Play back a dump of received audio with its original timing.
+    Replay {
+        file: String,
+
+        #[clap(long, default_value_t = false)]
+        no_denoise: bool,
+    },
+    /// Hear your microphone as peers would, without joining a room.
+    MicTest {
+        /// Seconds before you hear yourself.
+        #[clap(long, default_value_t = 2.0)]
+        delay: f64,
+
+        #[clap(long, default_value_t = false)]
+        no_denoise: bool,
+    },
+    /// Join the room as a bot, without a terminal or audio devices.
+    Bot {
+        /// WAV file to play on a loop instead of a tone.
+        #[clap(long)]
+        wav: Option<String>,
+
+        /// Frequency of the tone to play, in Hz.
+        #[clap(long, default_value_t = 440.0)]
+        tone: f32,
+
+        /// Play back what the bot hears instead.
+        #[clap(long, default_value_t = false)]
+        echo: bool,
+
+        /// Seconds before what the bot hears is played back.
+        #[clap(long, default_value_t = 3.0)]
+        echo_delay: f64,
+
+        /// Name shown to other peers.
+        #[clap(long, default_value = "insanity bot")]
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RoomCommand {
+    /// Add a peer key to the room's allowlist. Once it has one, only listed peers get in.
+    Allow { room: String, key: String },
+    /// Add a peer key to the room's blocklist.
+    Deny { room: String, key: String },
+    /// Take a peer key off both of the room's lists.
+    Forget { room: String, key: String },
+    /// Print the room's lists.
+    Show { room: String },
 }
 
 #[derive(Debug)]
@@ -72,8 +161,12 @@ struct RunOptions {
     port: u16,
     no_tui: bool,
     bridge: Vec<String>,
-    room: Option<String>,
+    room: Vec<String>,
+    join: Vec<Invite>,
     ip_version: IpVersion,
+    record: bool,
+    dump_received: bool,
+    resampler_quality: ResamplerQuality,
 }
 
 // RunOptions that can be specified via config file
@@ -83,8 +176,28 @@ struct OptionalRunOptions {
     port: Option<u16>,
     no_tui: Option<bool>,
     bridge: Option<Vec<String>>,
-    room: Option<Option<String>>,
+    room: Option<Rooms>,
     ip_version: Option<IpVersion>,
+    record: Option<bool>,
+    dump_received: Option<bool>,
+    resampler_quality: Option<ResamplerQuality>,
+}
+
+/// The config file takes a single room or a list of them.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Rooms {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Rooms> for Vec<String> {
+    fn from(rooms: Rooms) -> Vec<String> {
+        match rooms {
+            Rooms::One(room) => vec![room],
+            Rooms::Many(rooms) => rooms,
+        }
+    }
 }
 
 /// Merges two configuration options, with priority as follows:
@@ -111,26 +224,80 @@ fn merge_configs(primary: Cli, secondary: OptionalRunOptions, matches: &ArgMatch
             secondary.bridge,
             matches.value_source("bridge"),
         ),
-        room: merge_values(primary.room, secondary.room, matches.value_source("room")),
+        room: merge_values(
+            primary.room,
+            secondary.room.map(Vec::from),
+            matches.value_source("room"),
+        ),
+        join: primary.join,
         ip_version: merge_values(
             primary.ip_version,
             secondary.ip_version,
             matches.value_source("ip_version"),
         ),
+        record: merge_values(
+            primary.record,
+            secondary.record,
+            matches.value_source("record"),
+        ),
+        dump_received: merge_values(
+            primary.dump_received,
+            secondary.dump_received,
+            matches.value_source("dump_received"),
+        ),
+        resampler_quality: merge_values(
+            primary.resampler_quality,
+            secondary.resampler_quality,
+            matches.value_source("resampler_quality"),
+        ),
     }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cli_opts: Cli = Cli::parse();
+    let mut cli_opts: Cli = Cli::parse();
 
-    match cli_opts.command {
+    match cli_opts.command.take() {
         None | Some(Commands::Run) => run(cli_opts).await,
         Some(Commands::Update { dry_run, force }) => update::update(dry_run, force).await,
+        Some(Commands::Room { command }) => manage_room(cli_opts, command),
+        Some(Commands::Invite { room, new }) => print_invite(cli_opts, room, new),
         Some(Commands::PrintConfig) => {
             print_config_file(cli_opts.config_file);
             Ok(())
         }
+        Some(Commands::Replay { file, no_denoise }) => {
+            let (_, opts) = prepare(cli_opts)?;
+            replay::replay(&PathBuf::from(file), !no_denoise, opts.resampler_quality).await
+        }
+        Some(Commands::MicTest { delay, no_denoise }) => {
+            let (_, opts) = prepare(cli_opts)?;
+            let options = MicTestOptions {
+                delay: Duration::from_secs_f64(delay),
+                denoise: !no_denoise,
+                resampler_quality: opts.resampler_quality,
+            };
+            mic_test::mic_test(options).await
+        }
+        Some(Commands::Bot {
+            wav,
+            tone,
+            echo,
+            echo_delay,
+            name,
+        }) => {
+            let mode = match (echo, &wav) {
+                (true, _) => BotMode::Echo,
+                (false, Some(_)) => BotMode::Play,
+                (false, None) => BotMode::Tone(tone),
+            };
+            let options = BotOptions {
+                mode,
+                wav: wav.map(PathBuf::from),
+                echo_delay: Duration::from_secs_f64(echo_delay),
+            };
+            run_bot(cli_opts, options, name).await
+        }
         Some(Commands::PrintConfigPath) => {
             let config_file_path = get_config_file_path(cli_opts.config_file.as_ref());
             if let Some(path) = config_file_path.to_str() {
@@ -164,9 +331,8 @@ fn print_config_file(config_file_path: Option<String>) {
     };
 }
 
-async fn run(unprocessed_opts: Cli) -> anyhow::Result<()> {
-    let main_cancellation_token = CancellationToken::new();
-
+/// Sets up the data directory and logging, and merges the config file into the options.
+fn prepare(unprocessed_opts: Cli) -> anyhow::Result<(PathBuf, RunOptions)> {
     // Configure insanity data directory
     let insanity_dir = match unprocessed_opts.dir {
         Some(ref dir) => PathBuf::from_str(dir).unwrap(),
@@ -209,6 +375,84 @@ async fn run(unprocessed_opts: Cli) -> anyhow::Result<()> {
 
     // Merge configs
     let opts = merge_configs(unprocessed_opts, config_file, &Cli::command().get_matches());
+    Ok((insanity_dir, opts))
+}
+
+fn manage_room(unprocessed_opts: Cli, command: RoomCommand) -> anyhow::Result<()> {
+    let (insanity_dir, _) = prepare(unprocessed_opts)?;
+    let access_store = RoomAccessStore::open(&insanity_dir)?;
+    let (room, access) = match command {
+        RoomCommand::Allow { room, key } => {
+            let access = access_store.update(&room, |access| access.allow(key))?;
+            (room, access)
+        }
+        RoomCommand::Deny { room, key } => {
+            let access = access_store.update(&room, |access| access.deny(key))?;
+            (room, access)
+        }
+        RoomCommand::Forget { room, key } => {
+            let access = access_store.update(&room, |access| access.forget(&key))?;
+            (room, access)
+        }
+        RoomCommand::Show { room } => {
+            let access = access_store.load(&room);
+            (room, access)
+        }
+    };
+    if access.allowed.is_empty() {
+        println!("Room {room} is open to everyone not blocked.");
+    } else {
+        println!("Room {room} is open only to:");
+        for key in access.allowed.iter() {
+            println!("  {key}");
+        }
+    }
+    if !access.denied.is_empty() {
+        println!("Blocked:");
+        for key in access.denied.iter() {
+            println!("  {key}");
+        }
+    }
+    Ok(())
+}
+
+fn print_invite(unprocessed_opts: Cli, room: Option<String>, new: bool) -> anyhow::Result<()> {
+    let (insanity_dir, opts) = prepare(unprocessed_opts)?;
+    let Some(room) = room.or(opts.room.into_iter().next()) else {
+        anyhow::bail!("No room to invite to. Name one, or set one with --room.");
+    };
+    let Some(bridge) = opts.bridge.into_iter().next() else {
+        anyhow::bail!("Invites need a bridge server. Set one with --bridge.");
+    };
+    let secrets = RoomSecretStore::open(&insanity_dir);
+    let credentials = match (secrets.credentials(&room)?, new) {
+        (Some(credentials), false) => credentials,
+        (Some(_), true) => {
+            anyhow::bail!(
+                "Room {room} already has a secret. Leave out --new to invite to it as it is."
+            );
+        }
+        (None, true) => secrets.create(&room)?,
+        (None, false) => {
+            anyhow::bail!(
+                "Room {room} has no secret yet. Pass --new to create one; anyone meeting \
+                 there by name alone won't find you until they join with the invite."
+            );
+        }
+    };
+    println!(
+        "{}",
+        Invite {
+            bridge,
+            room: credentials,
+        }
+    );
+    Ok(())
+}
+
+async fn run(unprocessed_opts: Cli) -> anyhow::Result<()> {
+    let main_cancellation_token = CancellationToken::new();
+    let (insanity_dir, opts) = prepare(unprocessed_opts)?;
 
     let display_name = format!(
         "{} [{}]",
@@ -218,10 +462,13 @@ async fn run(unprocessed_opts: Cli) -> anyhow::Result<()> {
 
     let (app_event_sender, user_action_receiver, handle) = if !opts.no_tui {
         let (x, y, z) = insanity_tui_adapter::start_tui().await.unwrap();
-        x.send(AppEvent::SetServer(opts.bridge.clone()))?;
-        if let Some(room) = opts.room.clone() {
-            x.send(AppEvent::SetRoom(room))?;
+        let mut bridge = opts.bridge.clone();
+        for invite in opts.join.iter() {
+            if !bridge.contains(&invite.bridge) {
+                bridge.push(invite.bridge.clone());
+            }
         }
+        x.send(AppEvent::SetServer(bridge))?;
         x.send(AppEvent::SetOwnDisplayName(display_name.clone()))?;
         (Some(x), Some(y), Some(z))
     } else {
@@ -232,10 +479,16 @@ async fn run(unprocessed_opts: Cli) -> anyhow::Result<()> {
     let mut conn_manager_builder =
         ConnectionManager::builder(insanity_dir, opts.port, opts.bridge, opts.ip_version)
             .display_name(display_name)
+            .record(opts.record)
+            .dump_received(opts.dump_received)
+            .resampler_quality(opts.resampler_quality)
             .cancellation_token(main_cancellation_token.clone());
-    if let Some(room) = opts.room {
+    for room in opts.room {
         conn_manager_builder = conn_manager_builder.room(room);
     }
+    for invite in opts.join {
+        conn_manager_builder = conn_manager_builder.join(invite);
+    }
     if let Some(app_event_sender) = app_event_sender {
         conn_manager_builder = conn_manager_builder.app_event_sender(app_event_sender)
     }
@@ -268,6 +521,22 @@ async fn run(unprocessed_opts: Cli) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn run_bot(unprocessed_opts: Cli, options: BotOptions, name: String) -> anyhow::Result<()> {
+    let (insanity_dir, opts) = prepare(unprocessed_opts)?;
+    let mut builder =
+        ConnectionManager::builder(insanity_dir, opts.port, opts.bridge, opts.ip_version)
+            .display_name(name)
+            .dump_received(opts.dump_received)
+            .resampler_quality(opts.resampler_quality);
+    for room in opts.room {
+        builder = builder.room(room);
+    }
+    for invite in opts.join {
+        builder = builder.join(invite);
+    }
+    bot::run_bot(builder, options).await
+}
+
 fn renew_dir(dir: &PathBuf) -> anyhow::Result<()> {
     let version_file = dir.join("version");
     let version = match std::fs::read_to_string(&version_file) {
@@ -1,14 +1,33 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use bon::bon;
-use insanity_tui_adapter::{AppEvent, Peer, PeerState};
+use ed25519_dalek::VerifyingKey;
+use insanity_core::{equalizer::EqualizerPreset, pan::Pan};
+use insanity_tui_adapter::{AppEvent, Peer, PeerState, PeerTrust};
+use rubato_audio_source::ResamplerQuality;
 use tokio::sync::{broadcast, mpsc};
 use veq::veq::VeqSocket;
 
-use crate::{clerver::run_clerver, connection_manager::AugmentedInfo, protocol::ProtocolMessage};
+use crate::{
+    audio_device::AudioBackend,
+    clerver::{run_clerver, CallAudio},
+    connection_manager::AugmentedInfo,
+    peer_settings::PeerSettings,
+    processor::PlaybackControls,
+    protocol::ProtocolMessage,
+    recorder::Recorder,
+    room_access::RoomAccessStore,
+    room_handler::ConfirmedMemberKey,
+    safety_number::safety_number,
+    transport::{Transport, TransportInfo, TransportSession},
+};
 
 #[derive(Clone, Debug)]
 pub enum ConnectionStatus {
@@ -18,41 +37,82 @@ pub enum ConnectionStatus {
 }
 
 #[derive(Clone)]
-pub struct ManagedPeer {
+pub struct ManagedPeer<T: Transport = VeqSocket> {
     id: uuid::Uuid,
-    connection_info: veq::veq::ConnectionInfo,
-    socket: VeqSocket,
+    connection_info: T::Info,
+    socket: T,
     shutdown_tx: broadcast::Sender<()>,
     peer_message_tx: broadcast::Sender<ProtocolMessage>,
     app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
     connection_status: Arc<Mutex<ConnectionStatus>>,
     display_name: String,
+    /// Key the peer's room entry was signed with, and the one the peer sent us itself.
+    member_key: Option<VerifyingKey>,
+    confirmed_member_key: ConfirmedMemberKey,
+    /// Ours, sent to the peer.
+    own_member_key: VerifyingKey,
     sender_is_muted: Arc<AtomicBool>,
-    denoise: Arc<AtomicBool>,
-    volume: Arc<Mutex<usize>>,
+    /// Rooms this peer was found in, and whether that leaves it out of the one we talk to.
+    rooms: Arc<Mutex<BTreeSet<String>>>,
+    room_access: RoomAccessStore,
+    listen_only: Arc<AtomicBool>,
+    trust: Arc<Mutex<PeerTrust>>,
+    playback: PlaybackControls,
+    audio_backend: Arc<dyn AudioBackend>,
+    resampler_quality: ResamplerQuality,
+    dump_dir: Option<PathBuf>,
 }
 
 #[bon]
-impl ManagedPeer {
+impl<T: Transport> ManagedPeer<T> {
     #[builder]
     pub fn new(
         id: uuid::Uuid,
-        connection_info: veq::veq::ConnectionInfo,
-        socket: VeqSocket,
+        connection_info: T::Info,
+        socket: T,
+        room_access: RoomAccessStore,
         app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
         display_name: String,
+        member_key: Option<VerifyingKey>,
+        own_member_key: VerifyingKey,
         denoise: bool,
         volume: usize,
+        #[builder(default)] pan: Pan,
+        #[builder(default)] equalizer: EqualizerPreset,
+        #[builder(default)] muted: bool,
+        #[builder(default)] trust: PeerTrust,
         sender_is_muted: Arc<AtomicBool>,
-    ) -> ManagedPeer {
+        deafened: Arc<AtomicBool>,
+        recorder: Recorder,
+        audio_backend: Arc<dyn AudioBackend>,
+        #[builder(default)] resampler_quality: ResamplerQuality,
+        dump_dir: Option<PathBuf>,
+    ) -> ManagedPeer<T> {
         let (shutdown_tx, _shutdown_rx) = broadcast::channel(10);
         let (peer_message_tx, _) = broadcast::channel(10);
         ManagedPeer {
-            denoise: Arc::new(AtomicBool::new(denoise)),
-            volume: Arc::new(Mutex::new(volume)),
+            playback: PlaybackControls {
+                denoise: Arc::new(AtomicBool::new(denoise)),
+                volume: Arc::new(Mutex::new(volume)),
+                pan: Arc::new(Mutex::new(pan)),
+                equalizer: Arc::new(Mutex::new(equalizer)),
+                muted: Arc::new(AtomicBool::new(muted)),
+                deafened,
+                recorder,
+            },
             sender_is_muted,
+            rooms: Arc::new(Mutex::new(BTreeSet::new())),
+            room_access,
+            listen_only: Arc::new(AtomicBool::new(false)),
+            trust: Arc::new(Mutex::new(trust)),
+            audio_backend,
+            resampler_quality,
+            dump_dir,
             connection_info,
             display_name,
+            member_key,
+            confirmed_member_key: ConfirmedMemberKey::default(),
+            own_member_key,
             shutdown_tx,
             peer_message_tx,
             socket,
@@ -62,25 +122,33 @@ impl ManagedPeer {
         }
     }
 
-    pub fn set_info(&mut self, info: AugmentedInfo) {
+    pub fn set_info(&mut self, info: AugmentedInfo<T::Info>) {
         self.connection_info = info.connection_info;
         self.display_name = info.display_name;
+        self.member_key = info.member_key;
     }
 
-    pub fn info(&self) -> AugmentedInfo {
+    pub fn info(&self) -> AugmentedInfo<T::Info> {
         AugmentedInfo {
             connection_info: self.connection_info.clone(),
             display_name: self.display_name.clone(),
+            member_key: self.member_key,
         }
     }
 
+    /// Whether `info` can be from the peer itself, rather than someone else publishing
+    /// its identity under their own member key.
+    pub fn admits_info(&self, info: &AugmentedInfo<T::Info>) -> bool {
+        self.confirmed_member_key.admits(info.member_key.as_ref())
+    }
+
     pub fn connection_status(&self) -> ConnectionStatus {
         let connection_status = self.connection_status.lock().unwrap();
         connection_status.clone()
     }
 
     pub fn set_denoise(&self, denoise: bool) -> anyhow::Result<()> {
-        self.denoise.store(denoise, Ordering::Relaxed);
+        self.playback.denoise.store(denoise, Ordering::Relaxed);
         if let Some(app_event_tx) = &self.app_event_tx {
             app_event_tx.send(AppEvent::SetPeerDenoise(self.id.to_string(), denoise))?;
         }
@@ -88,7 +156,7 @@ impl ManagedPeer {
     }
 
     pub fn set_volume(&self, volume: usize) -> anyhow::Result<()> {
-        let mut volume_guard = self.volume.lock().unwrap();
+        let mut volume_guard = self.playback.volume.lock().unwrap();
         *volume_guard = volume;
         if let Some(app_event_tx) = &self.app_event_tx {
             app_event_tx.send(AppEvent::SetPeerVolume(self.id.to_string(), volume))?;
@@ -96,6 +164,156 @@ impl ManagedPeer {
         Ok(())
     }
 
+    /// Places the peer manually, or hands it back to automatic placement with `None`.
+    /// Automatic placement keeps the current position until the peers are spread again.
+    pub fn set_pan(&self, pan: Option<isize>) -> anyhow::Result<()> {
+        let mut pan_guard = self.playback.pan.lock().unwrap();
+        let new_pan = match pan {
+            Some(position) => Pan::Manual(position),
+            None => Pan::Auto(pan_guard.position()),
+        };
+        *pan_guard = new_pan;
+        if let Some(app_event_tx) = &self.app_event_tx {
+            app_event_tx.send(AppEvent::SetPeerPan(self.id.to_string(), *pan_guard))?;
+        }
+        Ok(())
+    }
+
+    /// Moves the peer to its automatic position, unless it was placed manually.
+    pub fn set_auto_pan(&self, position: isize) -> anyhow::Result<()> {
+        let mut pan_guard = self.playback.pan.lock().unwrap();
+        if !pan_guard.is_auto() || pan_guard.position() == position {
+            return Ok(());
+        }
+        *pan_guard = Pan::Auto(position);
+        if let Some(app_event_tx) = &self.app_event_tx {
+            app_event_tx.send(AppEvent::SetPeerPan(self.id.to_string(), *pan_guard))?;
+        }
+        Ok(())
+    }
+
+    pub fn set_equalizer(&self, equalizer: EqualizerPreset) -> anyhow::Result<()> {
+        let mut equalizer_guard = self.playback.equalizer.lock().unwrap();
+        *equalizer_guard = equalizer;
+        if let Some(app_event_tx) = &self.app_event_tx {
+            app_event_tx.send(AppEvent::SetPeerEqualizer(self.id.to_string(), equalizer))?;
+        }
+        Ok(())
+    }
+
+    pub fn set_muted(&self, muted: bool) -> anyhow::Result<()> {
+        self.playback.muted.store(muted, Ordering::Relaxed);
+        if let Some(app_event_tx) = &self.app_event_tx {
+            app_event_tx.send(AppEvent::SetPeerMuted(self.id.to_string(), muted))?;
+        }
+        Ok(())
+    }
+
+    pub fn trust(&self) -> PeerTrust {
+        *self.trust.lock().unwrap()
+    }
+
+    pub fn set_trust(&self, trust: PeerTrust) -> anyhow::Result<()> {
+        *self.trust.lock().unwrap() = trust;
+        if let Some(app_event_tx) = &self.app_event_tx {
+            app_event_tx.send(AppEvent::SetPeerTrust(self.id.to_string(), trust))?;
+        }
+        Ok(())
+    }
+
+    /// Rooms we share with the peer.
+    pub fn rooms(&self) -> Vec<String> {
+        self.rooms.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Notes that the peer is in `room` too. The connection is shared between rooms.
+    pub fn add_room(&self, room: String, talk_room: Option<&str>) {
+        let rooms = {
+            let mut rooms_guard = self.rooms.lock().unwrap();
+            if !rooms_guard.insert(room) {
+                return;
+            }
+            rooms_guard.iter().cloned().collect()
+        };
+        self.set_talk_room(talk_room);
+        if let Some(app_event_tx) = &self.app_event_tx
+            && let Err(e) = app_event_tx.send(AppEvent::SetPeerRooms(self.id.to_string(), rooms))
+        {
+            log::debug!("Failed to send peer rooms: {:?}", e);
+        }
+    }
+
+    /// Notes that we left `room`. Returns true if it was the last room we shared with the
+    /// peer, so there's no reason left to stay connected.
+    pub fn remove_room(&self, room: &str) -> bool {
+        let rooms: Vec<String> = {
+            let mut rooms_guard = self.rooms.lock().unwrap();
+            if !rooms_guard.remove(room) {
+                return false;
+            }
+            rooms_guard.iter().cloned().collect()
+        };
+        if rooms.is_empty() {
+            return true;
+        }
+        if let Some(app_event_tx) = &self.app_event_tx
+            && let Err(e) = app_event_tx.send(AppEvent::SetPeerRooms(self.id.to_string(), rooms))
+        {
+            log::debug!("Failed to send peer rooms: {:?}", e);
+        }
+        false
+    }
+
+    /// Whether the peer holding `key` may connect: through a room whose access lists let
+    /// it in, or directly if it was added outside any room.
+    fn admits(&self, key: &str) -> bool {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.is_empty() || rooms.iter().any(|room| self.room_access.permits(room, key))
+    }
+
+    /// Stops sending our audio to the peer unless it's in `talk_room`. Peers added
+    /// directly, outside any room, always hear us.
+    pub fn set_talk_room(&self, talk_room: Option<&str>) {
+        let rooms = self.rooms.lock().unwrap();
+        let listen_only =
+            talk_room.is_some_and(|talk_room| !rooms.is_empty() && !rooms.contains(talk_room));
+        self.listen_only.store(listen_only, Ordering::Relaxed);
+    }
+
+    /// The settings worth remembering for the next time we see this peer.
+    pub fn settings(&self) -> PeerSettings {
+        let pan = *self.playback.pan.lock().unwrap();
+        PeerSettings {
+            volume: *self.playback.volume.lock().unwrap(),
+            denoise: self.playback.denoise.load(Ordering::Relaxed),
+            pan: (!pan.is_auto()).then(|| pan.position()),
+            equalizer: *self.playback.equalizer.lock().unwrap(),
+            muted: self.playback.muted.load(Ordering::Relaxed),
+            verified: self.trust() == PeerTrust::Verified,
+        }
+    }
+
+    /// Snapshot of this peer for the UI.
+    fn app_peer(&self, state: PeerState) -> Peer {
+        Peer::new(
+            self.id.to_string(),
+            Some(self.display_name.clone()),
+            state,
+            self.playback.denoise.load(Ordering::Relaxed),
+            *self.playback.volume.lock().unwrap(),
+        )
+        .with_pan(*self.playback.pan.lock().unwrap())
+        .with_equalizer(*self.playback.equalizer.lock().unwrap())
+        .with_muted(self.playback.muted.load(Ordering::Relaxed))
+        .with_rooms(self.rooms())
+        .with_key(self.connection_info.identity())
+        .with_trust(self.trust())
+        .with_safety_number(safety_number(
+            &self.socket.connection_info().identity(),
+            &self.connection_info.identity(),
+        ))
+    }
+
     pub fn send_message(&self, message: String) -> anyhow::Result<()> {
         let protocol_message = ProtocolMessage::ChatMessage(message);
         if self.peer_message_tx.receiver_count() > 0 {
@@ -104,6 +322,14 @@ impl ManagedPeer {
         Ok(())
     }
 
+    pub fn send_recording_notice(&self, is_recording: bool) -> anyhow::Result<()> {
+        let protocol_message = ProtocolMessage::RecordingNotice(is_recording);
+        if self.peer_message_tx.receiver_count() > 0 {
+            self.peer_message_tx.send(protocol_message)?;
+        }
+        Ok(())
+    }
+
     pub fn enable(&self) {
         let id = self.id;
         let mut shutdown_rx = self.shutdown_tx.subscribe();
@@ -129,13 +355,9 @@ impl ManagedPeer {
         }
 
         if let Some(app_event_tx) = &self.app_event_tx
-            && let Err(e) = app_event_tx.send(AppEvent::AddPeer(Peer::new(
-                self.id.to_string(),
-                Some(self.display_name.clone()),
-                PeerState::Disabled,
-                self.denoise.load(Ordering::Relaxed),
-                *self.volume.lock().unwrap(),
-            ))) {
+            && let Err(e) = app_event_tx.send(AppEvent::AddPeer(
+                self.app_peer(PeerState::Disabled),
+            )) {
                 log::debug!("Failed to send app event: {:?}", e);
             }
 
@@ -143,14 +365,9 @@ impl ManagedPeer {
     }
 }
 
-/// Should never terminate.
-async fn run_connection_loop(peer: ManagedPeer) {
-    let ip_addresses: Vec<String> = peer
-        .connection_info
-        .addresses
-        .iter()
-        .map(|ip_addr| ip_addr.to_string())
-        .collect();
+/// Ends only once the peer may no longer connect.
+async fn run_connection_loop<T: Transport>(peer: ManagedPeer<T>) {
+    let ip_addresses = peer.connection_info.addresses();
 
     loop {
         log::info!("Beginning connect loop to peer {}", peer.id);
@@ -166,41 +383,51 @@ async fn run_connection_loop(peer: ManagedPeer) {
                 if let Ok(session) = session {
                     log::debug!("Connected to {}", peer.id);
 
+                    // The key the other side proved it holds while connecting.
+                    let key = peer.connection_info.identity();
+                    if !peer.admits(&key) {
+                        log::info!("Refusing session with {key}, which no shared room lets in.");
+                        drop(session);
+                        if let Err(e) = peer.disable() {
+                            log::debug!("Failed to disable refused peer: {:?}", e);
+                        }
+                        return;
+                    }
+
                     if let Ok(mut connection_status) = peer.connection_status.lock() {
                         *connection_status = ConnectionStatus::Connected;
                     }
 
                     if let Some(app_event_tx) = &peer.app_event_tx
-                        && let Err(e) = app_event_tx.send(AppEvent::AddPeer(Peer::new(
-                            peer.id.to_string(),
-                            Some(peer.display_name.clone()),
-                            PeerState::Connected(session.remote_addr().await.to_string()),
-                            peer.denoise.load(Ordering::Relaxed),
-                            *peer.volume.lock().unwrap(),
+                        && let Err(e) = app_event_tx.send(AppEvent::AddPeer(peer.app_peer(
+                            PeerState::Connected(session.remote_addr().await),
                         ))) {
                             log::debug!("Failed to send app event: {:?}", e);
                         }
 
                     log::info!("Starting clerver for connection with {}.", peer.id);
+                    let audio = CallAudio {
+                        backend: peer.audio_backend.clone(),
+                        sender_is_muted: peer.sender_is_muted.clone(),
+                        listen_only: peer.listen_only.clone(),
+                        controls: peer.playback.clone(),
+                        resampler_quality: peer.resampler_quality,
+                    };
                     run_clerver(
                         session,
+                        audio,
+                        (peer.own_member_key, peer.confirmed_member_key.clone()),
                         peer.app_event_tx.clone(),
-                        peer.sender_is_muted.clone(),
                         peer.peer_message_tx.subscribe(),
-                        peer.denoise.clone(),
-                        peer.volume.clone(),
+                        peer.dump_dir.clone(),
                         peer.id,
                     )
                     .await;
                 }
             },
             _ = update_app_connecting_status(
-                peer.id,
-                peer.display_name.clone(),
-                peer.denoise.clone(),
-                peer.volume.clone(),
+                peer.clone(),
                 ip_addresses.clone(),
-                peer.app_event_tx.clone()
             ) => {
                 log::debug!("Connecting status updater ended early.");
              },
@@ -209,30 +436,22 @@ async fn run_connection_loop(peer: ManagedPeer) {
 }
 
 /// Cycles through connection info and sends to app. Should never terminate.
-async fn update_app_connecting_status(
-    id: uuid::Uuid,
-    display_name: String,
-    denoise: Arc<AtomicBool>,
-    volume: Arc<Mutex<usize>>,
+async fn update_app_connecting_status<T: Transport>(
+    peer: ManagedPeer<T>,
     ip_addresses: Vec<String>,
-    app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
 ) {
     if ip_addresses.is_empty() {
         loop {
             tokio::time::sleep(std::time::Duration::from_secs(10000)).await;
         }
-    } else { match app_event_tx { Some(app_event_tx) => {
+    } else { match &peer.app_event_tx { Some(app_event_tx) => {
         let mut interval = tokio::time::interval(std::time::Duration::from_millis(500));
         loop {
             for ip_address in ip_addresses.iter() {
                 interval.tick().await;
-                if let Err(e) = app_event_tx.send(AppEvent::AddPeer(Peer::new(
-                    id.to_string(),
-                    Some(display_name.clone()),
-                    PeerState::Connecting(ip_address.clone()),
-                    denoise.load(Ordering::Relaxed),
-                    *volume.lock().unwrap(),
-                ))) {
+                if let Err(e) = app_event_tx.send(AppEvent::AddPeer(
+                    peer.app_peer(PeerState::Connecting(ip_address.clone())),
+                )) {
                     log::debug!("Failed to send app event: {:?}", e);
                 }
             }
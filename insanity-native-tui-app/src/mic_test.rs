@@ -0,0 +1,146 @@
+//! Hearing yourself as peers would, without joining a room. The microphone goes through
+//! the same encode, decode and playback path as a call, just delayed.
+
+use std::{collections::VecDeque, io::Write, sync::Arc, time::Duration};
+
+use insanity_core::{audio_source::AudioSource, loudness::calculate_loudness};
+use insanity_tui_adapter::AppEvent;
+use rubato_audio_source::{ResampledAudioSource, ResamplerQuality};
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+
+use crate::{
+    audio_device::{AudioBackend, CpalBackend},
+    clerver::{next_chunk, start_playback, FrameDecoder, FrameEncoder},
+    processor::{PlaybackControls, AUDIO_CHUNK_SIZE},
+};
+
+/// Width of the level meter printed by `insanity mic-test`.
+const METER_WIDTH: usize = 40;
+
+#[derive(Clone, Debug)]
+pub struct MicTestOptions {
+    /// How long after speaking you hear yourself.
+    pub delay: Duration,
+    pub denoise: bool,
+    pub resampler_quality: ResamplerQuality,
+}
+
+impl Default for MicTestOptions {
+    fn default() -> Self {
+        MicTestOptions {
+            delay: Duration::from_secs(2),
+            denoise: true,
+            resampler_quality: ResamplerQuality::default(),
+        }
+    }
+}
+
+/// Plays the microphone back after `options.delay`, reporting its level before encoding
+/// as `AppEvent::MicTestLevel`. Runs until dropped.
+pub async fn run_mic_test(
+    backend: &dyn AudioBackend,
+    options: MicTestOptions,
+    app_event_sender: Option<mpsc::UnboundedSender<AppEvent>>,
+) -> anyhow::Result<()> {
+    let input = backend.open_input()?;
+    let mut encoder = FrameEncoder::new(input.channels())?;
+    let mut input =
+        ResampledAudioSource::new(input, 48000, AUDIO_CHUNK_SIZE, options.resampler_quality);
+    let controls = PlaybackControls::standalone(options.denoise);
+    let (processor, _output_stream) = start_playback(
+        backend,
+        controls,
+        options.resampler_quality,
+        None,
+        "mic-test".to_string(),
+    )?;
+    let mut decoder = FrameDecoder::new();
+
+    // Encoded frames and when to play them.
+    let mut delayed = VecDeque::new();
+    loop {
+        let samples = next_chunk(&mut input).await;
+        if let Some(app_event_sender) = &app_event_sender
+            && let Err(e) =
+                app_event_sender.send(AppEvent::MicTestLevel(calculate_loudness(&samples)))
+        {
+            log::debug!("Failed to send mic test level: {:?}", e);
+        }
+        delayed.push_back((Instant::now() + options.delay, encoder.encode(samples)?));
+
+        while delayed
+            .front()
+            .is_some_and(|(due, _)| *due <= Instant::now())
+        {
+            let Some((_, frame)) = delayed.pop_front() else {
+                break;
+            };
+            // One bad frame is a gap in the loopback, not a reason to end the test.
+            match decoder.decode(frame) {
+                Ok(chunk) => processor.handle_incoming(chunk),
+                Err(e) => log::debug!("Skipping mic test frame that failed to decode: {:?}", e),
+            }
+        }
+    }
+}
+
+/// A mic test running in the background. It stops when dropped.
+pub struct MicTest {
+    task: JoinHandle<()>,
+}
+
+impl MicTest {
+    /// Sends `AppEvent::MicTest(false)` if the test fails.
+    pub fn start(
+        backend: Arc<dyn AudioBackend>,
+        options: MicTestOptions,
+        app_event_sender: Option<mpsc::UnboundedSender<AppEvent>>,
+    ) -> MicTest {
+        let task = tokio::spawn(async move {
+            let result = run_mic_test(backend.as_ref(), options, app_event_sender.clone()).await;
+            if let Err(e) = result {
+                log::error!("Mic test failed: {:?}", e);
+                if let Some(app_event_sender) = app_event_sender
+                    && let Err(e) = app_event_sender.send(AppEvent::MicTest(false))
+                {
+                    log::debug!("Failed to send mic test event: {:?}", e);
+                }
+            }
+        });
+        MicTest { task }
+    }
+}
+
+impl Drop for MicTest {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Runs the mic test on the default devices until Ctrl-C, printing a level meter.
+pub async fn mic_test(options: MicTestOptions) -> anyhow::Result<()> {
+    println!(
+        "Playing back your microphone after {:?}. Press Ctrl-C to stop.",
+        options.delay
+    );
+    let (app_event_tx, mut app_event_rx) = mpsc::unbounded_channel();
+    let test = run_mic_test(&CpalBackend, options, Some(app_event_tx));
+    tokio::pin!(test);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let mut stdout = std::io::stdout();
+    loop {
+        tokio::select! {
+            result = &mut test => return result,
+            Some(AppEvent::MicTestLevel(level)) = app_event_rx.recv() => {
+                let filled = std::cmp::min((level * METER_WIDTH as f64) as usize, METER_WIDTH);
+                print!("\r[{}{}]", "|".repeat(filled), " ".repeat(METER_WIDTH - filled));
+                stdout.flush()?;
+            }
+            _ = &mut ctrl_c => break,
+        }
+    }
+    println!();
+    Ok(())
+}
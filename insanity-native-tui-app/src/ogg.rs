@@ -0,0 +1,250 @@
+//! Just enough of the Ogg container (RFC 3533) to write and read back our own files.
+//! Every packet gets a page to itself, which keeps granule positions exact per packet.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+};
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+const HEADER_LEN: usize = 27;
+const MAX_SEGMENTS: usize = 255;
+
+pub const HEADER_TYPE_CONTINUED: u8 = 0x01;
+pub const HEADER_TYPE_BOS: u8 = 0x02;
+pub const HEADER_TYPE_EOS: u8 = 0x04;
+
+/// CRC-32 with polynomial 0x04c11db7, no reflection and no final xor, as Ogg uses.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// One logical bitstream being written.
+pub struct OggStream {
+    serial: u32,
+    page_sequence: u32,
+}
+
+impl OggStream {
+    pub fn new(serial: u32) -> OggStream {
+        OggStream {
+            serial,
+            page_sequence: 0,
+        }
+    }
+
+    /// Writes `packet` as a single page ending at `granule_position`.
+    pub fn write_packet<W: Write>(
+        &mut self,
+        writer: &mut W,
+        packet: &[u8],
+        granule_position: u64,
+        header_type: u8,
+    ) -> io::Result<()> {
+        // Lacing values: a run of 255s and a final value below 255 that ends the packet.
+        let mut segments = vec![255u8; packet.len() / 255];
+        segments.push((packet.len() % 255) as u8);
+        if segments.len() > MAX_SEGMENTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Packet of {} bytes does not fit in one page.", packet.len()),
+            ));
+        }
+
+        let mut page = Vec::with_capacity(HEADER_LEN + segments.len() + packet.len());
+        page.extend_from_slice(CAPTURE_PATTERN);
+        page.push(0); // Version
+        page.push(header_type);
+        page.extend_from_slice(&granule_position.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.page_sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]); // CRC, filled in below.
+        page.push(segments.len() as u8);
+        page.extend_from_slice(&segments);
+        page.extend_from_slice(packet);
+
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.page_sequence += 1;
+        writer.write_all(&page)
+    }
+}
+
+/// A packet read back from an Ogg file.
+pub struct OggPacket {
+    pub serial: u32,
+    /// Granule position of the page the packet ended on.
+    pub granule_position: u64,
+    /// Header type of the page the packet ended on.
+    pub header_type: u8,
+    pub data: Vec<u8>,
+}
+
+/// Reads packets from every logical bitstream in an Ogg file, in file order.
+pub struct OggReader<R: Read> {
+    reader: R,
+    /// Partial packets carried over to the next page, by serial.
+    partial: Vec<(u32, Vec<u8>)>,
+    ready: VecDeque<OggPacket>,
+}
+
+impl<R: Read> OggReader<R> {
+    pub fn new(reader: R) -> OggReader<R> {
+        OggReader {
+            reader,
+            partial: Vec::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Returns the next complete packet, or `None` at the end of the file.
+    pub fn next_packet(&mut self) -> io::Result<Option<OggPacket>> {
+        while self.ready.is_empty() {
+            if !self.read_page()? {
+                return Ok(None);
+            }
+        }
+        Ok(self.ready.pop_front())
+    }
+
+    /// Reads one page, returning false at a clean end of file.
+    fn read_page(&mut self) -> io::Result<bool> {
+        let mut header = [0u8; HEADER_LEN];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        if &header[0..4] != CAPTURE_PATTERN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Missing Ogg capture pattern.",
+            ));
+        }
+
+        let header_type = header[5];
+        let granule_position = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        let expected_crc = u32::from_le_bytes(header[22..26].try_into().unwrap());
+        let mut segments = vec![0u8; header[26] as usize];
+        self.reader.read_exact(&mut segments)?;
+        let body_len: usize = segments.iter().map(|segment| *segment as usize).sum();
+        let mut body = vec![0u8; body_len];
+        self.reader.read_exact(&mut body)?;
+
+        let mut page = header.to_vec();
+        page[22..26].fill(0);
+        page.extend_from_slice(&segments);
+        page.extend_from_slice(&body);
+        if crc32(&page) != expected_crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Ogg page checksum mismatch.",
+            ));
+        }
+
+        let previous = self
+            .partial
+            .iter()
+            .position(|(partial_serial, _)| *partial_serial == serial)
+            .map(|index| self.partial.swap_remove(index).1);
+        let mut packet = match previous {
+            Some(previous) if header_type & HEADER_TYPE_CONTINUED != 0 => previous,
+            _ => Vec::new(),
+        };
+        let mut offset = 0;
+        for segment in segments {
+            packet.extend_from_slice(&body[offset..offset + segment as usize]);
+            offset += segment as usize;
+            if segment < 255 {
+                self.ready.push_back(OggPacket {
+                    serial,
+                    granule_position,
+                    header_type,
+                    data: std::mem::take(&mut packet),
+                });
+            }
+        }
+        if !packet.is_empty() {
+            self.partial.push((serial, packet));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_matches_the_cksum_check_value() {
+        // CRC-32/CKSUM uses the same CRC with a final xor, and checks "123456789" as
+        // 0x765e7680.
+        assert_eq!(crc32(b"123456789") ^ 0xffff_ffff, 0x765e_7680);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn packets_read_back_as_written() {
+        // Sizes around the 255 byte lacing boundary, over two interleaved streams.
+        let sizes = [0, 1, 254, 255, 256, 510, 1000, 255 * 254];
+        let mut first = OggStream::new(7);
+        let mut second = OggStream::new(9);
+        let mut file = Vec::new();
+        let mut written = Vec::new();
+        for (i, size) in sizes.into_iter().enumerate() {
+            let data: Vec<u8> = (0..size).map(|byte| (byte * 31 + i) as u8).collect();
+            let (stream, serial) = if i % 2 == 0 {
+                (&mut first, 7)
+            } else {
+                (&mut second, 9)
+            };
+            let header_type = if i < 2 { HEADER_TYPE_BOS } else { 0 };
+            stream
+                .write_packet(&mut file, &data, i as u64 * 960, header_type)
+                .unwrap();
+            written.push((serial, i as u64 * 960, data));
+        }
+
+        let mut reader = OggReader::new(&file[..]);
+        let mut read = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            read.push((packet.serial, packet.granule_position, packet.data));
+        }
+        assert_eq!(read, written);
+    }
+
+    #[test]
+    fn corrupted_pages_are_rejected() {
+        let mut file = Vec::new();
+        OggStream::new(1)
+            .write_packet(&mut file, b"hello", 0, HEADER_TYPE_BOS)
+            .unwrap();
+        let last = file.len() - 1;
+        file[last] ^= 1;
+
+        let error = OggReader::new(&file[..]).next_packet().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn packets_too_big_for_one_page_are_refused() {
+        let packet = vec![0; 255 * 255];
+        let error = OggStream::new(1)
+            .write_packet(&mut Vec::new(), &packet, 0, 0)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
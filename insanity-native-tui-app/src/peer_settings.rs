@@ -0,0 +1,168 @@
+use insanity_core::{equalizer::EqualizerPreset, pan::Pan};
+
+const DB_TREE_PEER_SETTINGS: &str = "peer_settings";
+const DB_TREE_KNOWN_NAMES: &str = "known_names";
+
+/// What we remember about a peer between connections and restarts.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PeerSettings {
+    pub volume: usize,
+    pub denoise: bool,
+    /// Manually chosen position, or `None` for automatic placement.
+    pub pan: Option<isize>,
+    pub equalizer: EqualizerPreset,
+    pub muted: bool,
+    /// Safety number compared out of band. Settings are per key, so a new key starts over.
+    pub verified: bool,
+}
+
+impl Default for PeerSettings {
+    fn default() -> Self {
+        PeerSettings {
+            volume: 100,
+            denoise: true,
+            pan: None,
+            equalizer: EqualizerPreset::default(),
+            muted: false,
+            verified: false,
+        }
+    }
+}
+
+impl PeerSettings {
+    pub fn initial_pan(&self) -> Pan {
+        match self.pan {
+            Some(position) => Pan::Manual(position),
+            None => Pan::default(),
+        }
+    }
+}
+
+/// Per-peer settings in the connection manager database, keyed by the peer's identity,
+/// and the identity each display name was first seen with.
+#[derive(Clone)]
+pub struct PeerSettingsStore {
+    tree: sled::Tree,
+    known_names: sled::Tree,
+}
+
+impl PeerSettingsStore {
+    pub fn open(db: &sled::Db) -> anyhow::Result<PeerSettingsStore> {
+        Ok(PeerSettingsStore {
+            tree: db.open_tree(DB_TREE_PEER_SETTINGS)?,
+            known_names: db.open_tree(DB_TREE_KNOWN_NAMES)?,
+        })
+    }
+
+    /// Returns the stored settings, or the defaults for a peer we haven't seen before.
+    pub fn load(&self, identity: &str) -> PeerSettings {
+        match self.tree.get(identity) {
+            Ok(Some(value)) => serde_json::from_slice(&value).unwrap_or_else(|e| {
+                log::debug!(
+                    "Failed to parse stored peer settings, using defaults: {:?}",
+                    e
+                );
+                PeerSettings::default()
+            }),
+            Ok(None) => PeerSettings::default(),
+            Err(e) => {
+                log::debug!("Failed to read peer settings, using defaults: {:?}", e);
+                PeerSettings::default()
+            }
+        }
+    }
+
+    pub fn save(&self, identity: &str, settings: &PeerSettings) -> anyhow::Result<()> {
+        self.tree.insert(identity, serde_json::to_vec(settings)?)?;
+        Ok(())
+    }
+
+    /// Trusts the first identity seen with `display_name`. Returns false if the name was
+    /// first seen with a different one.
+    pub fn check_name(&self, display_name: &str, identity: &str) -> anyhow::Result<bool> {
+        match self.known_names.compare_and_swap(
+            display_name,
+            None as Option<&[u8]>,
+            Some(identity.as_bytes()),
+        )? {
+            Ok(()) => Ok(true),
+            Err(e) => Ok(e.current.is_some_and(|known| known == identity.as_bytes())),
+        }
+    }
+
+    /// Makes `identity` the one trusted with `display_name` from now on.
+    pub fn pin_name(&self, display_name: &str, identity: &str) -> anyhow::Result<()> {
+        self.known_names.insert(display_name, identity.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> PeerSettingsStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        PeerSettingsStore::open(&db).unwrap()
+    }
+
+    #[test]
+    fn settings_are_saved_per_peer_and_read_back() {
+        let store = store();
+        assert_eq!(store.load("alice key"), PeerSettings::default());
+
+        let settings = PeerSettings {
+            volume: 40,
+            denoise: false,
+            pan: Some(-50),
+            equalizer: EqualizerPreset::Voice,
+            muted: true,
+            verified: true,
+        };
+        store.save("alice key", &settings).unwrap();
+        assert_eq!(store.load("alice key"), settings);
+        assert_eq!(store.load("bob key"), PeerSettings::default());
+    }
+
+    #[test]
+    fn settings_saved_before_newer_fields_get_their_defaults() {
+        let store = store();
+        store
+            .tree
+            .insert(
+                "alice key",
+                br#"{"volume":40,"denoise":false,"pan":null,"equalizer":"Off","muted":true}"#
+                    .as_slice(),
+            )
+            .unwrap();
+        assert_eq!(
+            store.load("alice key"),
+            PeerSettings {
+                volume: 40,
+                denoise: false,
+                muted: true,
+                ..PeerSettings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn names_are_pinned_to_the_first_key_seen() {
+        let store = store();
+        assert!(store.check_name("alice", "alice key").unwrap());
+        assert!(store.check_name("alice", "alice key").unwrap());
+        assert!(!store.check_name("alice", "mallory key").unwrap());
+        // Other names are pinned separately.
+        assert!(store.check_name("bob", "bob key").unwrap());
+    }
+
+    #[test]
+    fn pinning_a_name_replaces_its_key() {
+        let store = store();
+        assert!(store.check_name("alice", "old key").unwrap());
+        store.pin_name("alice", "new key").unwrap();
+        assert!(store.check_name("alice", "new key").unwrap());
+        assert!(!store.check_name("alice", "old key").unwrap());
+    }
+}
@@ -1,23 +1,41 @@
+use std::f32::consts::{FRAC_1_SQRT_2, SQRT_2};
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 
 use std::sync::{Arc, Mutex};
 
 use cpal::{Sample, SampleRate};
 use insanity_core::audio_source::SyncAudioSource;
+use insanity_core::equalizer::EqualizerPreset;
 use insanity_core::loudness::calculate_loudness;
+use insanity_core::pan::{pan_gains, Pan, MAX_PAN};
 use insanity_tui_adapter::AppEvent;
-use log::error;
+use log::{error, trace};
 use nnnoiseless::DenoiseState;
-use rubato_audio_source::ResampledAudioSource;
+use rubato_audio_source::{ResampledAudioSource, ResamplerQuality};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
 
+use crate::drift::DriftCompensator;
+use crate::filter::FilterChain;
 use crate::realtime_buffer::RealTimeBuffer;
+use crate::recorder::Recorder;
+use crate::sample_ring::{sample_ring, SampleConsumer, SampleProducer};
 use crate::server::RealtimeAudioSource;
 
 pub const AUDIO_CHUNK_SIZE: usize = 480;
-pub const AUDIO_CHANNELS: u16 = 2;
+/// Channels of audio we make ourselves, like the bot's and virtual devices', where no
+/// device decides.
+pub const DEFAULT_CHANNELS: u16 = 2;
+/// Opus only encodes mono or stereo, so this is the most channels a stream can carry.
+pub const MAX_STREAM_CHANNELS: u16 = 2;
+
+/// Least output kept queued ahead of the audio callback, in chunks.
+const MIN_QUEUED_CHUNKS: usize = 1;
+/// The most output the playback ring holds, in chunks.
+const RING_CHUNKS: usize = 8;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct AudioFormat {
@@ -60,6 +78,70 @@ impl AudioChunk {
             audio_format: format,
         }
     }
+    pub fn remix(self, channel_count: u16) -> AudioChunk {
+        if self.audio_format.channel_count == channel_count {
+            return self;
+        }
+        let audio_data = remix_channels(
+            &self.audio_data,
+            self.audio_format.channel_count,
+            channel_count,
+        );
+        AudioChunk {
+            sequence_number: self.sequence_number,
+            audio_data,
+            audio_format: AudioFormat::new(channel_count, self.audio_format.sample_rate),
+        }
+    }
+}
+
+/// Converts interleaved samples from one channel layout to another.
+///
+/// Mono is copied to both front channels. Layouts with more than two channels keep their
+/// first two as left and right and fold the rest into both at -3 dB. Any output channels
+/// beyond the first two are left silent. Nothing comes out of or into zero channels.
+pub fn remix_channels(samples: &[f32], input_channels: u16, output_channels: u16) -> Vec<f32> {
+    if input_channels == 0 || output_channels == 0 {
+        log::debug!("Can't remix {input_channels} channels to {output_channels}.");
+        return Vec::new();
+    }
+    let input_channels = input_channels as usize;
+    let output_channels = output_channels as usize;
+    let mut output = Vec::with_capacity(samples.len() / input_channels * output_channels);
+    for frame in samples.chunks_exact(input_channels) {
+        let (left, right) = match frame {
+            [mono] => (*mono, *mono),
+            [left, right, rest @ ..] => {
+                let folded = rest.iter().sum::<f32>() * FRAC_1_SQRT_2;
+                (left + folded, right + folded)
+            }
+            [] => continue,
+        };
+        if output_channels == 1 {
+            output.push((left + right) / 2.0);
+        } else {
+            output.push(left);
+            output.push(right);
+            output.extend(std::iter::repeat_n(0.0, output_channels - 2));
+        }
+    }
+    output
+}
+
+/// Places the first two channels of each frame in the stereo field. The image narrows
+/// as it moves off center, from the peer's own stereo when centered to mono at either
+/// edge, so every step of the position sounds alike. Any further channels are left
+/// untouched.
+fn apply_pan(audio_data: &mut [f32], channel_count: u16, position: isize) {
+    let (left_gain, right_gain) = pan_gains(position);
+    // Both gains are 1 when centered.
+    let (left_gain, right_gain) = (left_gain * SQRT_2, right_gain * SQRT_2);
+    let width = 1.0 - (position.clamp(-MAX_PAN, MAX_PAN) as f32 / MAX_PAN as f32).abs();
+    for frame in audio_data.chunks_exact_mut(channel_count as usize) {
+        let mid = (frame[0] + frame[1]) / 2.0;
+        frame[0] = (width * frame[0] + (1.0 - width) * mid) * left_gain;
+        frame[1] = (width * frame[1] + (1.0 - width) * mid) * right_gain;
+    }
 }
 
 pub struct MultiChannelDenoiser<'a> {
@@ -141,50 +223,138 @@ impl MultiChannelDenoiser<'_> {
     }
 }
 
+/// Playback settings for one peer, shared between the connection manager and the audio path.
+#[derive(Clone)]
+pub struct PlaybackControls {
+    pub denoise: Arc<AtomicBool>,
+    pub volume: Arc<Mutex<usize>>,
+    pub pan: Arc<Mutex<Pan>>,
+    pub equalizer: Arc<Mutex<EqualizerPreset>>,
+    /// Silences this peer for us only.
+    pub muted: Arc<AtomicBool>,
+    /// Silences every peer. The same flag is shared by all of them.
+    pub deafened: Arc<AtomicBool>,
+    /// The call recorder, shared by all peers.
+    pub recorder: Recorder,
+}
+
+impl PlaybackControls {
+    /// Controls for playback outside a call: full volume, centered, nothing silenced.
+    pub fn standalone(denoise: bool) -> PlaybackControls {
+        PlaybackControls {
+            denoise: Arc::new(AtomicBool::new(denoise)),
+            volume: Arc::new(Mutex::new(100)),
+            pan: Arc::new(Mutex::new(Pan::default())),
+            equalizer: Arc::new(Mutex::new(EqualizerPreset::default())),
+            muted: Arc::new(AtomicBool::new(false)),
+            deafened: Arc::new(AtomicBool::new(false)),
+            recorder: Recorder::new(),
+        }
+    }
+
+    pub fn is_silenced(&self) -> bool {
+        self.muted.load(Ordering::Relaxed) || self.deafened.load(Ordering::Relaxed)
+    }
+}
+
+/// Processes what a peer sends us and queues it for playback. The audio callback reads
+/// through the matching [`PlaybackOutput`].
 pub struct AudioProcessor<'a> {
-    enable_denoise: Arc<AtomicBool>,
-    volume: Arc<Mutex<usize>>,
+    controls: PlaybackControls,
     denoiser: Mutex<MultiChannelDenoiser<'a>>,
+    filter_chain: Mutex<Option<FilterChain>>,
     chunk_buffer: Arc<Mutex<RealTimeBuffer<AudioChunk>>>,
-    audio_receiver: Mutex<ResampledAudioSource<RealtimeAudioSource>>,
     app_event_sender: Option<UnboundedSender<AppEvent>>,
     peer_id: String,
-    last_sample: Mutex<f32>,
+    output_channels: u16,
+    /// Resamples buffered chunks into the playback ring.
+    refill: JoinHandle<()>,
 }
 
 impl AudioProcessor<'_> {
+    /// Must be called within a tokio runtime, which runs the worker feeding playback.
     pub fn new(
-        enable_denoise: Arc<AtomicBool>,
-        volume: Arc<Mutex<usize>>,
+        controls: PlaybackControls,
         output_sample_rate: SampleRate,
+        output_channels: u16,
+        resampler_quality: ResamplerQuality,
         app_event_sender: Option<UnboundedSender<AppEvent>>,
         peer_id: String,
-    ) -> Self {
+    ) -> (Self, PlaybackOutput) {
+        // A device without channels plays nothing, but a stream of them would divide by zero.
+        let output_channels = output_channels.max(1);
         let chunk_buffer = Arc::new(Mutex::new(RealTimeBuffer::new(10)));
-        let audio_receiver = RealtimeAudioSource::new(chunk_buffer.clone(), 48000, 2);
-        let audio_receiver =
-            ResampledAudioSource::new(audio_receiver, output_sample_rate.0, AUDIO_CHUNK_SIZE);
+        let audio_receiver = RealtimeAudioSource::new(chunk_buffer.clone(), 48000, output_channels);
+        // Adjustable so playback can follow the sender's clock.
+        let audio_receiver = ResampledAudioSource::adjustable(
+            audio_receiver,
+            output_sample_rate.0,
+            AUDIO_CHUNK_SIZE,
+            resampler_quality,
+        );
+
+        let chunk_len = AUDIO_CHUNK_SIZE * output_channels as usize;
+        let (producer, consumer) = sample_ring(RING_CHUNKS * chunk_len);
+        let callback_len = Arc::new(AtomicUsize::new(0));
+        let refill = tokio::spawn(refill_playback(
+            audio_receiver,
+            chunk_buffer.clone(),
+            producer,
+            callback_len.clone(),
+            chunk_len,
+        ));
 
-        AudioProcessor {
-            enable_denoise,
-            volume,
+        let processor = AudioProcessor {
+            controls,
             denoiser: Mutex::new(MultiChannelDenoiser::new()),
-            audio_receiver: Mutex::new(audio_receiver),
+            filter_chain: Mutex::new(None),
             chunk_buffer,
             app_event_sender,
             peer_id,
-            last_sample: Mutex::new(0.0),
-        }
+            output_channels,
+            refill,
+        };
+        let output = PlaybackOutput {
+            consumer,
+            samples: vec![0.0; chunk_len],
+            last_sample: 0.0,
+            callback_len,
+        };
+        (processor, output)
     }
 
     pub fn handle_incoming(&self, mut chunk: AudioChunk) {
-        if self.enable_denoise.load(Ordering::Relaxed) {
+        // Recorded as the peer sent it, before anything we do to our own playback.
+        if self.controls.recorder.is_recording() {
+            self.controls.recorder.record(&self.peer_id, &chunk);
+        }
+
+        // Nothing of it is played, so there's no point denoising or filtering it.
+        let silenced = self.controls.is_silenced();
+
+        if !silenced && self.controls.denoise.load(Ordering::Relaxed) {
             let mut denoiser_guard = self.denoiser.lock().unwrap();
             chunk = denoiser_guard.denoise_chunk(&chunk);
         }
 
+        let equalizer = { *self.controls.equalizer.lock().unwrap() };
+        if !silenced && equalizer != EqualizerPreset::Off {
+            let AudioFormat {
+                channel_count,
+                sample_rate,
+            } = chunk.audio_format;
+            let mut filter_chain_guard = self.filter_chain.lock().unwrap();
+            let filter_chain = filter_chain_guard
+                .take()
+                .filter(|filter_chain| filter_chain.matches(equalizer, sample_rate, channel_count))
+                .unwrap_or_else(|| FilterChain::new(equalizer, sample_rate, channel_count));
+            filter_chain_guard
+                .insert(filter_chain)
+                .process(&mut chunk.audio_data);
+        }
+
         // Adjust volume if necessary
-        let volume = { *self.volume.lock().unwrap() };
+        let volume = { *self.controls.volume.lock().unwrap() };
         if volume != 100 {
             let mut audio_data = chunk.audio_data;
             let a: f32 = 0.2;
@@ -203,29 +373,210 @@ impl AudioProcessor<'_> {
             }
         }
 
+        // Still buffered while silenced so playback resumes without a gap.
+        if silenced {
+            chunk.audio_data.fill(0.0);
+        }
+
+        // Match the output device layout so the playback path only ever sees one format.
+        let mut chunk = chunk.remix(self.output_channels);
+
+        // Centered peers are played as they are.
+        let pan_position = { self.controls.pan.lock().unwrap().position() };
+        if self.output_channels >= 2 && pan_position != 0 {
+            apply_pan(&mut chunk.audio_data, self.output_channels, pan_position);
+        }
+
         let mut guard = self.chunk_buffer.lock().unwrap();
         guard.set(chunk.sequence_number, chunk);
     }
+}
 
-    pub fn fill_buffer<T: Sample>(&self, to_fill: &mut [T]) {
-        let mut last_sample = {
-            let last_sample_guard = self.last_sample.lock().unwrap();
-            *last_sample_guard
-        };
+impl Drop for AudioProcessor<'_> {
+    fn drop(&mut self) {
+        self.refill.abort();
+    }
+}
 
-        // LOL this is insane maybe we should use channels or something proper
-        for val in to_fill.iter_mut() {
-            let mut audio_receiver_guard = self.audio_receiver.lock().unwrap();
-            *val = match audio_receiver_guard.next_sync() {
-                None => Sample::from(&last_sample), // cry b/c there's no packets
-                Some(sample) => {
-                    last_sample = sample;
-                    Sample::from(&sample)
-                }
+/// Keeps enough output queued for the next callback, and a little more, by resampling
+/// buffered chunks after each callback. Runs outside the audio callback so it may lock
+/// and allocate.
+///
+/// Also keeps the chunk buffer at a steady depth despite clock drift, by nudging the
+/// resampling ratio.
+async fn refill_playback(
+    mut audio_receiver: ResampledAudioSource<RealtimeAudioSource>,
+    chunk_buffer: Arc<Mutex<RealTimeBuffer<AudioChunk>>>,
+    mut producer: SampleProducer,
+    callback_len: Arc<AtomicUsize>,
+    chunk_len: usize,
+) {
+    let mut block = vec![0f32; chunk_len];
+    let mut drift = DriftCompensator::new();
+    loop {
+        let depth = { chunk_buffer.lock().unwrap().len() };
+        if let Some(ratio) = drift.update(depth, tokio::time::Instant::now()) {
+            trace!(
+                "Buffer depth {depth}, drift {:.0} ppm, playing at {ratio:.5}",
+                drift.drift_ppm()
+            );
+            audio_receiver.set_ratio_relative(ratio);
+        }
+        let target = std::cmp::max(
+            MIN_QUEUED_CHUNKS * chunk_len,
+            2 * callback_len.load(Ordering::Relaxed),
+        )
+        .min(producer.capacity());
+        while producer.queued() < target {
+            let wanted = std::cmp::min(block.len(), target - producer.queued());
+            let filled = audio_receiver.fill_sync(&mut block[..wanted]);
+            producer.push_slice(&block[..filled]);
+            if filled < wanted {
+                break; // cry b/c there's no packets
+            }
+        }
+        // The audio callback wakes us each time it takes samples.
+        producer.writable().await;
+    }
+}
+
+/// The audio callback's end of playback. It never locks or allocates.
+pub struct PlaybackOutput {
+    consumer: SampleConsumer,
+    /// Scratch space for converting samples, allocated up front.
+    samples: Vec<f32>,
+    /// Repeated when there are no packets.
+    last_sample: f32,
+    /// Size of the latest callback, so the refill worker knows how far ahead to stay.
+    callback_len: Arc<AtomicUsize>,
+}
+
+impl PlaybackOutput {
+    pub fn fill_buffer<T: Sample>(&mut self, to_fill: &mut [T]) {
+        self.callback_len.store(to_fill.len(), Ordering::Relaxed);
+        for to_fill in to_fill.chunks_mut(self.samples.len()) {
+            let samples = &mut self.samples[..to_fill.len()];
+            let filled = self.consumer.pop_slice(samples);
+            if let Some(sample) = samples[..filled].last() {
+                self.last_sample = *sample;
+            }
+            samples[filled..].fill(self.last_sample);
+            for (val, sample) in to_fill.iter_mut().zip(samples.iter()) {
+                *val = Sample::from(sample);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remix_spreads_mono_and_folds_surround() {
+        assert_eq!(remix_channels(&[0.5, -0.5], 1, 2), [0.5, 0.5, -0.5, -0.5]);
+        assert_eq!(remix_channels(&[0.2, 0.4], 2, 1), [0.3]);
+        let folded = remix_channels(&[0.1, 0.2, 0.5, 0.5], 4, 3);
+        let expected = [0.1 + FRAC_1_SQRT_2, 0.2 + FRAC_1_SQRT_2, 0.0];
+        for (sample, expected) in folded.iter().zip(expected) {
+            assert!((sample - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn remix_of_zero_channels_is_empty() {
+        assert!(remix_channels(&[0.5, 0.5], 0, 2).is_empty());
+        assert!(remix_channels(&[0.5, 0.5], 2, 0).is_empty());
+    }
+
+    fn panned(frame: [f32; 2], position: isize) -> [f32; 2] {
+        let mut frame = frame;
+        apply_pan(&mut frame, 2, position);
+        frame
+    }
+
+    #[test]
+    fn centered_peers_keep_their_stereo() {
+        let [left, right] = panned([0.2, 0.6], 0);
+        assert!((left - 0.2).abs() < 1e-6 && (right - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn panning_narrows_stereo_step_by_step() {
+        let frame = [0.2, 0.6];
+        let mut previous = panned(frame, 0);
+        for position in 1..=MAX_PAN {
+            let current = panned(frame, position);
+            // No jump between neighboring positions, in particular just off center.
+            assert!((current[0] - previous[0]).abs() < 0.02, "{position}");
+            assert!((current[1] - previous[1]).abs() < 0.02, "{position}");
+            previous = current;
+        }
+        // Mono at the edge, all on the right.
+        let [left, right] = panned(frame, MAX_PAN);
+        assert!(left.abs() < 1e-6);
+        assert!((right - 0.4 * SQRT_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn panning_keeps_the_power_of_mono() {
+        for position in [-MAX_PAN, -30, 0, 1, 70, MAX_PAN] {
+            let [left, right] = panned([0.5, 0.5], position);
+            assert!(
+                (left * left + right * right - 0.5).abs() < 1e-5,
+                "{position}"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silenced_peers_are_still_recorded_as_sent() {
+        let recordings_dir = tempfile::tempdir().unwrap();
+        let controls = PlaybackControls::standalone(false);
+        *controls.volume.lock().unwrap() = 10;
+        *controls.pan.lock().unwrap() = Pan::Manual(-100);
+        let recording = controls
+            .recorder
+            .start(recordings_dir.path())
+            .unwrap()
+            .unwrap();
+        let (processor, _output) = AudioProcessor::new(
+            controls.clone(),
+            SampleRate(48000),
+            2,
+            ResamplerQuality::default(),
+            None,
+            "peer".to_string(),
+        );
+
+        let format = AudioFormat::new(2, 48000);
+        for sequence_number in 0..100 {
+            // Muted for the first half, deafened for the rest.
+            let silenced = if sequence_number < 50 {
+                &controls.muted
+            } else {
+                &controls.deafened
             };
+            controls.muted.store(false, Ordering::Relaxed);
+            silenced.store(true, Ordering::Relaxed);
+            tokio::time::advance(std::time::Duration::from_millis(10)).await;
+            let samples = [0.25, 0.5].repeat(AUDIO_CHUNK_SIZE);
+            processor.handle_incoming(AudioChunk::new(sequence_number, format.clone(), samples));
         }
+        controls.recorder.stop();
+        controls.recorder.finished().await;
 
-        let mut last_sample_guard = self.last_sample.lock().unwrap();
-        *last_sample_guard = last_sample;
+        let track = recording.dir.join(crate::recorder::track_file_name("peer"));
+        let samples = hound::WavReader::open(track)
+            .unwrap()
+            .into_samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let frames: Vec<&[f32]> = samples
+            .chunks(2)
+            .filter(|frame| frame.iter().any(|sample| *sample != 0.0))
+            .collect();
+        assert_eq!(frames.len(), 100 * AUDIO_CHUNK_SIZE);
+        assert!(frames.iter().all(|frame| *frame == [0.25, 0.5]));
     }
 }
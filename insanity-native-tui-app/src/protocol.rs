@@ -1,4 +1,5 @@
 use bincode::ErrorKind;
+use ed25519_dalek::VerifyingKey;
 use serde::{Deserialize, Serialize};
 
 use std::io::{Error, Write};
@@ -11,6 +12,11 @@ pub enum ProtocolMessage {
     IdentityDeclaration(PeerIdentity),
     PeerDiscovery(Vec<PeerIdentity>),
     ChatMessage(String),
+    /// Tells the peer whether we are recording the call.
+    RecordingNotice(bool),
+    /// The key we sign our room entries with. Sent over the connection, only the holder of
+    /// our identity can vouch for it.
+    MemberKey(VerifyingKey),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -0,0 +1,444 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use tokio::{sync::watch, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::processor::AudioChunk;
+
+const RECORDING_SAMPLE_RATE: u32 = 48000;
+const RECORDING_CHANNELS: u16 = 2;
+const MIX_FILE_NAME: &str = "mix.wav";
+/// The track our own microphone is recorded to.
+pub const SELF_TRACK: &str = "self";
+
+/// How far tracks and the mix lag behind the newest audio, so late chunks can still be
+/// placed. A stream that falls further behind than this is re-anchored.
+const WRITE_DELAY_FRAMES: u64 = RECORDING_SAMPLE_RATE as u64;
+
+type Writer = WavWriter<BufWriter<File>>;
+
+enum RecorderMessage {
+    Chunk {
+        track: String,
+        sequence_number: u128,
+        /// When the chunk arrived, as the frame it would have started at.
+        arrival_frame: u64,
+        samples: Vec<f32>,
+    },
+    Stop,
+}
+
+struct RecordingSession {
+    started: Instant,
+    message_tx: mpsc::Sender<RecorderMessage>,
+    stopped: CancellationToken,
+}
+
+/// A recording that has just started.
+pub struct Recording {
+    pub dir: PathBuf,
+    /// Cancelled once the recording stops.
+    pub stopped: CancellationToken,
+}
+
+/// Records the call to WAV files: one track per peer with its audio as it was sent, one
+/// for our own microphone and the mix of all of them. Our own volume, pan, mute and
+/// deafen only change what we hear, not what's recorded.
+///
+/// Every track starts at the same instant. Chunks are placed by their sequence numbers,
+/// so network jitter and reordering don't shift them, and the files line up with each
+/// other and with the call.
+#[derive(Clone)]
+pub struct Recorder {
+    session: Arc<Mutex<Option<RecordingSession>>>,
+    /// How many writers are still finalizing their files.
+    writers: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Recorder {
+    fn default() -> Recorder {
+        Recorder {
+            session: Arc::default(),
+            writers: Arc::new(watch::Sender::new(0)),
+        }
+    }
+}
+
+/// Counts a writer as running until it is dropped, even if the writer panics.
+struct RunningWriter(Arc<watch::Sender<usize>>);
+
+impl Drop for RunningWriter {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            log::error!("Recording writer panicked.");
+        }
+        self.0.send_modify(|writers| *writers -= 1);
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    /// Starts recording into a new directory under `recordings_dir`. Returns `None` if
+    /// already recording, leaving that recording running.
+    pub fn start(&self, recordings_dir: &Path) -> anyhow::Result<Option<Recording>> {
+        let mut session = self.session.lock().unwrap();
+        if session.is_some() {
+            log::debug!("Already recording.");
+            return Ok(None);
+        }
+
+        let dir = recordings_dir.join(chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string());
+        std::fs::create_dir_all(&dir)?;
+        let mix = Timeline::create(&dir.join(MIX_FILE_NAME))?;
+
+        let (message_tx, message_rx) = mpsc::channel();
+        let writer_dir = dir.clone();
+        self.writers.send_modify(|writers| *writers += 1);
+        let running = RunningWriter(self.writers.clone());
+        std::thread::spawn(move || {
+            let _running = running;
+            run_writer(writer_dir, mix, message_rx);
+        });
+        let stopped = CancellationToken::new();
+        *session = Some(RecordingSession {
+            started: Instant::now(),
+            message_tx,
+            stopped: stopped.clone(),
+        });
+        log::info!("Started recording to {:?}", dir);
+        Ok(Some(Recording { dir, stopped }))
+    }
+
+    /// Stops recording. The files are finalized in the background; see
+    /// [`Recorder::finished`].
+    pub fn stop(&self) {
+        let Some(session) = self.session.lock().unwrap().take() else {
+            return;
+        };
+        session.stopped.cancel();
+        if let Err(e) = session.message_tx.send(RecorderMessage::Stop) {
+            log::debug!("Failed to stop recording writer: {:?}", e);
+        }
+        log::info!("Stopped recording.");
+    }
+
+    /// Waits until no recording's files are still being written, after [`Recorder::stop`].
+    pub async fn finished(&self) {
+        let mut writers = self.writers.subscribe();
+        if writers.wait_for(|writers| *writers == 0).await.is_err() {
+            log::debug!("Recorder dropped while waiting for its files.");
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.session.lock().unwrap().is_some()
+    }
+
+    /// Adds a chunk that has just arrived to the named track.
+    pub fn record(&self, track: &str, chunk: &AudioChunk) {
+        let session = self.session.lock().unwrap();
+        let Some(session) = session.as_ref() else {
+            return;
+        };
+        if chunk.audio_format.sample_rate != RECORDING_SAMPLE_RATE {
+            log::debug!(
+                "Not recording chunk at unexpected sample rate {}.",
+                chunk.audio_format.sample_rate
+            );
+            return;
+        }
+
+        let samples = chunk.clone().remix(RECORDING_CHANNELS).audio_data;
+        let frames = (samples.len() / RECORDING_CHANNELS as usize) as u64;
+        let end_frame =
+            (session.started.elapsed().as_secs_f64() * RECORDING_SAMPLE_RATE as f64) as u64;
+        let message = RecorderMessage::Chunk {
+            track: track.to_string(),
+            sequence_number: chunk.sequence_number,
+            arrival_frame: end_frame.saturating_sub(frames),
+            samples,
+        };
+        if let Err(e) = session.message_tx.send(message) {
+            log::debug!("Failed to send chunk to recording writer: {:?}", e);
+        }
+    }
+}
+
+fn wav_spec() -> WavSpec {
+    WavSpec {
+        channels: RECORDING_CHANNELS,
+        sample_rate: RECORDING_SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    }
+}
+
+/// Places a stream's chunks by sequence number, relative to when the stream's least
+/// delayed chunk arrived.
+#[derive(Default)]
+struct Clock {
+    /// The recording frame sequence number 0 would start at.
+    anchor: Option<i128>,
+}
+
+impl Clock {
+    fn place(&mut self, sequence_number: u128, frames: u64, arrival_frame: u64) -> u64 {
+        let sent_frame = (sequence_number as i128).saturating_mul(frames as i128);
+        let anchor = arrival_frame as i128 - sent_frame;
+        let anchor = match self.anchor {
+            // A chunk that arrived sooner than the others shows how early they could have.
+            Some(current) if anchor < current => anchor,
+            // Too far behind to place: the sender restarted or its clock drifted.
+            Some(current) if anchor - current > WRITE_DELAY_FRAMES as i128 => {
+                log::debug!("Re-anchoring recorded stream.");
+                anchor
+            }
+            Some(current) => current,
+            None => anchor,
+        };
+        self.anchor = Some(anchor);
+        (anchor + sent_frame).max(0) as u64
+    }
+}
+
+struct Track {
+    timeline: Timeline,
+    clock: Clock,
+}
+
+/// Audio laid out by frame, held in memory until no more can arrive for it. Audio placed
+/// on top of other audio is summed with it.
+struct Timeline {
+    writer: Writer,
+    /// Frame of the first sample in `pending`.
+    start_frame: u64,
+    pending: VecDeque<f32>,
+}
+
+impl Timeline {
+    fn create(path: &Path) -> hound::Result<Timeline> {
+        Ok(Timeline {
+            writer: WavWriter::create(path, wav_spec())?,
+            start_frame: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn add(&mut self, start_frame: u64, samples: &[f32]) {
+        let channels = RECORDING_CHANNELS as usize;
+        // Anything before the start of the pending audio has already been written.
+        let skip = self.start_frame.saturating_sub(start_frame) as usize * channels;
+        if skip >= samples.len() {
+            return;
+        }
+        let offset = start_frame.saturating_sub(self.start_frame) as usize * channels;
+        let end = offset + samples.len() - skip;
+        if self.pending.len() < end {
+            self.pending.resize(end, 0.0);
+        }
+        for (i, sample) in samples[skip..].iter().enumerate() {
+            self.pending[offset + i] += sample;
+        }
+    }
+
+    /// Writes out all pending audio before `frame`, padding with silence if needed.
+    fn write_until(&mut self, frame: u64) -> hound::Result<()> {
+        while self.start_frame < frame {
+            for _ in 0..RECORDING_CHANNELS {
+                self.writer
+                    .write_sample(self.pending.pop_front().unwrap_or(0.0))?;
+            }
+            self.start_frame += 1;
+        }
+        Ok(())
+    }
+
+    fn finalize(mut self) -> hound::Result<()> {
+        let end_frame =
+            self.start_frame + (self.pending.len() / RECORDING_CHANNELS as usize) as u64;
+        self.write_until(end_frame)?;
+        self.writer.finalize()
+    }
+}
+
+pub(crate) fn track_file_name(track: &str) -> String {
+    if track == SELF_TRACK {
+        format!("{SELF_TRACK}.wav")
+    } else {
+        format!("peer-{track}.wav")
+    }
+}
+
+fn run_writer(dir: PathBuf, mut mix: Timeline, message_rx: mpsc::Receiver<RecorderMessage>) {
+    let mut tracks: HashMap<String, Track> = HashMap::new();
+    while let Ok(RecorderMessage::Chunk {
+        track,
+        sequence_number,
+        arrival_frame,
+        samples,
+    }) = message_rx.recv()
+    {
+        if !tracks.contains_key(&track) {
+            match Timeline::create(&dir.join(track_file_name(&track))) {
+                Ok(timeline) => {
+                    let new_track = Track {
+                        timeline,
+                        clock: Clock::default(),
+                    };
+                    tracks.insert(track.clone(), new_track);
+                }
+                Err(e) => {
+                    log::error!("Failed to create recording track for {track}: {:?}", e);
+                    continue;
+                }
+            }
+        }
+        let Some(recording_track) = tracks.get_mut(&track) else {
+            continue;
+        };
+
+        let frames = (samples.len() / RECORDING_CHANNELS as usize) as u64;
+        let position = recording_track
+            .clock
+            .place(sequence_number, frames, arrival_frame);
+        recording_track.timeline.add(position, &samples);
+        mix.add(position, &samples);
+        let written = arrival_frame.saturating_sub(WRITE_DELAY_FRAMES);
+        let result = recording_track
+            .timeline
+            .write_until(written)
+            .and_then(|()| mix.write_until(written));
+        if let Err(e) = result {
+            log::error!("Failed to write recording for {track}: {:?}", e);
+        }
+    }
+
+    for (track, recording_track) in tracks {
+        if let Err(e) = recording_track.timeline.finalize() {
+            log::error!("Failed to finalize recording track for {track}: {:?}", e);
+        }
+    }
+    if let Err(e) = mix.finalize() {
+        log::error!("Failed to finalize recording mix: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::processor::AudioFormat;
+
+    const FRAMES: u64 = 480;
+
+    #[test]
+    fn jitter_and_reordering_leave_chunks_in_place() {
+        let mut clock = Clock::default();
+        // Sent every 480 frames, the first one arriving 50 frames after it was sent and the
+        // rest later still, out of order.
+        let arrivals = [(0, 50), (2, 1100), (1, 700), (3, 1500), (4, 1990)];
+        for (sequence_number, arrival_frame) in arrivals {
+            let position = clock.place(sequence_number, FRAMES, arrival_frame);
+            assert_eq!(position, 50 + sequence_number as u64 * FRAMES);
+        }
+    }
+
+    #[test]
+    fn the_least_delayed_chunk_sets_the_anchor() {
+        let mut clock = Clock::default();
+        assert_eq!(clock.place(0, FRAMES, 500), 500);
+        // Arrived sooner after being sent than the first chunk did.
+        assert_eq!(clock.place(1, FRAMES, 530), 530);
+        assert_eq!(clock.place(2, FRAMES, 1100), 1010);
+    }
+
+    #[test]
+    fn a_restarted_stream_is_re_anchored() {
+        let mut clock = Clock::default();
+        for sequence_number in 0..4 {
+            clock.place(sequence_number, FRAMES, sequence_number as u64 * FRAMES);
+        }
+        let restart = 3 * RECORDING_SAMPLE_RATE as u64;
+        assert_eq!(clock.place(0, FRAMES, restart), restart);
+        assert_eq!(clock.place(1, FRAMES, restart + FRAMES), restart + FRAMES);
+    }
+
+    /// A chunk that is silent apart from a click on its first frame.
+    fn click(sequence_number: u128) -> AudioChunk {
+        let mut samples = vec![0.0; FRAMES as usize * RECORDING_CHANNELS as usize];
+        samples[0] = 0.5;
+        samples[1] = 0.5;
+        AudioChunk::new(
+            sequence_number,
+            AudioFormat::new(RECORDING_CHANNELS, RECORDING_SAMPLE_RATE),
+            samples,
+        )
+    }
+
+    fn clicks(path: &Path) -> Vec<(usize, f32)> {
+        let samples = hound::WavReader::open(path)
+            .unwrap()
+            .into_samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        samples
+            .chunks(RECORDING_CHANNELS as usize)
+            .enumerate()
+            .filter(|(_, frame)| frame[0] != 0.0)
+            .map(|(frame, samples)| (frame, samples[0]))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tracks_and_mix_follow_sequence_numbers() {
+        let recordings_dir = tempfile::tempdir().unwrap();
+        let recorder = Recorder::new();
+        let recording = recorder.start(recordings_dir.path()).unwrap().unwrap();
+        // Asking again leaves the recording as it is.
+        assert!(recorder.start(recordings_dir.path()).unwrap().is_none());
+        assert!(!recording.stopped.is_cancelled());
+
+        // Each chunk lasts 10 ms. Peer a's second chunk arrives late, after its third.
+        let arrivals = [
+            vec![("a", 0), (SELF_TRACK, 0)],
+            vec![(SELF_TRACK, 1)],
+            vec![("a", 2), ("a", 1), (SELF_TRACK, 2)],
+            vec![("a", 3), (SELF_TRACK, 3)],
+        ];
+        for chunks in arrivals {
+            tokio::time::advance(Duration::from_millis(10)).await;
+            for (track, sequence_number) in chunks {
+                recorder.record(track, &click(sequence_number));
+            }
+        }
+        recorder.stop();
+        recorder.finished().await;
+        assert!(recording.stopped.is_cancelled());
+
+        let expected: Vec<usize> = (0..4).map(|i| i * FRAMES as usize).collect();
+        for file_name in [track_file_name("a"), track_file_name(SELF_TRACK)] {
+            let track = clicks(&recording.dir.join(file_name));
+            let frames: Vec<usize> = track.iter().map(|(frame, _)| *frame).collect();
+            assert_eq!(frames, expected);
+        }
+        let mix = clicks(&recording.dir.join(MIX_FILE_NAME));
+        assert_eq!(
+            mix,
+            expected
+                .iter()
+                .map(|frame| (*frame, 1.0))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::{path::Path, time::Duration};
+
+use rubato_audio_source::ResamplerQuality;
+
+use crate::{
+    audio_device::{AudioBackend, CpalBackend},
+    clerver::{start_playback, FrameDecoder},
+    dump::{read_dump, DumpedFrame},
+    processor::PlaybackControls,
+};
+
+/// How long to keep playing after the last frame so the buffer drains.
+const DRAIN_DURATION: Duration = Duration::from_millis(500);
+
+/// Plays a frame dump through the same decode and playback path as a live call,
+/// feeding each frame in at the time it originally arrived.
+pub async fn replay(
+    path: &Path,
+    denoise: bool,
+    resampler_quality: ResamplerQuality,
+) -> anyhow::Result<()> {
+    replay_on(&CpalBackend, path, denoise, resampler_quality).await
+}
+
+async fn replay_on(
+    backend: &dyn AudioBackend,
+    path: &Path,
+    denoise: bool,
+    resampler_quality: ResamplerQuality,
+) -> anyhow::Result<()> {
+    let frames = read_dump(path)?;
+    println!("Replaying {} frames from {:?}", frames.len(), path);
+
+    let controls = PlaybackControls::standalone(denoise);
+    let (processor, _output_stream) = start_playback(
+        backend,
+        controls,
+        resampler_quality,
+        None,
+        "replay".to_string(),
+    )?;
+    let mut decoder = FrameDecoder::new();
+
+    let start = tokio::time::Instant::now();
+    for DumpedFrame { arrival, frame } in frames {
+        tokio::time::sleep_until(start + arrival).await;
+        match decoder.decode(frame) {
+            Ok(chunk) => processor.handle_incoming(chunk),
+            Err(e) => println!("Failed to decode frame: {e}"),
+        }
+    }
+    tokio::time::sleep(DRAIN_DURATION).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use insanity_core::{
+        audio_source::SyncAudioSource,
+        generators::{Generator, Sine},
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        audio_device::VirtualBackend, clerver::FrameEncoder, dump::FrameDump,
+        processor::AUDIO_CHUNK_SIZE,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn dumps_replay_as_heard() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.opus");
+        let mut tone = Generator::new(Sine::new(440.0), 48000, 1).with_amplitude(0.5);
+        let mut encoder = FrameEncoder::new(1).unwrap();
+        {
+            let mut dump = FrameDump::create(&path).unwrap();
+            for _ in 0..50 {
+                let mut chunk = vec![0.0; AUDIO_CHUNK_SIZE];
+                tone.fill_sync(&mut chunk);
+                dump.write(&encoder.encode(chunk).unwrap()).unwrap();
+            }
+        }
+
+        let (heard_tx, mut heard) = mpsc::unbounded_channel();
+        let backend = VirtualBackend::new(|| Generator::new(Sine::new(440.0), 48000, 1))
+            .with_output_sink(heard_tx);
+        // Denoising takes out tones.
+        replay_on(&backend, &path, false, ResamplerQuality::default())
+            .await
+            .unwrap();
+
+        let mut loud_blocks = 0;
+        while let Ok(block) = heard.try_recv() {
+            if block.iter().any(|sample| sample.abs() > 0.1) {
+                loud_blocks += 1;
+            }
+        }
+        assert!(loud_blocks > 10, "Heard only {loud_blocks} loud blocks.");
+    }
+}
@@ -0,0 +1,261 @@
+//! Who may connect through each room. Peers are keyed by their veq public key, so
+//! knowing a room's name or secret isn't enough to get into a call.
+//!
+//! The lists are kept in a file of their own rather than the connection manager
+//! database, so `insanity room` works while a call is running.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+const ROOM_ACCESS_FILE: &str = "room_access.json";
+
+/// A room's access lists. Denied keys are always refused; once any key is allowed, only
+/// allowed keys get in.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RoomAccess {
+    pub allowed: BTreeSet<String>,
+    pub denied: BTreeSet<String>,
+}
+
+impl RoomAccess {
+    pub fn permits(&self, key: &str) -> bool {
+        !self.denied.contains(key) && (self.allowed.is_empty() || self.allowed.contains(key))
+    }
+
+    pub fn allow(&mut self, key: String) {
+        self.denied.remove(&key);
+        self.allowed.insert(key);
+    }
+
+    pub fn deny(&mut self, key: String) {
+        self.allowed.remove(&key);
+        self.denied.insert(key);
+    }
+
+    /// Takes the key off both lists.
+    pub fn forget(&mut self, key: &str) {
+        self.allowed.remove(key);
+        self.denied.remove(key);
+    }
+}
+
+/// When the file was last written and how long it was, to notice other processes'
+/// changes.
+type FileVersion = Option<(SystemTime, u64)>;
+
+struct CachedAccess {
+    version: FileVersion,
+    rooms: BTreeMap<String, RoomAccess>,
+}
+
+/// Access lists keyed by room name. They're kept in memory, since every peer found and
+/// every connection checks them, and read again whenever the file changes.
+#[derive(Clone)]
+pub struct RoomAccessStore {
+    path: PathBuf,
+    cache: Arc<RwLock<CachedAccess>>,
+}
+
+impl RoomAccessStore {
+    pub fn open(base_dir: &Path) -> anyhow::Result<RoomAccessStore> {
+        let path = base_dir.join(ROOM_ACCESS_FILE);
+        let version = file_version(&path);
+        let rooms = load(&path)?;
+        Ok(RoomAccessStore {
+            path,
+            cache: Arc::new(RwLock::new(CachedAccess { version, rooms })),
+        })
+    }
+
+    /// Reads the file again if it changed since we last did. Keeps the lists we have if
+    /// it can't be read.
+    fn refresh(&self) {
+        let version = file_version(&self.path);
+        if self.cache.read().unwrap().version == version {
+            return;
+        }
+        match load(&self.path) {
+            Ok(rooms) => *self.cache.write().unwrap() = CachedAccess { version, rooms },
+            Err(e) => log::error!("Failed to read changed room access lists: {:?}", e),
+        }
+    }
+
+    /// Returns the room's lists, or open access for a room without any.
+    pub fn load(&self, room: &str) -> RoomAccess {
+        self.refresh();
+        self.cache
+            .read()
+            .unwrap()
+            .rooms
+            .get(room)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether the peer with `key` may connect through `room`.
+    pub fn permits(&self, room: &str, key: &str) -> bool {
+        self.refresh();
+        self.cache
+            .read()
+            .unwrap()
+            .rooms
+            .get(room)
+            .is_none_or(|access| access.permits(key))
+    }
+
+    /// Changes the room's lists and returns them as saved. Starts from the file, so
+    /// changes made by other processes aren't lost.
+    pub fn update(
+        &self,
+        room: &str,
+        change: impl FnOnce(&mut RoomAccess),
+    ) -> anyhow::Result<RoomAccess> {
+        let mut cache = self.cache.write().unwrap();
+        let mut rooms = load(&self.path)?;
+        let access = rooms.entry(room.to_string()).or_default();
+        change(access);
+        let access = access.clone();
+        store(&self.path, &rooms)?;
+        *cache = CachedAccess {
+            version: file_version(&self.path),
+            rooms,
+        };
+        Ok(access)
+    }
+}
+
+fn file_version(path: &Path) -> FileVersion {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn load(path: &Path) -> anyhow::Result<BTreeMap<String, RoomAccess>> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the file whole, so a reader never sees it half written.
+fn store(path: &Path, rooms: &BTreeMap<String, RoomAccess>) -> anyhow::Result<()> {
+    let partial_path = path.with_extension("json.partial");
+    std::fs::write(&partial_path, serde_json::to_vec_pretty(rooms)?)?;
+    std::fs::rename(&partial_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(allowed: &[&str], denied: &[&str]) -> RoomAccess {
+        RoomAccess {
+            allowed: allowed.iter().map(|key| key.to_string()).collect(),
+            denied: denied.iter().map(|key| key.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn empty_lists_let_everyone_in() {
+        assert!(RoomAccess::default().permits("anyone"));
+    }
+
+    #[test]
+    fn allowlists_keep_everyone_else_out() {
+        let access = access(&["alice", "bob"], &[]);
+        assert!(access.permits("alice"));
+        assert!(access.permits("bob"));
+        assert!(!access.permits("mallory"));
+    }
+
+    #[test]
+    fn blocklists_keep_only_the_blocked_out() {
+        let access = access(&[], &["mallory"]);
+        assert!(access.permits("alice"));
+        assert!(!access.permits("mallory"));
+    }
+
+    #[test]
+    fn blocking_wins_over_allowing() {
+        let access = access(&["mallory"], &["mallory"]);
+        assert!(!access.permits("mallory"));
+    }
+
+    #[test]
+    fn allowing_and_denying_move_keys_between_lists() {
+        let mut access = RoomAccess::default();
+        access.deny("bob".to_string());
+        assert!(!access.permits("bob"));
+        access.allow("bob".to_string());
+        assert_eq!(access, self::access(&["bob"], &[]));
+        access.deny("bob".to_string());
+        assert_eq!(access, self::access(&[], &["bob"]));
+        access.forget("bob");
+        assert_eq!(access, RoomAccess::default());
+    }
+
+    #[test]
+    fn lists_are_saved_per_room_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RoomAccessStore::open(dir.path()).unwrap();
+        assert!(store.permits("team", "mallory"));
+
+        let saved = store
+            .update("team", |access| access.allow("alice".to_string()))
+            .unwrap();
+        assert_eq!(saved, access(&["alice"], &[]));
+        assert!(store.permits("team", "alice"));
+        assert!(!store.permits("team", "mallory"));
+        assert!(store.permits("lobby", "mallory"));
+
+        // Clones share the lists, as the peers' copies do.
+        let clone = store.clone();
+        store
+            .update("team", |access| access.deny("alice".to_string()))
+            .unwrap();
+        assert!(!clone.permits("team", "alice"));
+
+        let reopened = RoomAccessStore::open(dir.path()).unwrap();
+        assert_eq!(reopened.load("team"), access(&[], &["alice"]));
+        assert_eq!(reopened.load("lobby"), RoomAccess::default());
+    }
+
+    #[test]
+    fn changes_from_other_processes_are_seen() {
+        let dir = tempfile::tempdir().unwrap();
+        // The running client's store, and the one `insanity room` opens.
+        let client = RoomAccessStore::open(dir.path()).unwrap();
+        let cli = RoomAccessStore::open(dir.path()).unwrap();
+        assert!(client.permits("team", "mallory"));
+
+        cli.update("team", |access| access.deny("mallory".to_string()))
+            .unwrap();
+        assert!(!client.permits("team", "mallory"));
+
+        // Neither side's update drops the other's.
+        client
+            .update("lobby", |access| access.allow("alice".to_string()))
+            .unwrap();
+        assert_eq!(cli.load("team"), access(&[], &["mallory"]));
+        assert_eq!(cli.load("lobby"), access(&["alice"], &[]));
+    }
+
+    #[test]
+    fn unreadable_changes_keep_the_lists_we_have() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RoomAccessStore::open(dir.path()).unwrap();
+        store
+            .update("team", |access| access.deny("mallory".to_string()))
+            .unwrap();
+
+        std::fs::write(dir.path().join(ROOM_ACCESS_FILE), "not json").unwrap();
+        assert!(!store.permits("team", "mallory"));
+        assert!(store.update("team", |_| {}).is_err());
+    }
+}
@@ -1,9 +1,18 @@
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use tokio::sync::mpsc;
-use tokio_util::sync::CancellationToken;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::connection_manager::AugmentedInfo;
+use crate::{
+    bridges::{Bridge, Bridges},
+    connection_manager::{AugmentedInfo, DiscoveredPeer},
+    room_secrets::RoomCredentials,
+    transport::TransportInfo,
+};
 
 use baybridge::client::Actions;
 use baybridge::models::Value;
@@ -15,10 +24,15 @@ use chacha20poly1305::{
 use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 
 use argon2::Argon2;
+use veq::snow_types::SnowKeypair;
 
 const ENCRYPTION_KEY_SALT: [u8; 20] = *b"tubechipchillipepper";
 const FINGERPRINT_SALT: [u8; 16] = *b"fasteturtleplane";
-const SIGNING_SALT: [u8; 19] = *b"openbinderbikezebra";
+const MEMBER_KEY_CONTEXT: &str = "insanity 2024 room member signing key";
+/// How long bridges keep our entry.
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60 * 60 * 12);
+/// How long before our entry expires it's published again.
+const REPUBLISH_BEFORE_EXPIRY: Duration = Duration::from_secs(60 * 60);
 
 #[derive(serde::Serialize, serde::Deserialize)]
 struct EncryptedValue {
@@ -30,16 +44,70 @@ struct EncryptedValue {
 #[derive(serde::Serialize, serde::Deserialize)]
 struct SignedValue {
     msg: Vec<u8>,
+    /// The member's own key. Signs the message together with the baybridge identity it's
+    /// stored under, and must be the key the entry names.
+    signer: VerifyingKey,
     signature: Signature,
 }
 
-async fn action_set(
-    action: &Actions,
+/// What we publish about ourselves in each room.
+#[derive(Clone)]
+pub struct Presence<I> {
+    pub connection_info: I,
+    pub display_name: Option<String>,
+    pub signing_key: SigningKey,
+}
+
+/// Our key for signing room entries. Derived from the veq keypair, so it stays the same
+/// for as long as our identity does. Peers learn it from our entries and check it against
+/// the one we send over the connection, which only the holder of our identity can make.
+pub fn member_signing_key(keypair: &SnowKeypair) -> anyhow::Result<SigningKey> {
+    let keypair_bytes = bincode::serialize(keypair)?;
+    Ok(SigningKey::from_bytes(&blake3::derive_key(
+        MEMBER_KEY_CONTEXT,
+        &keypair_bytes,
+    )))
+}
+
+/// The key a peer signs its room entries with, as the peer itself told us over a
+/// connection to its identity. Entries for the peer signed by any other key were made by
+/// someone else.
+#[derive(Clone, Default)]
+pub struct ConfirmedMemberKey(Arc<Mutex<Option<VerifyingKey>>>);
+
+impl ConfirmedMemberKey {
+    pub fn confirm(&self, key: VerifyingKey) {
+        let mut confirmed = self.0.lock().unwrap();
+        if confirmed.is_some_and(|confirmed| confirmed != key) {
+            log::debug!("Peer changed its member key.");
+        }
+        *confirmed = Some(key);
+    }
+
+    /// Whether an entry naming `key` can be the peer's own. Until the peer confirms its
+    /// key any entry can be, and entries without a key come from us adding the peer.
+    pub fn admits(&self, key: Option<&VerifyingKey>) -> bool {
+        match (*self.0.lock().unwrap(), key) {
+            (Some(confirmed), Some(key)) => confirmed == *key,
+            _ => true,
+        }
+    }
+}
+
+/// Binds an entry to its owner's baybridge identity, so nobody can copy it into their own
+/// namespace and pass it off as theirs.
+fn bound_message(owner: &[u8], msg: &[u8]) -> Vec<u8> {
+    [owner, msg].concat()
+}
+
+/// Encrypts `value` and signs it, bound to the baybridge identity `owner` it's stored
+/// under.
+fn seal(
     cipher: &ChaCha20Poly1305,
     signing_key: &SigningKey,
-    key: String,
+    owner: &[u8],
     value: &[u8],
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Vec<u8>> {
     // Encrypt value
     let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
     let Ok(ciphertext) = cipher.encrypt(&nonce, value) else {
@@ -52,26 +120,38 @@ async fn action_set(
         nonce: nonce.to_vec(),
     })?;
 
-    // Sign (value, nonce)
+    // Sign (owner, value, nonce)
     // TODO: one day baybridge should have verified namespaces, so this code should be pushed into the baybridge side,
     // and verification should happen on the server or something
-    let signature = signing_key.sign(&encrypted_value);
+    let signature = signing_key.sign(&bound_message(owner, &encrypted_value));
     let signed_value = SignedValue {
         msg: encrypted_value,
+        signer: signing_key.verifying_key(),
         signature,
     };
+    Ok(bincode::serialize(&signed_value)?)
+}
+
+async fn action_set(
+    action: &Actions,
+    cipher: &ChaCha20Poly1305,
+    signing_key: &SigningKey,
+    key: String,
+    value: &[u8],
+) -> anyhow::Result<()> {
+    let owner = action.whoami().await;
+    let serialized_signed_value = seal(cipher, signing_key, owner.as_bytes(), value)?;
 
     // Set to key
-    let serialized_signed_value: Vec<u8> = bincode::serialize(&signed_value)?;
-    let expires_in_12_hours = std::time::SystemTime::now()
+    let expires_at = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)?
         .as_secs()
-        + 60 * 60 * 12;
+        + ENTRY_LIFETIME.as_secs();
     if let Err(e) = action
         .set()
         .name(key.into())
         .value(serialized_signed_value.into())
-        .expiry(baybridge::client::Expiry::ExpiresAt(expires_in_12_hours))
+        .expiry(baybridge::client::Expiry::ExpiresAt(expires_at))
         .call()
         .await
     {
@@ -81,40 +161,20 @@ async fn action_set(
     Ok(())
 }
 
-async fn set_own_info(
-    action: &Actions,
-    cipher: &ChaCha20Poly1305,
-    signing_key: &SigningKey,
-    room_fingerprint: String,
-    connection_info: veq::veq::ConnectionInfo,
-    display_name: String,
-) -> anyhow::Result<()> {
-    let info = AugmentedInfo {
-        connection_info,
-        display_name,
-    };
-    let serialized_info: &[u8] = &bincode::serialize(&info)?;
-
-    action_set(
-        action,
-        cipher,
-        signing_key,
-        room_fingerprint,
-        serialized_info,
-    )
-    .await
-}
-
-fn verify_and_decrypt(
+/// Returns the entry if it's signed by the key it names, for the owner it's stored under.
+fn verify_and_decrypt<I: TransportInfo>(
     cipher: &ChaCha20Poly1305,
-    verifying_key: &VerifyingKey,
-    info: Value,
-) -> anyhow::Result<AugmentedInfo> {
+    owner: &[u8],
+    info: &[u8],
+) -> anyhow::Result<AugmentedInfo<I>> {
     // Deserialize to SignedValue
-    let signed_value: SignedValue = bincode::deserialize(info.as_bytes())?;
+    let signed_value: SignedValue = bincode::deserialize(info)?;
 
     // Verify signature
-    verifying_key.verify_strict(&signed_value.msg, &signed_value.signature)?;
+    signed_value.signer.verify_strict(
+        &bound_message(owner, &signed_value.msg),
+        &signed_value.signature,
+    )?;
 
     // Deserialize to EncryptedValue
     let encrypted_value: EncryptedValue = bincode::deserialize(&signed_value.msg)?;
@@ -127,127 +187,363 @@ fn verify_and_decrypt(
         anyhow::bail!("Failed to deserialize encrypted value.");
     };
 
-    let info = bincode::deserialize(&serialized_info)?;
+    let info: AugmentedInfo<I> = bincode::deserialize(&serialized_info)?;
+    if info.member_key != Some(signed_value.signer) {
+        anyhow::bail!("Entry is signed by a key other than the one it names.");
+    }
     Ok(info)
 }
 
-/// Find peer connection info on the Bay Bridge room
-/// and send it over the conn_info_tx channel.
-pub async fn start_room_connection(
-    action: Actions,
-    room_name: &str,
-    connection_info: veq::veq::ConnectionInfo,
-    display_name: Option<String>,
-    conn_info_tx: mpsc::UnboundedSender<AugmentedInfo>,
-    app_event_tx: Option<mpsc::UnboundedSender<insanity_tui_adapter::AppEvent>>,
-    cancellation_token: CancellationToken,
-) -> anyhow::Result<()> {
-    let argon = Argon2::default();
-
-    // Set up room encryption cipher
-    let cipher = {
-        let mut encryption_key = [0u8; 32];
-        if let Err(e) = argon.hash_password_into(
-            room_name.as_bytes(),
-            &ENCRYPTION_KEY_SALT,
-            &mut encryption_key,
-        ) {
-            anyhow::bail!(e);
-        }
-        ChaCha20Poly1305::new(&encryption_key.into())
-    };
+/// Everything derived from a room's secret: members encrypt their entries with the same
+/// key, and find each other under the fingerprint.
+struct RoomKeys {
+    cipher: ChaCha20Poly1305,
+    fingerprint: String,
+}
+
+impl RoomKeys {
+    /// Derives everything from the room's secret, never its name, which others can guess.
+    fn derive(room_secret: &str) -> anyhow::Result<RoomKeys> {
+        let argon = Argon2::default();
+
+        // Set up room encryption cipher
+        let cipher = {
+            let mut encryption_key = [0u8; 32];
+            if let Err(e) = argon.hash_password_into(
+                room_secret.as_bytes(),
+                &ENCRYPTION_KEY_SALT,
+                &mut encryption_key,
+            ) {
+                anyhow::bail!(e);
+            }
+            ChaCha20Poly1305::new(&encryption_key.into())
+        };
 
-    // Make fingerprint (which will be used as key on baybridge) by hashing encryption_
-    let room_fingerprint = {
-        let mut fingerprint_material = [0u8; 32];
-        if let Err(e) = argon.hash_password_into(
-            room_name.as_bytes(),
-            &FINGERPRINT_SALT,
-            &mut fingerprint_material,
-        ) {
-            anyhow::bail!(e);
+        // Make fingerprint (which will be used as key on baybridge) by hashing encryption_
+        let fingerprint = {
+            let mut fingerprint_material = [0u8; 32];
+            if let Err(e) = argon.hash_password_into(
+                room_secret.as_bytes(),
+                &FINGERPRINT_SALT,
+                &mut fingerprint_material,
+            ) {
+                anyhow::bail!(e);
+            }
+            let fingerprint = blake3::hash(&fingerprint_material);
+            fingerprint.to_string()
+        };
+
+        Ok(RoomKeys {
+            cipher,
+            fingerprint,
+        })
+    }
+}
+
+/// What a room's tasks on each bridge share.
+struct RoomSync<I> {
+    room_name: String,
+    cipher: ChaCha20Poly1305,
+    fingerprint: String,
+    signing_key: SigningKey,
+    /// Our serialized entry, published to every bridge.
+    own_info: Vec<u8>,
+    conn_info_tx: mpsc::UnboundedSender<DiscoveredPeer<I>>,
+}
+
+impl<I: TransportInfo> RoomSync<I> {
+    fn forward_peer(&self, owner: &[u8], encrypted_info: Value) {
+        let Ok(info) = verify_and_decrypt::<I>(&self.cipher, owner, encrypted_info.as_bytes())
+        else {
+            log::debug!("Failed to parse contents of response into AugmentedInfo.");
+            return;
+        };
+        log::debug!("Got info in room {}: {:?}", self.room_name, info);
+        let discovered = DiscoveredPeer {
+            info,
+            room: Some(self.room_name.clone()),
+        };
+        if let Err(e) = self.conn_info_tx.send(discovered) {
+            log::debug!("Failed to send received connection info: {:?}", e);
         }
-        let fingerprint = blake3::hash(&fingerprint_material);
-        fingerprint.to_string()
-    };
+    }
+}
+
+/// Publishes our connection info to the room on every bridge server, and sends the peers
+/// found on any of them over the conn_info_tx channel.
+///
+/// Can be called once per room with the same `bridges`: each room gets its own presence
+/// entry, stored under the room's fingerprint. Fails before anything is sent if the room
+/// can't be joined. The returned tracker holds the sync tasks, which end once
+/// `cancellation_token` is cancelled.
+pub fn start_room_connection<I: TransportInfo>(
+    bridges: Arc<Bridges>,
+    room: &RoomCredentials,
+    presence: Presence<I>,
+    conn_info_tx: mpsc::UnboundedSender<DiscoveredPeer<I>>,
+    app_event_tx: Option<mpsc::UnboundedSender<insanity_tui_adapter::AppEvent>>,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<TaskTracker> {
+    let RoomKeys {
+        cipher,
+        fingerprint: room_fingerprint,
+    } = RoomKeys::derive(&room.secret)?;
     log::debug!("Room fingerprint: {room_fingerprint}");
     if let Some(app_event_tx) = app_event_tx.clone()
         && let Err(e) = app_event_tx.send(insanity_tui_adapter::AppEvent::SetRoomFingerprint(
+            room.name.clone(),
             room_fingerprint.clone(),
         )) {
             log::debug!("Failed to write room fingerprint to UI: {e}");
         }
 
-    let signing_key = {
-        let mut signing_key_material = [0u8; 32];
-        if let Err(e) = argon.hash_password_into(
-            room_name.as_bytes(),
-            &SIGNING_SALT,
-            &mut signing_key_material,
-        ) {
-            anyhow::bail!(e);
-        }
-        ed25519_dalek::SigningKey::from_bytes(&signing_key_material)
-    };
-
-    // Write self to server.
     // TODO: handle default name better
-    let display_name = display_name.clone().unwrap_or("missing_name".to_string());
-    set_own_info(
-        &action,
-        &cipher,
-        &signing_key,
-        room_fingerprint.clone(),
-        connection_info,
+    let display_name = presence.display_name.unwrap_or("missing_name".to_string());
+    let own_info = bincode::serialize(&AugmentedInfo {
+        connection_info: presence.connection_info,
         display_name,
-    )
-    .await?;
-
-    // Start background task to read connections to the room.
-    tokio::spawn(async move {
-        let verifying_key = signing_key.verifying_key();
-        tokio::select! {
-            e = retrieve_peers(action, &cipher, &verifying_key, &room_fingerprint, conn_info_tx) => {
-                log::error!("Retrieve peers loop failed: {:?}", e);
-            },
-            _ = cancellation_token.cancelled() => {
-                log::debug!("Baybridge-related tasks shutdown.");
+        member_key: Some(presence.signing_key.verifying_key()),
+    })?;
+    let room_sync = Arc::new(RoomSync {
+        room_name: room.name.clone(),
+        cipher,
+        fingerprint: room_fingerprint,
+        signing_key: presence.signing_key,
+        own_info,
+        conn_info_tx,
+    });
+
+    // A task per bridge, so one that's down doesn't hold up the others.
+    let tasks = TaskTracker::new();
+    for bridge in bridges.iter() {
+        let bridge = bridge.clone();
+        let room_sync = room_sync.clone();
+        let cancellation_token = cancellation_token.clone();
+        tasks.spawn(async move {
+            tokio::select! {
+                _ = sync_with_bridge(&bridge, &room_sync) => {},
+                _ = cancellation_token.cancelled() => {
+                    log::debug!(
+                        "Stopped syncing room {} with bridge {}.",
+                        room_sync.room_name,
+                        bridge.url
+                    );
+                }
             }
+        });
+    }
+    Ok(tasks)
+}
+
+/// Replaces our entry in the room with an empty one on every bridge, which peers skip
+/// since it holds no connection info. Entries left on bridges that are down expire on
+/// their own.
+pub async fn withdraw_from_room(
+    bridges: &Bridges,
+    room: &RoomCredentials,
+    signing_key: &SigningKey,
+) -> anyhow::Result<()> {
+    let RoomKeys {
+        cipher,
+        fingerprint,
+    } = RoomKeys::derive(&room.secret)?;
+    let mut withdrawn = false;
+    for bridge in bridges.iter() {
+        let withdrawal = action_set(
+            &bridge.actions,
+            &cipher,
+            signing_key,
+            fingerprint.clone(),
+            &[],
+        );
+        match bridge.call(withdrawal).await {
+            Ok(()) => withdrawn = true,
+            Err(e) => log::debug!("Failed to withdraw from bridge {}: {:?}", bridge.url, e),
         }
-    });
+    }
+    if !withdrawn {
+        anyhow::bail!("Failed to withdraw from every bridge server.");
+    }
     Ok(())
 }
 
-async fn retrieve_peers(
-    action: Actions,
-    cipher: &ChaCha20Poly1305,
-    verifying_key: &VerifyingKey,
-    room_fingerprint: &str,
-    conn_info_tx: mpsc::UnboundedSender<AugmentedInfo>,
-) -> anyhow::Result<()> {
+/// Whether to publish our entry (again): it was never published, the bridge may have
+/// lost it, or it's about to expire.
+fn needs_publishing(published_at: Option<Instant>, now: Instant) -> bool {
+    published_at.is_none_or(|published_at| {
+        now.saturating_duration_since(published_at) >= ENTRY_LIFETIME - REPUBLISH_BEFORE_EXPIRY
+    })
+}
+
+/// Publishes our entry to the bridge and keeps reading the room's entries from it. While
+/// the bridge is backing off it's left alone, and afterwards we publish again in case it
+/// lost our entry. We also publish again if our entry is missing from the room or about
+/// to expire.
+async fn sync_with_bridge<I: TransportInfo>(bridge: &Bridge, room: &RoomSync<I>) {
     let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(1000));
-    let me = action.whoami().await;
+    let me = bridge.actions.whoami().await;
+    let mut published_at = None;
     loop {
         interval.tick().await;
-        log::debug!("Interval tick on retrieve peers.");
-        let nsr = action.namespace(room_fingerprint).await?;
-        let mapping = nsr.mapping;
-        for (person, encrypted_info) in mapping {
-            if me == person {
+        if !bridge.ready() {
+            published_at = None;
+            continue;
+        }
+        if needs_publishing(published_at, Instant::now()) {
+            let publication = action_set(
+                &bridge.actions,
+                &room.cipher,
+                &room.signing_key,
+                room.fingerprint.clone(),
+                &room.own_info,
+            );
+            if let Err(e) = bridge.call(publication).await {
+                log::debug!(
+                    "Failed to publish to room {} on bridge {}: {:?}",
+                    room.room_name,
+                    bridge.url,
+                    e
+                );
                 continue;
             }
-            let Ok(info) = verify_and_decrypt(cipher, verifying_key, encrypted_info) else {
-                log::debug!("Failed to parse contents of response into AugmentedInfo.");
+            published_at = Some(Instant::now());
+        }
+
+        log::debug!("Interval tick on retrieve peers from {}.", bridge.url);
+        let entries = bridge
+            .call(async { Ok(bridge.actions.namespace(&room.fingerprint).await?) })
+            .await;
+        let nsr = match entries {
+            Ok(nsr) => nsr,
+            Err(e) => {
+                log::debug!(
+                    "Failed to read room {} from bridge {}: {:?}",
+                    room.room_name,
+                    bridge.url,
+                    e
+                );
+                published_at = None;
                 continue;
-            };
-            // let Ok(info) = serde_json::from_str::<AugmentedInfo>(&info) else {
-            //     log::debug!("Failed to parse contents of response into AugmentedInfo.");
-            //     continue;
-            // };
-            log::debug!("Got info: {:?}", info);
-            if let Err(e) = conn_info_tx.send(info) {
-                log::debug!("Failed to send received connection info: {:?}", e);
             }
+        };
+        if !nsr.mapping.iter().any(|(person, _)| *person == me) {
+            log::debug!(
+                "Our entry in room {} is missing from bridge {}.",
+                room.room_name,
+                bridge.url
+            );
+            published_at = None;
         }
+        for (person, encrypted_info) in nsr.mapping {
+            if me == person {
+                continue;
+            }
+            room.forward_peer(person.as_bytes(), encrypted_info);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryConnectionInfo;
+
+    fn cipher() -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&[7u8; 32].into())
+    }
+
+    fn member(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// The entry `name` publishes, naming `member_key` as the key it's signed with.
+    fn entry(name: &str, member_key: &SigningKey) -> Vec<u8> {
+        bincode::serialize(&AugmentedInfo {
+            connection_info: MemoryConnectionInfo {
+                name: name.to_string(),
+            },
+            display_name: name.to_string(),
+            member_key: Some(member_key.verifying_key()),
+        })
+        .unwrap()
+    }
+
+    fn open(owner: &[u8], sealed: &[u8]) -> anyhow::Result<AugmentedInfo<MemoryConnectionInfo>> {
+        verify_and_decrypt(&cipher(), owner, sealed)
+    }
+
+    #[test]
+    fn genuine_entries_are_read() {
+        let alice = member(1);
+        let sealed = seal(&cipher(), &alice, b"alice", &entry("alice", &alice)).unwrap();
+        let info = open(b"alice", &sealed).unwrap();
+        assert_eq!(info.display_name, "alice");
+        assert_eq!(info.member_key, Some(alice.verifying_key()));
+    }
+
+    #[test]
+    fn entries_signed_by_a_key_they_dont_name_are_rejected() {
+        let (alice, mallory) = (member(1), member(2));
+        let forged = seal(&cipher(), &mallory, b"mallory", &entry("alice", &alice)).unwrap();
+        assert!(open(b"mallory", &forged).is_err());
+    }
+
+    #[test]
+    fn entries_copied_to_another_owner_are_rejected() {
+        let alice = member(1);
+        let sealed = seal(&cipher(), &alice, b"alice", &entry("alice", &alice)).unwrap();
+        assert!(open(b"mallory", &sealed).is_err());
+    }
+
+    #[test]
+    fn entries_for_other_rooms_are_rejected() {
+        let alice = member(1);
+        let other_room = ChaCha20Poly1305::new(&[8u8; 32].into());
+        let sealed = seal(&other_room, &alice, b"alice", &entry("alice", &alice)).unwrap();
+        assert!(open(b"alice", &sealed).is_err());
+    }
+
+    #[test]
+    fn hijacks_are_ignored_once_the_peer_vouches_for_its_key() {
+        let (alice, mallory) = (member(1), member(2));
+        // Mallory publishes Alice's identity under Mallory's own key. The entry holds
+        // together, so only Alice can tell it apart from hers.
+        let hijack = seal(&cipher(), &mallory, b"mallory", &entry("alice", &mallory)).unwrap();
+        let hijack = open(b"mallory", &hijack).unwrap();
+        let genuine = seal(&cipher(), &alice, b"alice", &entry("alice", &alice)).unwrap();
+        let genuine = open(b"alice", &genuine).unwrap();
+
+        // Seeing Mallory's entry first doesn't shut Alice out.
+        let confirmed = ConfirmedMemberKey::default();
+        assert!(confirmed.admits(hijack.member_key.as_ref()));
+        assert!(confirmed.admits(genuine.member_key.as_ref()));
+
+        // Alice sends her key over the connection to her identity.
+        confirmed.confirm(alice.verifying_key());
+        assert!(!confirmed.admits(hijack.member_key.as_ref()));
+        assert!(confirmed.admits(genuine.member_key.as_ref()));
+        // Peers added directly aren't from any entry.
+        assert!(confirmed.admits(None));
+    }
+
+    #[test]
+    fn entries_are_published_until_they_stick() {
+        assert!(needs_publishing(None, Instant::now()));
+    }
+
+    #[test]
+    fn entries_are_published_again_before_they_expire() {
+        let published_at = Instant::now();
+        let republish_at = published_at + ENTRY_LIFETIME - REPUBLISH_BEFORE_EXPIRY;
+        assert!(!needs_publishing(Some(published_at), published_at));
+        assert!(!needs_publishing(
+            Some(published_at),
+            republish_at - Duration::from_secs(1)
+        ));
+        assert!(needs_publishing(Some(published_at), republish_at));
+        assert!(needs_publishing(
+            Some(published_at),
+            published_at + ENTRY_LIFETIME
+        ));
     }
 }
@@ -0,0 +1,130 @@
+//! Room secrets. A room's keys are derived from its secret rather than its name, so the
+//! name can be short and readable without being guessable.
+//!
+//! Secrets are kept in a file of their own rather than the connection manager database,
+//! so `insanity invite` works while a call is running.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+const ROOM_SECRETS_FILE: &str = "room_secrets.json";
+const SECRET_BYTES: usize = 32;
+
+/// What a room is called and what its keys are derived from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomCredentials {
+    pub name: String,
+    pub secret: String,
+}
+
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    BASE64_URL_SAFE_NO_PAD.encode(secret)
+}
+
+/// Secrets of the rooms we made or were invited to, keyed by room name.
+#[derive(Clone)]
+pub struct RoomSecretStore {
+    path: PathBuf,
+}
+
+impl RoomSecretStore {
+    pub fn open(base_dir: &Path) -> RoomSecretStore {
+        RoomSecretStore {
+            path: base_dir.join(ROOM_SECRETS_FILE),
+        }
+    }
+
+    fn load(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        match std::fs::read(&self.path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the file whole, so a reader never sees it half written.
+    fn store(&self, secrets: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        let partial_path = self.path.with_extension("json.partial");
+        std::fs::write(&partial_path, serde_json::to_vec_pretty(secrets)?)?;
+        std::fs::rename(&partial_path, &self.path)?;
+        Ok(())
+    }
+
+    /// The room's credentials, or `None` if we have no secret for it.
+    pub fn credentials(&self, name: &str) -> anyhow::Result<Option<RoomCredentials>> {
+        Ok(self.load()?.remove(name).map(|secret| RoomCredentials {
+            name: name.to_string(),
+            secret,
+        }))
+    }
+
+    /// Makes a secret for a room that has none. Fails if it has one, since replacing it
+    /// would leave everyone invited with the old one behind.
+    pub fn create(&self, name: &str) -> anyhow::Result<RoomCredentials> {
+        let mut secrets = self.load()?;
+        if secrets.contains_key(name) {
+            anyhow::bail!("Room {name} already has a secret.");
+        }
+        let credentials = RoomCredentials {
+            name: name.to_string(),
+            secret: generate_secret(),
+        };
+        secrets.insert(credentials.name.clone(), credentials.secret.clone());
+        self.store(&secrets)?;
+        Ok(credentials)
+    }
+
+    /// Remembers the secret, replacing any other room's of the same name.
+    pub fn save(&self, credentials: &RoomCredentials) -> anyhow::Result<()> {
+        let mut secrets = self.load()?;
+        let previous = secrets.insert(credentials.name.clone(), credentials.secret.clone());
+        if previous.is_some_and(|previous| previous != credentials.secret) {
+            log::warn!(
+                "Replaced the secret of room {} with the one from its invite.",
+                credentials.name
+            );
+        }
+        self.store(&secrets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_created_once_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RoomSecretStore::open(dir.path());
+        assert_eq!(store.credentials("lobby").unwrap(), None);
+
+        let created = store.create("lobby").unwrap();
+        assert_eq!(created.secret.len(), 43);
+        assert!(store.create("lobby").is_err());
+
+        // Another handle, as `insanity invite` has while a call runs.
+        let reopened = RoomSecretStore::open(dir.path());
+        assert_eq!(reopened.credentials("lobby").unwrap(), Some(created));
+        assert_eq!(reopened.credentials("other").unwrap(), None);
+    }
+
+    #[test]
+    fn invites_replace_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RoomSecretStore::open(dir.path());
+        store.create("lobby").unwrap();
+        let invited = RoomCredentials {
+            name: "lobby".to_string(),
+            secret: "from an invite".to_string(),
+        };
+        store.save(&invited).unwrap();
+        assert_eq!(store.credentials("lobby").unwrap(), Some(invited));
+    }
+}
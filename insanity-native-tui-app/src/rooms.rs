@@ -0,0 +1,202 @@
+//! The rooms we're in. Each has its own presence entry and peer search, which come and
+//! go as rooms are joined and left; the socket and the peers we're connected to stay.
+
+use std::sync::Arc;
+
+use ed25519_dalek::VerifyingKey;
+use insanity_tui_adapter::AppEvent;
+use tokio::sync::mpsc;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::{
+    bridges::Bridges,
+    connection_manager::DiscoveredPeer,
+    room_access::{RoomAccess, RoomAccessStore},
+    room_handler::{self, Presence},
+    room_secrets::{RoomCredentials, RoomSecretStore},
+    transport::TransportInfo,
+};
+
+/// A room we're in, with the token that stops its peer search and the tasks doing it.
+struct JoinedRoom {
+    credentials: RoomCredentials,
+    token: CancellationToken,
+    tasks: TaskTracker,
+}
+
+pub struct JoinedRooms<I: TransportInfo> {
+    /// Without a bridge server there's nowhere to find rooms.
+    bridges: Option<Arc<Bridges>>,
+    presence: Presence<I>,
+    app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
+    cancellation_token: CancellationToken,
+    secrets: RoomSecretStore,
+    access: RoomAccessStore,
+    rooms: Vec<JoinedRoom>,
+    talk_room: Option<String>,
+}
+
+impl<I: TransportInfo> JoinedRooms<I> {
+    pub fn new(
+        bridges: Option<Arc<Bridges>>,
+        presence: Presence<I>,
+        app_event_tx: Option<mpsc::UnboundedSender<AppEvent>>,
+        cancellation_token: CancellationToken,
+        secrets: RoomSecretStore,
+        access: RoomAccessStore,
+    ) -> JoinedRooms<I> {
+        JoinedRooms {
+            bridges,
+            presence,
+            app_event_tx,
+            cancellation_token,
+            secrets,
+            access,
+            rooms: vec![],
+            talk_room: None,
+        }
+    }
+
+    pub fn contains(&self, room: &str) -> bool {
+        self.rooms
+            .iter()
+            .any(|joined| joined.credentials.name == room)
+    }
+
+    /// Whether the peer with `key` may connect through `room`.
+    pub fn permits(&self, room: &str, key: &str) -> bool {
+        self.access.permits(room, key)
+    }
+
+    /// The key our room entries are signed with.
+    pub fn member_key(&self) -> VerifyingKey {
+        self.presence.signing_key.verifying_key()
+    }
+
+    /// The access lists, for checking peers as they connect.
+    pub fn access(&self) -> &RoomAccessStore {
+        &self.access
+    }
+
+    pub fn update_access(
+        &self,
+        room: &str,
+        change: impl FnOnce(&mut RoomAccess),
+    ) -> anyhow::Result<RoomAccess> {
+        self.access.update(room, change)
+    }
+
+    /// The room our microphone goes to, if we're in any.
+    pub fn talk_room(&self) -> Option<&str> {
+        self.talk_room.as_deref()
+    }
+
+    /// Announces us in `room` and starts sending the peers found there over
+    /// `conn_info_tx`. The first room joined becomes the talk room.
+    pub fn join(
+        &mut self,
+        room: String,
+        conn_info_tx: &mpsc::UnboundedSender<DiscoveredPeer<I>>,
+    ) -> anyhow::Result<()> {
+        let Some(bridges) = self.bridges.clone() else {
+            anyhow::bail!("Can't join room {room} without a bridge server.");
+        };
+        if self.contains(&room) {
+            anyhow::bail!("Already in room {room}.");
+        }
+        log::debug!("Joining room {room}.");
+
+        // A room keyed by its name alone could be found by anyone who guesses it.
+        let Some(credentials) = self.secrets.credentials(&room)? else {
+            anyhow::bail!(
+                "No secret for room {room}. Join it with an invite link, or start it with \
+                 `insanity invite {room} --new`."
+            );
+        };
+        let token = self.cancellation_token.child_token();
+        // Shown first so the room's fingerprint has somewhere to go.
+        self.send_app_event(AppEvent::AddRoom(room.clone()));
+        let tasks = match room_handler::start_room_connection(
+            bridges,
+            &credentials,
+            self.presence.clone(),
+            conn_info_tx.clone(),
+            self.app_event_tx.clone(),
+            token.clone(),
+        ) {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                self.send_app_event(AppEvent::RemoveRoom(room.clone()));
+                return Err(e.context(format!("Failed to join room {room}.")));
+            }
+        };
+        self.rooms.push(JoinedRoom {
+            credentials,
+            token,
+            tasks,
+        });
+        if self.talk_room.is_none() {
+            self.set_talk_room(room)?;
+        }
+        Ok(())
+    }
+
+    /// Stops looking for peers in `room` and withdraws our entry from it. If it was the
+    /// talk room, the earliest joined of the rest takes over.
+    pub fn leave(&mut self, room: &str) -> anyhow::Result<()> {
+        let Some(index) = self
+            .rooms
+            .iter()
+            .position(|joined| joined.credentials.name == room)
+        else {
+            anyhow::bail!("Not in room {room}.");
+        };
+        log::debug!("Leaving room {room}.");
+        let JoinedRoom {
+            credentials,
+            token,
+            tasks,
+        } = self.rooms.remove(index);
+        token.cancel();
+        tasks.close();
+        self.send_app_event(AppEvent::RemoveRoom(credentials.name.clone()));
+
+        if self.talk_room.as_ref() == Some(&credentials.name) {
+            self.talk_room = None;
+            if let Some(next) = self.rooms.first() {
+                self.set_talk_room(next.credentials.name.clone())?;
+            }
+        }
+
+        if let Some(bridges) = self.bridges.clone() {
+            let signing_key = self.presence.signing_key.clone();
+            tokio::spawn(async move {
+                // A publish still under way would otherwise land after the withdrawal.
+                tasks.wait().await;
+                if let Err(e) =
+                    room_handler::withdraw_from_room(&bridges, &credentials, &signing_key).await
+                {
+                    log::debug!("Failed to withdraw from room {}: {:?}", credentials.name, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub fn set_talk_room(&mut self, room: String) -> anyhow::Result<()> {
+        if !self.contains(&room) {
+            anyhow::bail!("Can't talk to room {room} without joining it.");
+        }
+        self.talk_room = Some(room.clone());
+        self.send_app_event(AppEvent::TalkRoom(room));
+        Ok(())
+    }
+
+    fn send_app_event(&self, event: AppEvent) {
+        if let Some(app_event_tx) = &self.app_event_tx
+            && let Err(e) = app_event_tx.send(event)
+        {
+            log::debug!("Failed to send room event: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Safety numbers, for two people to check out of band that each is talking to the other
+//! and not to someone in between. Both sides compute the same number from the two keys.
+
+const SAFETY_NUMBER_CONTEXT: &str = "insanity 2024 safety number";
+const GROUPS: usize = 6;
+const GROUP_DIGITS: u32 = 5;
+
+/// Thirty digits in groups of five, the same whichever of the two keys is ours.
+pub fn safety_number(own_identity: &str, peer_identity: &str) -> String {
+    let (first, second) = if own_identity <= peer_identity {
+        (own_identity, peer_identity)
+    } else {
+        (peer_identity, own_identity)
+    };
+    let mut hasher = blake3::Hasher::new_derive_key(SAFETY_NUMBER_CONTEXT);
+    // Lengths first, so the boundary between the keys can't be moved.
+    hasher.update(&(first.len() as u64).to_le_bytes());
+    hasher.update(first.as_bytes());
+    hasher.update(&(second.len() as u64).to_le_bytes());
+    hasher.update(second.as_bytes());
+    let hash = hasher.finalize();
+
+    hash.as_bytes()
+        .chunks_exact(5)
+        .take(GROUPS)
+        .map(|chunk| {
+            let value = chunk
+                .iter()
+                .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+            format!(
+                "{:0width$}",
+                value % 10u64.pow(GROUP_DIGITS),
+                width = GROUP_DIGITS as usize
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_compute_the_same_number() {
+        assert_eq!(
+            safety_number("alice key", "bob key"),
+            safety_number("bob key", "alice key")
+        );
+    }
+
+    #[test]
+    fn numbers_are_thirty_digits_in_groups_of_five() {
+        let number = safety_number("alice key", "bob key");
+        let groups: Vec<&str> = number.split(' ').collect();
+        assert_eq!(groups.len(), 6);
+        assert!(groups
+            .iter()
+            .all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit())));
+    }
+
+    #[test]
+    fn different_keys_give_different_numbers() {
+        let number = safety_number("alice key", "bob key");
+        assert_ne!(number, safety_number("alice key", "mallory key"));
+        assert_ne!(number, safety_number("mallory key", "bob key"));
+        // Moving the boundary between the keys changes the number too.
+        assert_ne!(number, safety_number("alice keyb", "ob key"));
+    }
+}
@@ -0,0 +1,249 @@
+//! A fixed-size queue of samples between one producer and one consumer. Neither side
+//! ever allocates or waits on the other, so either can run inside an audio callback,
+//! while the other side sleeps until there's something for it to do.
+
+use std::sync::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+
+struct Shared {
+    /// Samples stored as their bits, so both sides can touch them without locks.
+    slots: Box<[AtomicU32]>,
+    /// Samples ever written and read, wrapping. Their difference is how many are queued.
+    written: AtomicUsize,
+    read: AtomicUsize,
+    /// Raised after samples are written and read. Raising one only touches an atomic,
+    /// unless the other side is asleep on it.
+    readable: Notify,
+    writable: Notify,
+}
+
+impl Shared {
+    fn slot(&self, position: usize) -> &AtomicU32 {
+        // The slot count is a power of two, so this stays right when the counters wrap.
+        &self.slots[position & (self.slots.len() - 1)]
+    }
+}
+
+/// Makes a ring holding at least `capacity` samples.
+pub fn sample_ring(capacity: usize) -> (SampleProducer, SampleConsumer) {
+    let slots = (0..capacity.max(1).next_power_of_two())
+        .map(|_| AtomicU32::new(0))
+        .collect();
+    let shared = Arc::new(Shared {
+        slots,
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (
+        SampleProducer {
+            shared: shared.clone(),
+        },
+        SampleConsumer { shared },
+    )
+}
+
+pub struct SampleProducer {
+    shared: Arc<Shared>,
+}
+
+impl SampleProducer {
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    /// How many samples are waiting to be read.
+    pub fn queued(&self) -> usize {
+        let written = self.shared.written.load(Ordering::Relaxed);
+        written.wrapping_sub(self.shared.read.load(Ordering::Acquire))
+    }
+
+    /// Queues as many of `samples` as fit and returns how many did.
+    pub fn push_slice(&mut self, samples: &[f32]) -> usize {
+        let written = self.shared.written.load(Ordering::Relaxed);
+        let free = self.capacity() - self.queued();
+        let count = std::cmp::min(free, samples.len());
+        for (i, sample) in samples[..count].iter().enumerate() {
+            self.shared
+                .slot(written.wrapping_add(i))
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.shared
+            .written
+            .store(written.wrapping_add(count), Ordering::Release);
+        if count > 0 {
+            self.shared.readable.notify_one();
+        }
+        count
+    }
+
+    /// Waits until samples have been read since the last wait, so there may be room.
+    pub async fn writable(&self) {
+        self.shared.writable.notified().await;
+    }
+}
+
+pub struct SampleConsumer {
+    shared: Arc<Shared>,
+}
+
+impl SampleConsumer {
+    /// Takes as many queued samples as fit in `buffer` and returns how many there were.
+    pub fn pop_slice(&mut self, buffer: &mut [f32]) -> usize {
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let queued = self
+            .shared
+            .written
+            .load(Ordering::Acquire)
+            .wrapping_sub(read);
+        let count = std::cmp::min(queued, buffer.len());
+        for (i, sample) in buffer[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(
+                self.shared
+                    .slot(read.wrapping_add(i))
+                    .load(Ordering::Relaxed),
+            );
+        }
+        self.shared
+            .read
+            .store(read.wrapping_add(count), Ordering::Release);
+        if count > 0 {
+            self.shared.writable.notify_one();
+        }
+        count
+    }
+
+    /// Waits until samples have been written since the last wait, so some may be queued.
+    pub async fn readable(&self) {
+        self.shared.readable.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity_is_rounded_up_to_a_power_of_two() {
+        let (producer, _consumer) = sample_ring(6);
+        assert_eq!(producer.capacity(), 8);
+        let (producer, _consumer) = sample_ring(0);
+        assert_eq!(producer.capacity(), 1);
+    }
+
+    #[test]
+    fn full_ring_takes_no_more_and_empty_ring_gives_nothing() {
+        let (mut producer, mut consumer) = sample_ring(4);
+        let mut buffer = [0.0; 8];
+        assert_eq!(consumer.pop_slice(&mut buffer), 0);
+
+        assert_eq!(producer.push_slice(&[1.0, 2.0, 3.0, 4.0, 5.0]), 4);
+        assert_eq!(producer.queued(), 4);
+        assert_eq!(producer.push_slice(&[6.0]), 0);
+
+        assert_eq!(consumer.pop_slice(&mut buffer), 4);
+        assert_eq!(buffer[..4], [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(consumer.pop_slice(&mut buffer), 0);
+        assert_eq!(producer.queued(), 0);
+    }
+
+    #[test]
+    fn samples_keep_their_order_across_the_wraparound() {
+        let (mut producer, mut consumer) = sample_ring(8);
+        let mut next_in = 0.0;
+        let mut next_out = 0.0;
+        let mut buffer = [0.0; 5];
+        // Odd sizes so reads and writes straddle the end of the slots.
+        for _ in 0..100 {
+            let samples = [next_in, next_in + 1.0, next_in + 2.0];
+            next_in += producer.push_slice(&samples) as f32;
+            let popped = consumer.pop_slice(&mut buffer);
+            for sample in &buffer[..popped] {
+                assert_eq!(*sample, next_out);
+                next_out += 1.0;
+            }
+        }
+        assert!(next_out > 200.0);
+    }
+
+    #[test]
+    fn counters_wrap_around_usize() {
+        let (mut producer, mut consumer) = sample_ring(4);
+        producer
+            .shared
+            .written
+            .store(usize::MAX - 1, Ordering::Relaxed);
+        producer
+            .shared
+            .read
+            .store(usize::MAX - 1, Ordering::Relaxed);
+
+        assert_eq!(producer.push_slice(&[1.0, 2.0, 3.0]), 3);
+        assert_eq!(producer.queued(), 3);
+        let mut buffer = [0.0; 4];
+        assert_eq!(consumer.pop_slice(&mut buffer), 3);
+        assert_eq!(buffer[..3], [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn concurrent_producer_and_consumer_see_every_sample_in_order() {
+        const SAMPLES: u32 = 200_000;
+        let (mut producer, mut consumer) = sample_ring(64);
+        let writer = std::thread::spawn(move || {
+            let mut next = 0;
+            while next < SAMPLES {
+                let samples: Vec<f32> = (next..(next + 7).min(SAMPLES)).map(|i| i as f32).collect();
+                let pushed = producer.push_slice(&samples);
+                if pushed == 0 {
+                    std::thread::yield_now();
+                }
+                next += pushed as u32;
+            }
+        });
+
+        let mut expected = 0;
+        let mut buffer = [0.0; 13];
+        while expected < SAMPLES {
+            let popped = consumer.pop_slice(&mut buffer);
+            if popped == 0 {
+                std::thread::yield_now();
+            }
+            for sample in &buffer[..popped] {
+                assert_eq!(*sample, expected as f32);
+                expected += 1;
+            }
+        }
+        writer.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn writing_wakes_the_consumer() {
+        let (mut producer, mut consumer) = sample_ring(4);
+        let reader = tokio::spawn(async move {
+            consumer.readable().await;
+            let mut buffer = [0.0; 4];
+            consumer.pop_slice(&mut buffer)
+        });
+        tokio::task::yield_now().await;
+        producer.push_slice(&[1.0, 2.0]);
+        assert_eq!(reader.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn reading_wakes_the_producer() {
+        let (mut producer, mut consumer) = sample_ring(2);
+        producer.push_slice(&[1.0, 2.0]);
+        let writer = tokio::spawn(async move {
+            producer.writable().await;
+            producer.push_slice(&[3.0])
+        });
+        tokio::task::yield_now().await;
+        consumer.pop_slice(&mut [0.0]);
+        assert_eq!(writer.await.unwrap(), 1);
+    }
+}
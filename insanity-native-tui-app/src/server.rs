@@ -4,23 +4,32 @@ use std::sync::{Arc, Mutex};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{BufferSize, Device, Sample, SampleFormat, SampleRate, Stream, StreamConfig};
 use insanity_core::audio_source::{AudioSource, SyncAudioSource};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
-use crate::processor::{AudioChunk, AUDIO_CHANNELS};
+use crate::audio_device::{capture_queue, AudioInput};
+use crate::processor::AudioChunk;
 use crate::realtime_buffer::RealTimeBuffer;
+use crate::sample_ring::SampleProducer;
+
+/// Samples converted at a time in the input callback, on the stack so it never allocates.
+const CONVERT_BLOCK: usize = 256;
 
 fn run_input<T: Sample>(
     config: &cpal::StreamConfig,
     device: &Device,
-    sender: UnboundedSender<f32>,
+    mut producer: SampleProducer,
 ) -> Stream {
     let err_fn = |err| eprintln!("an error occurred in the input audio stream: {err}");
     device
         .build_input_stream(
             config,
             move |data: &[T], _: &cpal::InputCallbackInfo| {
-                for sample in data.iter() {
-                    if let Ok(()) = sender.send(sample.to_f32()) {}
+                let mut converted = [0f32; CONVERT_BLOCK];
+                for block in data.chunks(CONVERT_BLOCK) {
+                    for (slot, sample) in converted.iter_mut().zip(block) {
+                        *slot = sample.to_f32();
+                    }
+                    // Dropped if nobody has read for a while.
+                    producer.push_slice(&converted[..block.len()]);
                 }
             },
             err_fn,
@@ -32,18 +41,22 @@ fn setup_input_stream(
     sample_format: &SampleFormat,
     config: &cpal::StreamConfig,
     device: &Device,
-    sender: UnboundedSender<f32>,
+    producer: SampleProducer,
 ) -> Stream {
     match sample_format {
-        SampleFormat::F32 => run_input::<f32>(config, device, sender),
-        SampleFormat::I16 => run_input::<i16>(config, device, sender),
-        SampleFormat::U16 => run_input::<u16>(config, device, sender),
+        SampleFormat::F32 => run_input::<f32>(config, device, producer),
+        SampleFormat::I16 => run_input::<i16>(config, device, producer),
+        SampleFormat::U16 => run_input::<u16>(config, device, producer),
     }
 }
 
 fn get_input_config(device: &Device) -> (SampleFormat, cpal::StreamConfig) {
     let supported_configs_range = device.supported_input_configs().unwrap();
-    let supported_config_range = find_stereo_input(supported_configs_range).unwrap();
+    let default_channels = device
+        .default_input_config()
+        .ok()
+        .map(|config| config.channels());
+    let supported_config_range = find_input(supported_configs_range, default_channels).unwrap();
     let max_sample_rate = supported_config_range.max_sample_rate();
 
     let channels = supported_config_range.channels();
@@ -64,12 +77,15 @@ fn get_input_config(device: &Device) -> (SampleFormat, cpal::StreamConfig) {
     (sample_format, supported_config)
 }
 
-fn find_stereo_input(
+/// Prefers a config with the device's default channel count. Any count works, since the
+/// sender remixes to what a stream can carry.
+fn find_input(
     range: cpal::SupportedInputConfigs,
+    channels: Option<u16>,
 ) -> Option<cpal::SupportedStreamConfigRange> {
     let mut something = None;
     for item in range {
-        if item.channels() == AUDIO_CHANNELS {
+        if Some(item.channels()) == channels {
             return Some(item);
         } else {
             something = Some(item);
@@ -78,56 +94,39 @@ fn find_stereo_input(
     something
 }
 
-pub struct CpalStreamReceiver {
-    #[allow(dead_code)]
-    input_stream: send_safe::SendWrapperThread<Stream>,
-    input_receiver: UnboundedReceiver<f32>,
-    sample_rate: u32,
-    channels: u16,
-}
-
-impl AudioSource for CpalStreamReceiver {
-    async fn next(&mut self) -> Option<f32> {
-        self.input_receiver.recv().await
-    }
-    fn sample_rate(&self) -> u32 {
-        self.sample_rate
-    }
-    fn channels(&self) -> u16 {
-        self.channels
-    }
-}
-
 // impl AudioReceiver for Receiver<f32> {
 //     fn receiver(&mut self) -> &mut Receiver<f32> {
 //         self
 //     }
 // }
 
-pub fn make_audio_receiver() -> CpalStreamReceiver {
+pub fn make_audio_receiver() -> anyhow::Result<AudioInput> {
     let host = cpal::default_host();
-    let (input_sender, input_receiver) = unbounded_channel();
-    let input_device = host
-        .default_input_device()
-        .expect("No default input device");
-    // If input_stream is dropped, then the input_receiver stops receiving data.
-    // CpalStreamReceiver keeps input_stream alive along with input_receiver.
+    let Some(input_device) = host.default_input_device() else {
+        anyhow::bail!("No default input device.");
+    };
+    // If input_stream is dropped, then the consumer stops receiving data.
+    // AudioInput keeps input_stream alive along with the consumer.
     let (sample_format, config) = get_input_config(&input_device);
+    let (producer, consumer) = capture_queue(config.sample_rate.0, config.channels);
     let config_clone = config.clone();
     let mut wrapper = send_safe::SendWrapperThread::new(move || {
-        setup_input_stream(&sample_format, &config_clone, &input_device, input_sender)
+        setup_input_stream(&sample_format, &config_clone, &input_device, producer)
+    });
+    let started = wrapper.execute(|input_stream| {
+        if let Err(e) = input_stream.play() {
+            log::error!("Failed to play input stream: {:?}", e);
+        }
     });
-    wrapper
-        .execute(|input_stream| {
-            input_stream.play().unwrap();
-        })
-        .unwrap();
-    CpalStreamReceiver {
-        input_receiver,
-        input_stream: wrapper,
-        sample_rate: config.sample_rate.0,
-        channels: config.channels,
+    if started.is_err() {
+        anyhow::bail!("Failed to start input stream.");
     }
+    Ok(AudioInput::new(
+        consumer,
+        config.sample_rate.0,
+        config.channels,
+        Box::new(wrapper),
+    ))
 }
 
 // fn make_music_receiver(path: String) -> Receiver<f32> {
@@ -138,7 +137,7 @@ pub fn make_audio_receiver() -> CpalStreamReceiver {
 //         // println!("Music: {:?}", header);
 //         if let Sixteen(vec) = data {
 //             let mut now = SystemTime::now();
-//             for chunk in vec.chunks_exact(AUDIO_CHUNK_SIZE * (AUDIO_CHANNELS as usize)) {
+//             for chunk in vec.chunks_exact(AUDIO_CHUNK_SIZE * (DEFAULT_CHANNELS as usize)) {
 //                 for val in chunk {
 //                     let s: i16 = Sample::from(val);
 //                     if input_sender.send(s.to_f32()).is_ok() {}
@@ -209,4 +208,26 @@ impl SyncAudioSource for RealtimeAudioSource {
         }
         self.sample_buffer.pop_front()
     }
+
+    fn fill_sync(&mut self, buffer: &mut [f32]) -> usize {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            if self.sample_buffer.is_empty() {
+                let mut chunk_buffer = self.chunk_buffer.lock().unwrap();
+                match chunk_buffer.next_item() {
+                    Some(chunk) => self.sample_buffer.extend(chunk.audio_data),
+                    None => break,
+                }
+            }
+            let count = std::cmp::min(buffer.len() - filled, self.sample_buffer.len());
+            for (slot, sample) in buffer[filled..]
+                .iter_mut()
+                .zip(self.sample_buffer.drain(..count))
+            {
+                *slot = sample;
+            }
+            filled += count;
+        }
+        filled
+    }
 }
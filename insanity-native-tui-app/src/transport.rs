@@ -0,0 +1,540 @@
+//! How peers reach each other. Calls normally go over veq, but the connection manager,
+//! managed peers and clervers only depend on the traits here, so they can also run over
+//! an in-process [`MemoryNetwork`] with simulated network conditions.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use veq::veq::{ConnectionInfo, VeqSessionAlias, VeqSocket};
+
+/// What a peer publishes so that others can connect to it.
+pub trait TransportInfo:
+    Clone + Debug + PartialEq + Eq + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    /// Stable identity of the endpoint, used to key peers and their settings.
+    fn identity(&self) -> String;
+
+    /// Addresses to show while connecting.
+    fn addresses(&self) -> Vec<String>;
+}
+
+/// An established connection to one peer. Clones share the connection.
+pub trait TransportSession: Clone + Send + Sync + 'static {
+    fn send(&mut self, packet: Vec<u8>) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    fn recv(&mut self) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
+
+    fn remote_addr(&self) -> impl Future<Output = String> + Send;
+}
+
+/// A local endpoint that peers connect to and that connects to peers.
+pub trait Transport: Clone + Send + Sync + 'static {
+    type Info: TransportInfo;
+    type Session: TransportSession;
+
+    fn connection_info(&self) -> Self::Info;
+
+    /// Connects to the peer described by `info`. Both sides connect with the same `id`.
+    /// The session only comes up with the holder of `info`'s identity; over veq that's
+    /// the remote static key of the Noise handshake.
+    fn connect(
+        &mut self,
+        id: uuid::Uuid,
+        info: Self::Info,
+    ) -> impl Future<Output = anyhow::Result<Self::Session>> + Send;
+}
+
+impl TransportInfo for ConnectionInfo {
+    fn identity(&self) -> String {
+        self.public_key.clone().base64().to_string()
+    }
+
+    fn addresses(&self) -> Vec<String> {
+        self.addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect()
+    }
+}
+
+impl TransportSession for VeqSessionAlias {
+    async fn send(&mut self, packet: Vec<u8>) -> anyhow::Result<()> {
+        if VeqSessionAlias::send(self, packet).await.is_err() {
+            anyhow::bail!("Failed to send packet.");
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> anyhow::Result<Vec<u8>> {
+        match VeqSessionAlias::recv(self).await {
+            Ok(packet) => Ok(packet),
+            Err(_) => anyhow::bail!("Failed to receive packet."),
+        }
+    }
+
+    async fn remote_addr(&self) -> String {
+        VeqSessionAlias::remote_addr(self).await.to_string()
+    }
+}
+
+impl Transport for VeqSocket {
+    type Info = ConnectionInfo;
+    type Session = VeqSessionAlias;
+
+    fn connection_info(&self) -> ConnectionInfo {
+        VeqSocket::connection_info(self)
+    }
+
+    async fn connect(
+        &mut self,
+        id: uuid::Uuid,
+        info: ConnectionInfo,
+    ) -> anyhow::Result<VeqSessionAlias> {
+        match VeqSocket::connect(self, id, info).await {
+            Ok(session) => Ok(session),
+            Err(_) => anyhow::bail!("Failed to connect to {id}."),
+        }
+    }
+}
+
+/// Network conditions applied to every packet sent over a [`MemoryNetwork`].
+#[derive(Clone, Debug)]
+pub struct LinkConditions {
+    /// Delay added to every packet.
+    pub latency: Duration,
+    /// Up to this much extra delay, chosen at random per packet.
+    pub jitter: Duration,
+    /// Probability that a packet is dropped.
+    pub loss: f64,
+    /// Probability that a packet is held back by `reorder_delay`.
+    pub reordering: f64,
+    pub reorder_delay: Duration,
+    /// Probability that a packet is delivered twice.
+    pub duplication: f64,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        LinkConditions {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            reordering: 0.0,
+            reorder_delay: Duration::from_millis(20),
+            duplication: 0.0,
+        }
+    }
+}
+
+/// A connection attempt waiting for the other side to connect too.
+struct PendingConnect {
+    from: String,
+    to: String,
+    session_tx: oneshot::Sender<MemorySession>,
+}
+
+struct NetworkState {
+    conditions: LinkConditions,
+    /// xorshift64 state, so runs with the same seed make the same decisions.
+    rng: u64,
+    pending: HashMap<uuid::Uuid, PendingConnect>,
+}
+
+impl NetworkState {
+    fn next_f64(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    /// Decides when each copy of a packet arrives. No copies means it was lost.
+    fn plan_delivery(&mut self) -> Vec<Duration> {
+        let conditions = self.conditions.clone();
+        if self.chance(conditions.loss) {
+            return Vec::new();
+        }
+        let copies = if self.chance(conditions.duplication) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut delay = conditions.latency + conditions.jitter.mul_f64(self.next_f64());
+                if self.chance(conditions.reordering) {
+                    delay += conditions.reorder_delay;
+                }
+                delay
+            })
+            .collect()
+    }
+}
+
+/// An in-process network. Endpoints made from the same network can connect to each other.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl MemoryNetwork {
+    pub fn new(seed: u64, conditions: LinkConditions) -> MemoryNetwork {
+        MemoryNetwork {
+            state: Arc::new(Mutex::new(NetworkState {
+                conditions,
+                // xorshift never leaves zero.
+                rng: seed.max(1),
+                pending: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Changes the conditions for packets sent from now on.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.state.lock().unwrap().conditions = conditions;
+    }
+
+    pub fn endpoint(&self, name: &str) -> MemoryEndpoint {
+        MemoryEndpoint {
+            name: name.to_string(),
+            network: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryConnectionInfo {
+    pub name: String,
+}
+
+impl TransportInfo for MemoryConnectionInfo {
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
+    fn addresses(&self) -> Vec<String> {
+        vec![format!("memory:{}", self.name)]
+    }
+}
+
+/// One side of a connection over a [`MemoryNetwork`].
+#[derive(Clone)]
+pub struct MemorySession {
+    remote: String,
+    network: MemoryNetwork,
+    outbox: mpsc::UnboundedSender<Vec<u8>>,
+    inbox: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>>,
+}
+
+impl MemorySession {
+    /// Makes both sides of a new connection.
+    fn pair(network: &MemoryNetwork, a: &str, b: &str) -> (MemorySession, MemorySession) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        let a_session = MemorySession {
+            remote: b.to_string(),
+            network: network.clone(),
+            outbox: b_tx,
+            inbox: Arc::new(tokio::sync::Mutex::new(a_rx)),
+        };
+        let b_session = MemorySession {
+            remote: a.to_string(),
+            network: network.clone(),
+            outbox: a_tx,
+            inbox: Arc::new(tokio::sync::Mutex::new(b_rx)),
+        };
+        (a_session, b_session)
+    }
+}
+
+impl TransportSession for MemorySession {
+    async fn send(&mut self, packet: Vec<u8>) -> anyhow::Result<()> {
+        if self.outbox.is_closed() {
+            anyhow::bail!("Connection to {} closed.", self.remote);
+        }
+        let deliveries = self.network.state.lock().unwrap().plan_delivery();
+        for delay in deliveries {
+            let outbox = self.outbox.clone();
+            let packet = packet.clone();
+            if delay.is_zero() {
+                let _ = outbox.send(packet);
+            } else {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = outbox.send(packet);
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> anyhow::Result<Vec<u8>> {
+        match self.inbox.lock().await.recv().await {
+            Some(packet) => Ok(packet),
+            None => anyhow::bail!("Connection to {} closed.", self.remote),
+        }
+    }
+
+    async fn remote_addr(&self) -> String {
+        format!("memory:{}", self.remote)
+    }
+}
+
+/// An endpoint on a [`MemoryNetwork`], named like a peer.
+#[derive(Clone)]
+pub struct MemoryEndpoint {
+    name: String,
+    network: MemoryNetwork,
+}
+
+impl Transport for MemoryEndpoint {
+    type Info = MemoryConnectionInfo;
+    type Session = MemorySession;
+
+    fn connection_info(&self) -> MemoryConnectionInfo {
+        MemoryConnectionInfo {
+            name: self.name.clone(),
+        }
+    }
+
+    async fn connect(
+        &mut self,
+        id: uuid::Uuid,
+        info: MemoryConnectionInfo,
+    ) -> anyhow::Result<MemorySession> {
+        let session_rx = {
+            let mut state = self.network.state.lock().unwrap();
+            match state.pending.remove(&id) {
+                // The other side is already waiting for us.
+                Some(pending) if pending.from == info.name && pending.to == self.name => {
+                    let (ours, theirs) = MemorySession::pair(&self.network, &self.name, &info.name);
+                    if pending.session_tx.send(theirs).is_ok() {
+                        return Ok(ours);
+                    }
+                    log::debug!("{} stopped waiting for connection {id}.", info.name);
+                }
+                // Replaces our own earlier attempt, which then fails.
+                Some(pending) if pending.from == self.name && pending.to == info.name => {
+                    log::debug!("Replacing earlier attempt at connection {id}.");
+                }
+                // Another pair's attempt, which is left waiting.
+                Some(pending) => {
+                    let (from, to) = (pending.from.clone(), pending.to.clone());
+                    state.pending.insert(id, pending);
+                    anyhow::bail!("Connection {id} is already being made from {from} to {to}.");
+                }
+                None => {}
+            }
+            let (session_tx, session_rx) = oneshot::channel();
+            state.pending.insert(
+                id,
+                PendingConnect {
+                    from: self.name.clone(),
+                    to: info.name.clone(),
+                    session_tx,
+                },
+            );
+            session_rx
+        };
+        match session_rx.await {
+            Ok(session) => Ok(session),
+            Err(_) => anyhow::bail!("Connection attempt to {} was replaced.", info.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    const PACKETS: usize = 10_000;
+
+    fn plans(conditions: LinkConditions) -> Vec<Vec<Duration>> {
+        let network = MemoryNetwork::new(7, conditions);
+        let mut state = network.state.lock().unwrap();
+        (0..PACKETS).map(|_| state.plan_delivery()).collect()
+    }
+
+    fn share(plans: &[Vec<Duration>], pick: impl Fn(&Vec<Duration>) -> bool) -> f64 {
+        plans.iter().filter(|plan| pick(plan)).count() as f64 / plans.len() as f64
+    }
+
+    #[test]
+    fn same_seed_same_decisions() {
+        let conditions = LinkConditions {
+            jitter: Duration::from_millis(10),
+            loss: 0.5,
+            ..LinkConditions::default()
+        };
+        assert_eq!(plans(conditions.clone()), plans(conditions));
+    }
+
+    #[test]
+    fn default_conditions_deliver_everything_once_at_once() {
+        assert!(plans(LinkConditions::default())
+            .iter()
+            .all(|plan| *plan == [Duration::ZERO]));
+    }
+
+    #[test]
+    fn lost_packets_have_no_copies() {
+        let plans = plans(LinkConditions {
+            loss: 0.3,
+            ..LinkConditions::default()
+        });
+        let lost = share(&plans, |plan| plan.is_empty());
+        assert!((0.27..0.33).contains(&lost), "{lost}");
+        assert!(plans.iter().all(|plan| plan.len() <= 1));
+    }
+
+    #[test]
+    fn duplicated_packets_have_two_copies() {
+        let plans = plans(LinkConditions {
+            duplication: 0.2,
+            ..LinkConditions::default()
+        });
+        let duplicated = share(&plans, |plan| plan.len() == 2);
+        assert!((0.17..0.23).contains(&duplicated), "{duplicated}");
+        assert!(plans.iter().all(|plan| !plan.is_empty()));
+    }
+
+    #[test]
+    fn latency_jitter_and_reordering_add_delay() {
+        let latency = Duration::from_millis(30);
+        let jitter = Duration::from_millis(10);
+        let reorder_delay = Duration::from_millis(50);
+        let plans = plans(LinkConditions {
+            latency,
+            jitter,
+            reordering: 0.1,
+            reorder_delay,
+            ..LinkConditions::default()
+        });
+        let delays: Vec<Duration> = plans.into_iter().flatten().collect();
+        assert_eq!(delays.len(), PACKETS);
+        let held_back = delays
+            .iter()
+            .filter(|delay| **delay >= latency + reorder_delay)
+            .count() as f64
+            / PACKETS as f64;
+        assert!((0.08..0.12).contains(&held_back), "{held_back}");
+        for delay in delays {
+            let delay = if delay >= latency + reorder_delay {
+                delay - reorder_delay
+            } else {
+                delay
+            };
+            assert!(delay >= latency && delay < latency + jitter, "{delay:?}");
+        }
+    }
+
+    async fn connected(network: &MemoryNetwork) -> (MemorySession, MemorySession) {
+        let id = uuid::Uuid::new_v4();
+        let (mut a, mut b) = (network.endpoint("a"), network.endpoint("b"));
+        let (a_info, b_info) = (a.connection_info(), b.connection_info());
+        let (a_session, b_session) = tokio::join!(a.connect(id, b_info), b.connect(id, a_info));
+        (a_session.unwrap(), b_session.unwrap())
+    }
+
+    /// Sends numbered packets one millisecond apart and returns them in the order they
+    /// arrived, with when they did.
+    async fn exchange(conditions: LinkConditions, packets: u8) -> Vec<(u8, Duration)> {
+        let network = MemoryNetwork::new(3, conditions);
+        let (mut sender, mut receiver) = connected(&network).await;
+        let start = Instant::now();
+        for packet in 0..packets {
+            sender.send(vec![packet]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let mut received = vec![];
+        while let Ok(Ok(packet)) =
+            tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await
+        {
+            received.push((packet[0], start.elapsed()));
+        }
+        received
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lossless_links_deliver_in_order() {
+        let received = exchange(LinkConditions::default(), 100).await;
+        let packets: Vec<u8> = received.iter().map(|(packet, _)| *packet).collect();
+        assert_eq!(packets, (0..100).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_delays_every_packet() {
+        let latency = Duration::from_millis(40);
+        let received = exchange(
+            LinkConditions {
+                latency,
+                ..LinkConditions::default()
+            },
+            100,
+        )
+        .await;
+        assert_eq!(received.len(), 100);
+        for (packet, arrived) in received {
+            // Sent `packet` milliseconds in.
+            let sent = Duration::from_millis(packet as u64);
+            assert!(arrived >= sent + latency, "{packet} arrived at {arrived:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lossy_links_drop_duplicate_and_reorder() {
+        let received = exchange(
+            LinkConditions {
+                loss: 0.2,
+                duplication: 0.2,
+                reordering: 0.2,
+                reorder_delay: Duration::from_millis(20),
+                ..LinkConditions::default()
+            },
+            200,
+        )
+        .await;
+        let packets: Vec<u8> = received.iter().map(|(packet, _)| *packet).collect();
+        let distinct: BTreeSet<u8> = packets.iter().copied().collect();
+        assert!(distinct.len() < 200, "Nothing was dropped.");
+        assert!(packets.len() > distinct.len(), "Nothing was duplicated.");
+        assert!(
+            packets.windows(2).any(|pair| pair[1] < pair[0]),
+            "Nothing arrived out of order."
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_between_other_endpoints_are_left_alone() {
+        let network = MemoryNetwork::new(1, LinkConditions::default());
+        let id = uuid::Uuid::new_v4();
+        let (mut a, mut b, mut c) = (
+            network.endpoint("a"),
+            network.endpoint("b"),
+            network.endpoint("c"),
+        );
+        let (a_info, b_info) = (a.connection_info(), b.connection_info());
+        let waiting = tokio::spawn(async move { a.connect(id, b_info).await });
+        tokio::task::yield_now().await;
+
+        assert!(c.connect(id, a_info.clone()).await.is_err());
+        // a is still waiting for b.
+        b.connect(id, a_info).await.unwrap();
+        waiting.await.unwrap().unwrap();
+    }
+}
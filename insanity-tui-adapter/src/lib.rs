@@ -3,6 +3,8 @@ use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use insanity_core::equalizer::EqualizerPreset;
+use insanity_core::pan::{Pan, MAX_PAN};
 use insanity_core::user_input_event::UserInputEvent;
 use std::collections::BTreeMap;
 use std::{error::Error, io, io::Stdout};
@@ -34,6 +36,22 @@ pub const MOVE_UP_PEER_LIST_KEY: char = 'k';
 pub const MOVE_TOP_PEER_LIST_KEY: char = 'g';
 pub const MOVE_BOTTOM_PEER_LIST_KEY: char = 'G';
 pub const MUTE_KEY: char = 'm';
+pub const PAN_LEFT_KEY: char = '<';
+pub const PAN_RIGHT_KEY: char = '>';
+pub const AUTO_PAN_KEY: char = 'a';
+pub const CYCLE_EQUALIZER_KEY: char = 'e';
+pub const MUTE_PEER_KEY: char = 'x';
+pub const DEAFEN_KEY: char = 'D';
+pub const RECORD_KEY: char = 'r';
+pub const MIC_TEST_KEY: char = 't';
+pub const CYCLE_TALK_ROOM_KEY: char = 'c';
+pub const ALLOW_PEER_KEY: char = 'A';
+pub const DENY_PEER_KEY: char = 'B';
+pub const VERIFY_PEER_KEY: char = 'v';
+pub const JOIN_ROOM_KEY: char = 'n';
+pub const LEAVE_ROOM_KEY: char = 'l';
+
+const PAN_STEP: isize = 10;
 
 const NUM_TABS: usize = 3;
 const TAB_NAMES: [&str; NUM_TABS] = [TAB_NAME_PEERS, TAB_NAME_CHAT, TAB_NAME_SETTINGS];
@@ -46,6 +64,29 @@ pub enum PeerState {
     Connecting(String),
 }
 
+/// Whether a peer is who its name says.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PeerTrust {
+    /// Trusted on first use: the name hasn't been seen with another key.
+    #[default]
+    Unverified,
+    /// Its safety number was compared out of band.
+    Verified,
+    /// The name was first seen with another key, so this may not be the same person.
+    KeyChanged,
+}
+
+/// Whether a bridge server is answering.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BridgeStatus {
+    /// Nothing has been sent to it yet.
+    #[default]
+    Connecting,
+    Up,
+    /// Its last request failed with this error, and it's being retried with backoff.
+    Down(String),
+}
+
 #[derive(Debug, Clone)]
 pub struct Peer {
     id: String,
@@ -53,7 +94,18 @@ pub struct Peer {
     state: PeerState,
     denoised: bool,
     volume: usize,
+    pan: Pan,
+    equalizer: EqualizerPreset,
+    muted: bool,
+    recording: bool,
     loudness: f64,
+    /// Rooms we've found this peer in. Empty if we connected to it directly.
+    rooms: Vec<String>,
+    /// Public key rooms' access lists know the peer by.
+    key: Option<String>,
+    trust: PeerTrust,
+    /// Derived from both our keys, to compare with the peer out of band.
+    safety_number: Option<String>,
 }
 
 impl Peer {
@@ -70,7 +122,15 @@ impl Peer {
             state,
             denoised,
             volume,
+            pan: Pan::default(),
+            equalizer: EqualizerPreset::default(),
+            muted: false,
+            recording: false,
             loudness: 0.0,
+            rooms: vec![],
+            key: None,
+            trust: PeerTrust::default(),
+            safety_number: None,
         }
     }
 
@@ -85,6 +145,53 @@ impl Peer {
     pub fn with_volume(self, volume: usize) -> Peer {
         Peer { volume, ..self }
     }
+
+    pub fn with_pan(self, pan: Pan) -> Peer {
+        Peer { pan, ..self }
+    }
+
+    pub fn with_equalizer(self, equalizer: EqualizerPreset) -> Peer {
+        Peer { equalizer, ..self }
+    }
+
+    pub fn with_muted(self, muted: bool) -> Peer {
+        Peer { muted, ..self }
+    }
+
+    pub fn with_rooms(self, rooms: Vec<String>) -> Peer {
+        Peer { rooms, ..self }
+    }
+
+    pub fn with_key(self, key: String) -> Peer {
+        Peer {
+            key: Some(key),
+            ..self
+        }
+    }
+
+    pub fn with_trust(self, trust: PeerTrust) -> Peer {
+        Peer { trust, ..self }
+    }
+
+    pub fn with_safety_number(self, safety_number: String) -> Peer {
+        Peer {
+            safety_number: Some(safety_number),
+            ..self
+        }
+    }
+
+    /// Whether the peer can't hear us, because it isn't in the room we talk to.
+    fn is_listen_only(&self, talk_room: Option<&str>) -> bool {
+        talk_room.is_some_and(|talk_room| {
+            !self.rooms.is_empty() && !self.rooms.iter().any(|room| room == talk_room)
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Room {
+    pub name: String,
+    pub fingerprint: Option<String>,
 }
 
 #[derive(Debug)]
@@ -109,16 +216,32 @@ pub enum AppEvent {
     SetOwnPublicKey(String),
     SetOwnDisplayName(String),
     SetServer(Vec<String>),
-    SetRoom(String),
-    SetRoomFingerprint(String),
+    SetBridgeStatus(String, BridgeStatus),
+    AddRoom(String),
+    RemoveRoom(String),
+    /// A room's name and its fingerprint.
+    SetRoomFingerprint(String, String),
+    /// The room our microphone goes to.
+    TalkRoom(String),
     Down,
     Up,
     TogglePeer,
     ToggleDenoise,
     SetPeerDenoise(String, bool),
     SetPeerVolume(String, usize),
+    SetPeerPan(String, Pan),
+    SetPeerEqualizer(String, EqualizerPreset),
+    SetPeerMuted(String, bool),
+    SetPeerRecording(String, bool),
+    SetPeerRooms(String, Vec<String>),
+    SetPeerTrust(String, PeerTrust),
     MuteSelf(bool),
+    Deafen(bool),
+    Recording(bool),
     Loudness(String, f64),
+    MicTest(bool),
+    /// Loudness of our own microphone while the mic test runs.
+    MicTestLevel(f64),
 }
 
 pub struct App {
@@ -130,14 +253,22 @@ pub struct App {
     pub own_public_key: Option<String>,
     pub own_display_name: Option<String>,
     pub servers: Vec<String>,
-    pub room: Option<String>,
-    pub room_fingerprint: Option<String>,
+    pub bridge_statuses: BTreeMap<String, BridgeStatus>,
+    /// Rooms joined, in the order they were joined.
+    pub rooms: Vec<Room>,
+    pub talk_room: Option<String>,
     pub editor: Editor,
+    /// Name of a room to join, while it's being typed on the settings tab.
+    pub room_editor: Option<Editor>,
     pub peer_index: usize,
     pub chat_history: Vec<(String, String)>, // (Display Name, Message)
     pub unread_messages: bool,
     pub chat_offset: usize, // Offset from bottom of chat in full messages.
     pub mute_self: bool,
+    pub deafened: bool,
+    pub recording: bool,
+    pub mic_test: bool,
+    pub mic_test_level: f64,
 }
 
 impl App {
@@ -151,14 +282,20 @@ impl App {
             own_public_key: None,
             own_display_name: None,
             servers: vec![],
-            room: None,
-            room_fingerprint: None,
+            bridge_statuses: BTreeMap::new(),
+            rooms: vec![],
+            talk_room: None,
             editor: Editor::new(),
+            room_editor: None,
             peer_index: 0,
             chat_history: vec![],
             unread_messages: false,
             chat_offset: 0,
             mute_self: false,
+            deafened: false,
+            recording: false,
+            mic_test: false,
+            mic_test_level: 0.0,
         }
     }
 
@@ -209,18 +346,75 @@ impl App {
                     MUTE_KEY => {
                         self.toggle_mute_self();
                     }
+                    PAN_LEFT_KEY => {
+                        self.adjust_pan(-PAN_STEP);
+                    }
+                    PAN_RIGHT_KEY => {
+                        self.adjust_pan(PAN_STEP);
+                    }
+                    AUTO_PAN_KEY => {
+                        self.reset_pan();
+                    }
+                    CYCLE_EQUALIZER_KEY => {
+                        self.cycle_equalizer();
+                    }
+                    MUTE_PEER_KEY => {
+                        self.toggle_mute_peer();
+                    }
+                    DEAFEN_KEY => {
+                        self.toggle_deafen();
+                    }
+                    RECORD_KEY => {
+                        self.toggle_recording();
+                    }
+                    CYCLE_TALK_ROOM_KEY => {
+                        self.cycle_talk_room();
+                    }
+                    ALLOW_PEER_KEY => {
+                        self.set_peer_access(true);
+                    }
+                    DENY_PEER_KEY => {
+                        self.set_peer_access(false);
+                    }
+                    VERIFY_PEER_KEY => {
+                        self.toggle_verified();
+                    }
                     _ => {}
                 },
                 TAB_IDX_CHAT => {
                     self.editor.append(c);
                 }
+                TAB_IDX_SETTINGS => match &mut self.room_editor {
+                    Some(room_editor) => {
+                        room_editor.append(c);
+                    }
+                    None => match c {
+                        MIC_TEST_KEY => {
+                            self.toggle_mic_test();
+                        }
+                        JOIN_ROOM_KEY => {
+                            self.room_editor = Some(Editor::new());
+                        }
+                        LEAVE_ROOM_KEY => {
+                            self.leave_talk_room();
+                        }
+                        CYCLE_TALK_ROOM_KEY => {
+                            self.cycle_talk_room();
+                        }
+                        _ => {}
+                    },
+                },
                 _ => {}
             },
-            AppEvent::Enter => {
-                if self.tab_index == TAB_IDX_CHAT {
+            AppEvent::Enter => match self.tab_index {
+                TAB_IDX_CHAT => {
                     self.send_message();
                 }
-            }
+                TAB_IDX_SETTINGS => {
+                    self.join_room();
+                }
+                _ => {}
+            },
             AppEvent::NewMessage(sender_name, message) => {
                 self.add_message((sender_name, message));
                 if self.tab_index != TAB_IDX_CHAT || self.chat_offset > 0 {
@@ -228,28 +422,28 @@ impl App {
                 }
             }
             AppEvent::Backspace => {
-                self.editor.backspace();
+                self.focused_editor().backspace();
             }
             AppEvent::Left => {
-                self.editor.left();
+                self.focused_editor().left();
             }
             AppEvent::Right => {
-                self.editor.right();
+                self.focused_editor().right();
             }
             AppEvent::CursorBeginning => {
-                self.editor.cursor_beginning();
+                self.focused_editor().cursor_beginning();
             }
             AppEvent::CursorEnd => {
-                self.editor.cursor_end();
+                self.focused_editor().cursor_end();
             }
             AppEvent::PreviousWord => {
-                self.editor.previous_word();
+                self.focused_editor().previous_word();
             }
             AppEvent::NextWord => {
-                self.editor.next_word();
+                self.focused_editor().next_word();
             }
             AppEvent::DeleteWord => {
-                self.editor.delete_word();
+                self.focused_editor().delete_word();
             }
             AppEvent::SetOwnPublicKey(address) => {
                 self.own_public_key = Some(address);
@@ -260,11 +454,30 @@ impl App {
             AppEvent::SetServer(server) => {
                 self.servers = server;
             }
-            AppEvent::SetRoom(room) => {
-                self.room = Some(room);
+            AppEvent::SetBridgeStatus(server, status) => {
+                self.bridge_statuses.insert(server, status);
             }
-            AppEvent::SetRoomFingerprint(room_fingerprint) => {
-                self.room_fingerprint = Some(room_fingerprint);
+            AppEvent::AddRoom(name) => {
+                if !self.rooms.iter().any(|room| room.name == name) {
+                    self.rooms.push(Room {
+                        name,
+                        fingerprint: None,
+                    });
+                }
+            }
+            AppEvent::RemoveRoom(name) => {
+                self.rooms.retain(|room| room.name != name);
+                if self.talk_room.as_ref() == Some(&name) {
+                    self.talk_room = None;
+                }
+            }
+            AppEvent::SetRoomFingerprint(name, fingerprint) => {
+                if let Some(room) = self.rooms.iter_mut().find(|room| room.name == name) {
+                    room.fingerprint = Some(fingerprint);
+                }
+            }
+            AppEvent::TalkRoom(name) => {
+                self.talk_room = Some(name);
             }
             AppEvent::Down => match self.tab_index {
                 TAB_IDX_PEERS => {
@@ -306,14 +519,57 @@ impl App {
                     peer.volume = volume;
                 }
             }
+            AppEvent::SetPeerPan(peer_id, pan) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.pan = pan;
+                }
+            }
+            AppEvent::SetPeerEqualizer(peer_id, equalizer) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.equalizer = equalizer;
+                }
+            }
+            AppEvent::SetPeerMuted(peer_id, muted) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.muted = muted;
+                }
+            }
+            AppEvent::SetPeerTrust(peer_id, trust) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.trust = trust;
+                }
+            }
+            AppEvent::SetPeerRecording(peer_id, recording) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.recording = recording;
+                }
+            }
+            AppEvent::SetPeerRooms(peer_id, rooms) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.rooms = rooms;
+                }
+            }
             AppEvent::MuteSelf(is_muted) => {
                 self.mute_self = is_muted;
             }
+            AppEvent::Deafen(is_deafened) => {
+                self.deafened = is_deafened;
+            }
+            AppEvent::Recording(is_recording) => {
+                self.recording = is_recording;
+            }
             AppEvent::Loudness(peer_id, loudness) => {
                 if let Some(peer) = self.peers.get_mut(&peer_id) {
                     peer.loudness = loudness;
                 }
             }
+            AppEvent::MicTest(is_testing) => {
+                self.mic_test = is_testing;
+                self.mic_test_level = 0.0;
+            }
+            AppEvent::MicTestLevel(level) => {
+                self.mic_test_level = level;
+            }
         }
     }
 
@@ -385,6 +641,64 @@ impl App {
         }
     }
 
+    fn adjust_pan(&mut self, delta: isize) {
+        if let Some(peer) = self.selected_peer() {
+            let pan = (peer.pan.position() + delta).clamp(-MAX_PAN, MAX_PAN);
+            self.user_action_sender
+                .send(UserInputEvent::SetPan(peer.id.clone(), Some(pan)))
+                .unwrap();
+        }
+    }
+
+    fn reset_pan(&mut self) {
+        if let Some(peer) = self.selected_peer() {
+            self.user_action_sender
+                .send(UserInputEvent::SetPan(peer.id.clone(), None))
+                .unwrap();
+        }
+    }
+
+    fn cycle_equalizer(&mut self) {
+        if let Some(peer) = self.selected_peer() {
+            self.user_action_sender
+                .send(UserInputEvent::SetEqualizer(
+                    peer.id.clone(),
+                    peer.equalizer.next(),
+                ))
+                .unwrap();
+        }
+    }
+
+    fn toggle_mute_peer(&mut self) {
+        if let Some(peer) = self.selected_peer() {
+            self.user_action_sender
+                .send(UserInputEvent::SetMutePeer(peer.id.clone(), !peer.muted))
+                .unwrap();
+        }
+    }
+
+    // Only updated once the connection manager has remembered it.
+    fn toggle_verified(&mut self) {
+        if let Some(peer) = self.selected_peer() {
+            let verified = peer.trust != PeerTrust::Verified;
+            self.user_action_sender
+                .send(UserInputEvent::SetPeerVerified(peer.id.clone(), verified))
+                .unwrap();
+        }
+    }
+
+    fn set_peer_access(&mut self, allow: bool) {
+        if let Some(peer) = self.selected_peer() {
+            let id = peer.id.clone();
+            let action = if allow {
+                UserInputEvent::AllowPeer(id)
+            } else {
+                UserInputEvent::DenyPeer(id)
+            };
+            self.user_action_sender.send(action).unwrap();
+        }
+    }
+
     fn move_tabs(&mut self, adjustment: isize) {
         let num_tabs = self.tabs.len();
         self.tab_index = (self.tab_index + adjustment.rem_euclid(num_tabs as isize) as usize)
@@ -401,6 +715,73 @@ impl App {
             .unwrap();
     }
 
+    // Only updated once the connection manager confirms, as with recording.
+    fn toggle_deafen(&mut self) {
+        self.user_action_sender
+            .send(UserInputEvent::SetDeafen(!self.deafened))
+            .unwrap();
+    }
+
+    // Only updated once the recorder confirms, since starting it can fail.
+    fn toggle_recording(&mut self) {
+        self.user_action_sender
+            .send(UserInputEvent::SetRecording(!self.recording))
+            .unwrap();
+    }
+
+    // Only updated once the connection manager switches, so the indicator can't disagree
+    // with where the microphone actually goes.
+    fn cycle_talk_room(&mut self) {
+        let Some(current) = self
+            .rooms
+            .iter()
+            .position(|room| Some(&room.name) == self.talk_room.as_ref())
+        else {
+            return;
+        };
+        if self.rooms.len() > 1 {
+            let next = &self.rooms[(current + 1) % self.rooms.len()];
+            self.user_action_sender
+                .send(UserInputEvent::SetTalkRoom(next.name.clone()))
+                .unwrap();
+        }
+    }
+
+    fn join_room(&mut self) {
+        if let Some(mut room_editor) = self.room_editor.take() {
+            let room = room_editor.clear();
+            let room = room.trim();
+            if !room.is_empty() {
+                self.user_action_sender
+                    .send(UserInputEvent::JoinRoom(room.to_string()))
+                    .unwrap();
+            }
+        }
+    }
+
+    fn leave_talk_room(&mut self) {
+        if let Some(talk_room) = &self.talk_room {
+            self.user_action_sender
+                .send(UserInputEvent::LeaveRoom(talk_room.clone()))
+                .unwrap();
+        }
+    }
+
+    /// The room name while one is being typed, and the chat message otherwise.
+    fn focused_editor(&mut self) -> &mut Editor {
+        match &mut self.room_editor {
+            Some(room_editor) if self.tab_index == TAB_IDX_SETTINGS => room_editor,
+            _ => &mut self.editor,
+        }
+    }
+
+    // Only updated once the test starts, since opening the microphone can fail.
+    fn toggle_mic_test(&mut self) {
+        self.user_action_sender
+            .send(UserInputEvent::SetMicTest(!self.mic_test))
+            .unwrap();
+    }
+
     pub fn render<B: Backend>(&self, terminal: &mut Terminal<B>) -> io::Result<bool> {
         terminal.draw(|f| render::ui(f, self)).unwrap();
         Ok(self.killed)
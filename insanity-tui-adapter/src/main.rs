@@ -1,5 +1,7 @@
-use insanity_core::user_input_event::UserInputEvent;
-use insanity_tui_adapter::{start_tui, stop_tui, AppEvent, Peer, PeerState};
+use insanity_core::{pan::Pan, user_input_event::UserInputEvent};
+use insanity_tui_adapter::{
+    start_tui, stop_tui, AppEvent, BridgeStatus, Peer, PeerState, PeerTrust,
+};
 use std::{collections::BTreeMap, error::Error};
 
 #[tokio::main]
@@ -24,11 +26,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
             PeerState::Connected("hi".to_string()),
             false,
             100,
-        ),
+        )
+        .with_rooms(vec!["music".to_string()])
+        .with_key("bmljb2xhcydzIGtleQ".to_string())
+        .with_safety_number("08123 55210 94716 30387 71642 25509".to_string()),
     );
     peers.insert(
         "randall",
-        Peer::new("randall".to_string(), None, PeerState::Disabled, true, 100),
+        Peer::new("randall".to_string(), None, PeerState::Disabled, true, 100)
+            .with_trust(PeerTrust::KeyChanged),
     );
     peers.insert(
         "neelay",
@@ -41,6 +47,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
         ),
     );
 
+    let bridges = ["https://bridge.example.com", "https://backup.example.com"].map(String::from);
+    sender.send(AppEvent::SetServer(bridges.to_vec())).unwrap();
+    sender
+        .send(AppEvent::SetBridgeStatus(
+            bridges[0].clone(),
+            BridgeStatus::Up,
+        ))
+        .unwrap();
+    sender
+        .send(AppEvent::SetBridgeStatus(
+            bridges[1].clone(),
+            BridgeStatus::Down("connection refused".to_string()),
+        ))
+        .unwrap();
+    for room in ["lobby", "music"] {
+        sender.send(AppEvent::AddRoom(room.to_string())).unwrap();
+    }
+    sender
+        .send(AppEvent::TalkRoom("lobby".to_string()))
+        .unwrap();
     for peer in peers.values() {
         sender.send(AppEvent::AddPeer(peer.clone())).unwrap();
     }
@@ -85,8 +111,54 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         .send(AppEvent::SetPeerVolume(peer_id, volume))
                         .unwrap();
                 }
+                UserInputEvent::SetPan(peer_id, pan) => {
+                    sender
+                        .send(AppEvent::SetPeerPan(
+                            peer_id,
+                            pan.map_or(Pan::Auto(0), Pan::Manual),
+                        ))
+                        .unwrap();
+                }
+                UserInputEvent::SetEqualizer(peer_id, equalizer) => {
+                    sender
+                        .send(AppEvent::SetPeerEqualizer(peer_id, equalizer))
+                        .unwrap();
+                }
+                UserInputEvent::SetMutePeer(peer_id, muted) => {
+                    sender.send(AppEvent::SetPeerMuted(peer_id, muted)).unwrap();
+                }
                 UserInputEvent::SendMessage(_message) => {}
                 UserInputEvent::SetMuteSelf(_) => todo!(),
+                UserInputEvent::SetDeafen(is_deafened) => {
+                    sender.send(AppEvent::Deafen(is_deafened)).unwrap();
+                }
+                UserInputEvent::SetRecording(is_recording) => {
+                    sender.send(AppEvent::Recording(is_recording)).unwrap();
+                }
+                UserInputEvent::SetMicTest(is_testing) => {
+                    sender.send(AppEvent::MicTest(is_testing)).unwrap();
+                }
+                UserInputEvent::SetTalkRoom(room) => {
+                    sender.send(AppEvent::TalkRoom(room)).unwrap();
+                }
+                UserInputEvent::JoinRoom(room) => {
+                    sender.send(AppEvent::AddRoom(room)).unwrap();
+                }
+                UserInputEvent::LeaveRoom(room) => {
+                    sender.send(AppEvent::RemoveRoom(room)).unwrap();
+                }
+                UserInputEvent::AllowPeer(_) => {}
+                UserInputEvent::SetPeerVerified(id, verified) => {
+                    let trust = if verified {
+                        PeerTrust::Verified
+                    } else {
+                        PeerTrust::Unverified
+                    };
+                    sender.send(AppEvent::SetPeerTrust(id, trust)).unwrap();
+                }
+                UserInputEvent::DenyPeer(id) => {
+                    sender.send(AppEvent::RemovePeer(id)).unwrap();
+                }
             }
         }
     });
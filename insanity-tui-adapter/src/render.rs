@@ -1,4 +1,4 @@
-use insanity_core::built_info;
+use insanity_core::{built_info, equalizer::EqualizerPreset, pan::Pan};
 use tui::{
     backend::Backend,
     layout::{Constraint, Direction, Layout, Rect},
@@ -10,8 +10,11 @@ use tui::{
 };
 
 use crate::{
-    App, Editor, Peer, DECREMENT_PEER_VOLUME_KEY, INCREMENT_PEER_VOLUME_KEY, MUTE_KEY,
-    TAB_IDX_CHAT, TAB_IDX_PEERS, TAB_IDX_SETTINGS, TOGGLE_PEER_DENOISE_KEY, TOGGLE_PEER_KEY,
+    App, BridgeStatus, Editor, Peer, PeerTrust, ALLOW_PEER_KEY, AUTO_PAN_KEY, CYCLE_EQUALIZER_KEY,
+    CYCLE_TALK_ROOM_KEY, DEAFEN_KEY, DECREMENT_PEER_VOLUME_KEY, DENY_PEER_KEY,
+    INCREMENT_PEER_VOLUME_KEY, JOIN_ROOM_KEY, LEAVE_ROOM_KEY, MIC_TEST_KEY, MUTE_KEY,
+    MUTE_PEER_KEY, PAN_LEFT_KEY, PAN_RIGHT_KEY, RECORD_KEY, TAB_IDX_CHAT, TAB_IDX_PEERS,
+    TAB_IDX_SETTINGS, TOGGLE_PEER_DENOISE_KEY, TOGGLE_PEER_KEY, VERIFY_PEER_KEY,
 };
 
 const BG_GRAY: Color = Color::Rgb(50, 50, 50);
@@ -76,15 +79,34 @@ fn tab_list(app: &App) -> impl Widget + use<> {
         })
         .collect();
 
+    // Shown on every tab so it's impossible to miss.
+    let block = if app.recording {
+        default_block().title(Span::styled(
+            " ● REC ",
+            Style::default().fg(COLOR_RED).add_modifier(Modifier::BOLD),
+        ))
+    } else {
+        default_block()
+    };
+
     Tabs::new(titles)
-        .block(default_block())
+        .block(block)
         .select(app.tab_index)
         .style(Style::default().fg(Color::DarkGray))
         .highlight_style(Style::default().fg(Color::LightBlue))
         .divider(Span::styled(DOT, Style::default().fg(BG_GRAY)))
 }
 
-fn peer_row<'a>(peer: &Peer, selected: bool) -> Row<'a> {
+fn pan_label(pan: &Pan) -> String {
+    match pan.position() {
+        0 => "C".to_string(),
+        position if position < 0 => format!("L{}", -position),
+        position => format!("R{}", position),
+    }
+}
+
+/// `talk_room` is given when we're in several rooms, to show which of them the peer is in.
+fn peer_row<'a>(peer: &Peer, selected: bool, talk_room: Option<&str>) -> Row<'a> {
     let style = if selected {
         Style::default().bg(SELECTED)
     } else {
@@ -93,15 +115,53 @@ fn peer_row<'a>(peer: &Peer, selected: bool) -> Row<'a> {
 
     let denoise_symbol = if peer.denoised { "🤫" } else { "🫨" };
 
-    let attributes = Cell::from(Spans::from(vec![Span::styled(
-        format!("{}", peer.volume),
+    // A locally muted peer shows the mute symbol in place of its volume.
+    let attributes = if peer.muted {
+        Cell::from("🔇")
+    } else {
+        Cell::from(Spans::from(vec![Span::styled(
+            format!("{}", peer.volume),
+            Style::default().fg(match peer.state {
+                crate::PeerState::Connected(_) => Color::White,
+                _ => Color::DarkGray,
+            }),
+        )]))
+    };
+
+    // Automatic placement is dimmed so manual placements stand out.
+    let pan = Cell::from(Span::styled(
+        pan_label(&peer.pan),
+        Style::default().fg(match (&peer.state, peer.pan.is_auto()) {
+            (crate::PeerState::Connected(_), false) => Color::White,
+            _ => Color::DarkGray,
+        }),
+    ));
+
+    let equalizer = Cell::from(Span::styled(
+        peer.equalizer.name(),
         Style::default().fg(match peer.state {
-            crate::PeerState::Connected(_) => Color::White,
+            crate::PeerState::Connected(_) if peer.equalizer != EqualizerPreset::Off => {
+                Color::White
+            }
             _ => Color::DarkGray,
         }),
-    )]));
+    ));
 
     let display_name = peer.display_name.as_ref().unwrap_or(&peer.id).to_string();
+    let rooms = match talk_room {
+        Some(_) if !peer.rooms.is_empty() => format!(" [{}]", peer.rooms.join(", ")),
+        _ => String::new(),
+    };
+    let listen_only = if peer.is_listen_only(talk_room) {
+        " listen only"
+    } else {
+        ""
+    };
+    let (trust, trust_color) = match peer.trust {
+        PeerTrust::Unverified => ("", Color::DarkGray),
+        PeerTrust::Verified => (" ✓", CONNECTED),
+        PeerTrust::KeyChanged => (" ⚠ new key for this name", COLOR_RED),
+    };
 
     match peer.state {
         crate::PeerState::Connected(ref address) => {
@@ -118,11 +178,20 @@ fn peer_row<'a>(peer: &Peer, selected: bool) -> Row<'a> {
             Row::new(vec![
                 Cell::from(denoise_symbol),
                 attributes,
+                pan,
+                equalizer,
                 Cell::from(Spans::from(vec![
                     Span::styled(display_name_with_loudness_bg, style.fg(Color::Yellow)),
                     Span::styled(display_name_normal_bg, style.fg(CONNECTED)),
+                    Span::styled(trust, style.fg(trust_color)),
                     Span::styled(" <-> ", style.fg(Color::DarkGray)),
                     Span::styled(address.clone(), style.fg(Color::Cyan)),
+                    Span::styled(rooms, style.fg(Color::DarkGray)),
+                    Span::styled(listen_only, style.fg(COLOR_YELLOW)),
+                    Span::styled(
+                        if peer.recording { " ● recording" } else { "" },
+                        style.fg(COLOR_RED),
+                    ),
                 ]))
                 .style(style),
             ])
@@ -130,12 +199,16 @@ fn peer_row<'a>(peer: &Peer, selected: bool) -> Row<'a> {
         crate::PeerState::Disconnected => Row::new(vec![
             Cell::from(denoise_symbol),
             attributes,
+            pan,
+            equalizer,
             Cell::from(display_name).style(style),
         ])
         .style(Style::default().fg(Color::DarkGray)),
         crate::PeerState::Disabled => Row::new(vec![
             Cell::from(denoise_symbol),
             attributes,
+            pan,
+            equalizer,
             Cell::from(Span::styled(
                 display_name,
                 Style::default().add_modifier(Modifier::CROSSED_OUT),
@@ -145,8 +218,11 @@ fn peer_row<'a>(peer: &Peer, selected: bool) -> Row<'a> {
         crate::PeerState::Connecting(ref address) => Row::new(vec![
             Cell::from(denoise_symbol),
             attributes,
+            pan,
+            equalizer,
             Cell::from(Spans::from(vec![
                 Span::styled(display_name, style.fg(Color::DarkGray)),
+                Span::styled(trust, style.fg(trust_color)),
                 Span::styled(" --> ", style.fg(Color::DarkGray)),
                 Span::styled(address.clone(), style.fg(Color::DarkGray)),
             ]))
@@ -170,18 +246,29 @@ fn peer_command_help_entry(key: char, help_str: &'static str) -> String {
 fn render_peer_list<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+        .constraints(
+            [
+                Constraint::Min(0),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
         .split(area);
 
-    let muted = if app.mute_self { "🔇" } else { "🔊" };
-    let you_text = if app.mute_self {
+    // Deafening stops our microphone too, so it shows as muted.
+    let sends_nothing = app.mute_self || app.deafened;
+    let muted = if sends_nothing { "🔇" } else { "🔊" };
+    let you_text = if app.deafened {
+        " (you: DEAFENED)"
+    } else if app.mute_self {
         " (you: MUTED)"
     } else {
         " (you)"
     };
 
     let name_style = Style::default().fg(Color::Magenta);
-    let name_style = if app.mute_self {
+    let name_style = if sends_nothing {
         name_style.add_modifier(Modifier::CROSSED_OUT)
     } else {
         name_style
@@ -191,6 +278,8 @@ fn render_peer_list<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
         Some(display_name) => vec![Row::new(vec![
             Cell::from(""),
             Cell::from(muted),
+            Cell::from(""),
+            Cell::from(""),
             Cell::from(Spans::from(vec![
                 Span::styled(display_name, name_style),
                 Span::styled(you_text, Style::default().fg(Color::DarkGray)),
@@ -198,11 +287,12 @@ fn render_peer_list<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
         ])],
         None => vec![],
     };
+    let talk_room = app.talk_room.as_deref().filter(|_| app.rooms.len() > 1);
     let rows: Vec<Row> = app
         .peers
         .values()
         .enumerate()
-        .map(|(i, peer)| peer_row(peer, i == app.peer_index))
+        .map(|(i, peer)| peer_row(peer, i == app.peer_index, talk_room))
         .collect();
     let rows = self_row.into_iter().chain(rows).collect::<Vec<_>>();
     let peer_list = Table::new(rows)
@@ -210,12 +300,34 @@ fn render_peer_list<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
         .widths(&[
             Constraint::Min(2),
             Constraint::Length(3),
+            Constraint::Length(4),
+            Constraint::Length(6),
             Constraint::Percentage(100),
         ])
         .column_spacing(1)
         .block(default_block());
     f.render_widget(peer_list, chunks[0]);
 
+    // The selected peer's key, to copy into `insanity room allow`, and safety number.
+    if let Some(peer) = app.peers.values().nth(app.peer_index) {
+        let mut details = vec![];
+        if let Some(key) = &peer.key {
+            details.push(Span::styled(
+                "   key ",
+                Style::default().fg(Color::DarkGray),
+            ));
+            details.push(Span::raw(key.clone()));
+        }
+        if let Some(safety_number) = &peer.safety_number {
+            details.push(Span::styled(
+                "   safety number ",
+                Style::default().fg(Color::DarkGray),
+            ));
+            details.push(Span::raw(safety_number.clone()));
+        }
+        f.render_widget(Paragraph::new(Spans::from(details)), chunks[1]);
+    }
+
     // Command help list
     let commands = [
         ('\t', "tab"),
@@ -224,6 +336,17 @@ fn render_peer_list<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
         (INCREMENT_PEER_VOLUME_KEY, "volume up"),
         (DECREMENT_PEER_VOLUME_KEY, "volume down"),
         (MUTE_KEY, "toggle self mute"),
+        (MUTE_PEER_KEY, "toggle peer mute"),
+        (DEAFEN_KEY, "toggle deafen"),
+        (RECORD_KEY, "toggle recording"),
+        (PAN_LEFT_KEY, "pan left"),
+        (PAN_RIGHT_KEY, "pan right"),
+        (AUTO_PAN_KEY, "auto pan"),
+        (CYCLE_EQUALIZER_KEY, "cycle equalizer"),
+        (CYCLE_TALK_ROOM_KEY, "cycle talk room"),
+        (ALLOW_PEER_KEY, "allow in rooms"),
+        (DENY_PEER_KEY, "block from rooms"),
+        (VERIFY_PEER_KEY, "toggle verified"),
         // (MOVE_DOWN_PEER_LIST_KEY, "move down"),
         // (MOVE_UP_PEER_LIST_KEY, "move up"),
         // (MOVE_TOP_PEER_LIST_KEY, "move to top"),
@@ -235,11 +358,16 @@ fn render_peer_list<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
         .fold("   ".to_string(), |acc, x| acc + &x);
     let commands =
         Paragraph::new(text).block(Block::default().style(Style::default().fg(Color::DarkGray)));
-    f.render_widget(commands, chunks[1]);
+    f.render_widget(commands, chunks[2]);
 }
 
 fn render_editor<'a>(editor: &'a Editor, area: &'a Rect) -> Paragraph<'a> {
     let max_text_width = area.width.saturating_sub(2) as usize;
+    Paragraph::new(vec![Spans::from(editor_spans(editor, max_text_width))])
+}
+
+/// The editor's text with the cursor highlighted, cut to fit `max_text_width`.
+fn editor_spans(editor: &Editor, max_text_width: usize) -> Vec<Span<'static>> {
     let mut remaining_width = max_text_width;
     let before_cursor: String = editor
         .buffer
@@ -260,15 +388,14 @@ fn render_editor<'a>(editor: &'a Editor, area: &'a Rect) -> Paragraph<'a> {
         .skip(editor.cursor + 1)
         .take(remaining_width)
         .collect();
-    let text = vec![Spans::from(vec![
+    vec![
         Span::raw(before_cursor),
         Span::styled(
             at_cursor,
             Style::default().fg(Color::Black).bg(Color::White),
         ),
         Span::raw(after_cursor),
-    ])];
-    Paragraph::new(text)
+    ]
 }
 
 fn hash<T: std::hash::Hash>(object: &T) -> u64 {
@@ -375,11 +502,16 @@ fn render_chat<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
 }
 
 fn render_settings<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    // Each room takes a line for its name and one for its fingerprint, and one more line
+    // is for joining another.
+    let room_lines = 2 * std::cmp::max(app.rooms.len(), 1) as u16 + 1;
+    let bridge_lines = std::cmp::max(app.servers.len(), 1) as u16;
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints(
             [
-                Constraint::Length(6),
+                Constraint::Length(3 + bridge_lines + room_lines),
+                Constraint::Length(4),
                 Constraint::Length(4),
                 Constraint::Min(0),
             ]
@@ -387,25 +519,54 @@ fn render_settings<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
         )
         .split(area);
 
-    let server_widget = Paragraph::new(vec![
-        Spans::from(vec![
-            Span::styled("Bridge servers: ", Style::default().fg(Color::DarkGray)),
+    let mut server_lines = vec![];
+    if app.servers.is_empty() {
+        server_lines.push(Spans::from(vec![Span::styled(
+            "Bridge server: none, so rooms can't be joined...".to_string(),
+            Style::default().fg(Color::DarkGray),
+        )]));
+    }
+    for server in app.servers.iter() {
+        let (status, status_color) = match app.bridge_statuses.get(server) {
+            None | Some(BridgeStatus::Connecting) => (" connecting".to_string(), Color::DarkGray),
+            Some(BridgeStatus::Up) => (" up".to_string(), CONNECTED),
+            Some(BridgeStatus::Down(error)) => (format!(" down, retrying: {error}"), COLOR_RED),
+        };
+        server_lines.push(Spans::from(vec![
+            Span::styled("Bridge server: ", Style::default().fg(Color::DarkGray)),
+            Span::styled(server.clone(), Style::default().fg(Color::LightBlue)),
+            Span::styled(status, Style::default().fg(status_color)),
+        ]));
+    }
+    if app.rooms.is_empty() {
+        server_lines.push(Spans::from(vec![Span::styled(
+            "Room: no room specified...".to_string(),
+            Style::default().fg(Color::DarkGray),
+        )]));
+        server_lines.push(Spans::from(vec![Span::styled(
+            "Room fingerprint: no room fingerprint...".to_string(),
+            Style::default().fg(Color::DarkGray),
+        )]));
+    }
+    for room in app.rooms.iter() {
+        let talking = app.talk_room.as_ref() == Some(&room.name);
+        server_lines.push(Spans::from(vec![
+            Span::styled("Room: ", Style::default().fg(Color::DarkGray)),
+            Span::styled(room.name.clone(), Style::default().fg(Color::LightBlue)),
             Span::styled(
-                app.servers.join(", "),
-                Style::default().fg(Color::LightBlue),
+                if talking {
+                    " (talking)"
+                } else {
+                    " (listening)"
+                },
+                Style::default().fg(if talking {
+                    Color::Green
+                } else {
+                    Color::DarkGray
+                }),
             ),
-        ]),
-        match app.room.as_ref() {
-            Some(room) => Spans::from(vec![
-                Span::styled("Room: ", Style::default().fg(Color::DarkGray)),
-                Span::styled(room.to_string(), Style::default().fg(Color::LightBlue)),
-            ]),
-            None => Spans::from(vec![Span::styled(
-                "Room: no room specified...".to_string(),
-                Style::default().fg(Color::DarkGray),
-            )]),
-        },
-        match app.room_fingerprint.as_ref() {
+        ]));
+        server_lines.push(match room.fingerprint.as_ref() {
             Some(room_fingerprint) => Spans::from(vec![
                 Span::styled("Room fingerprint: ", Style::default().fg(Color::DarkGray)),
                 Span::styled(
@@ -417,20 +578,40 @@ fn render_settings<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
                 "Room fingerprint: no room fingerprint...".to_string(),
                 Style::default().fg(Color::DarkGray),
             )]),
-        },
-        match app.own_public_key.as_ref() {
-            Some(key) => Spans::from(vec![
-                Span::styled("Your public key: ", Style::default().fg(Color::DarkGray)),
-                Span::styled(key.to_string(), Style::default().fg(Color::LightBlue)),
-            ]),
-            None => Spans::from(vec![Span::styled(
-                "Your public key: waiting to connect to server...".to_string(),
-                Style::default().fg(Color::DarkGray),
-            )]),
-        },
-    ])
-    .block(default_block())
-    .style(Style::default().fg(Color::White));
+        });
+    }
+    server_lines.push(match app.room_editor.as_ref() {
+        Some(room_editor) => {
+            let label = "Join room: ";
+            let max_text_width = (chunks[0].width.saturating_sub(2) as usize)
+                .saturating_sub(label.len());
+            let mut spans = vec![Span::styled(label, Style::default().fg(Color::DarkGray))];
+            spans.extend(editor_spans(room_editor, max_text_width));
+            Spans::from(spans)
+        }
+        None => Spans::from(vec![Span::styled(
+            format!(
+                "Press [{}] to join a room, [{}] to leave the talk room, [{}] to cycle the talk room",
+                char_to_readable(JOIN_ROOM_KEY),
+                char_to_readable(LEAVE_ROOM_KEY),
+                char_to_readable(CYCLE_TALK_ROOM_KEY)
+            ),
+            Style::default().fg(Color::DarkGray),
+        )]),
+    });
+    server_lines.push(match app.own_public_key.as_ref() {
+        Some(key) => Spans::from(vec![
+            Span::styled("Your public key: ", Style::default().fg(Color::DarkGray)),
+            Span::styled(key.to_string(), Style::default().fg(Color::LightBlue)),
+        ]),
+        None => Spans::from(vec![Span::styled(
+            "Your public key: waiting to connect to server...".to_string(),
+            Style::default().fg(Color::DarkGray),
+        )]),
+    });
+    let server_widget = Paragraph::new(server_lines)
+        .block(default_block())
+        .style(Style::default().fg(Color::White));
     f.render_widget(server_widget, chunks[0]);
 
     let version_widget = Paragraph::new(vec![
@@ -449,4 +630,30 @@ fn render_settings<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
     .block(default_block())
     .style(Style::default().fg(Color::White));
     f.render_widget(version_widget, chunks[1]);
+
+    let level_width = chunks[2].width.saturating_sub(2) as usize;
+    let level_length = (level_width as f64 * app.mic_test_level) as usize;
+    let mic_test_widget = Paragraph::new(vec![
+        Spans::from(vec![
+            Span::styled("Mic test: ", Style::default().fg(Color::DarkGray)),
+            if app.mic_test {
+                Span::styled(
+                    "playing back what peers would hear",
+                    Style::default().fg(Color::LightBlue),
+                )
+            } else {
+                Span::styled(
+                    format!("off, press [{}] to start", char_to_readable(MIC_TEST_KEY)),
+                    Style::default().fg(Color::DarkGray),
+                )
+            },
+        ]),
+        Spans::from(vec![Span::styled(
+            "|".repeat(level_length),
+            Style::default().fg(Color::Yellow),
+        )]),
+    ])
+    .block(default_block())
+    .style(Style::default().fg(Color::White));
+    f.render_widget(mic_test_widget, chunks[2]);
 }
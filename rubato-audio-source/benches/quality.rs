@@ -0,0 +1,45 @@
+//! Compares the CPU cost of each resampler quality preset.
+//!
+//! Run with `cargo bench -p rubato-audio-source`.
+
+use std::time::{Duration, Instant};
+
+use insanity_core::{
+    audio_source::SyncAudioSource,
+    generators::{Generator, PinkNoise},
+};
+use rubato_audio_source::{ResampledAudioSource, ResamplerQuality};
+
+const CHUNK_SIZE: usize = 480;
+const CHANNELS: u16 = 2;
+const AUDIO_DURATION: Duration = Duration::from_secs(10);
+/// Rates resampled between: the usual device rates to and from the call rate.
+const CONVERSIONS: [(u32, u32); 3] = [(44100, 48000), (48000, 44100), (16000, 48000)];
+
+fn resample(quality: ResamplerQuality, from: u32, to: u32) -> Duration {
+    let source = Generator::new(PinkNoise::new(1), from, CHANNELS).with_duration(AUDIO_DURATION);
+    let mut resampled = ResampledAudioSource::new(source, to, CHUNK_SIZE, quality);
+    let mut block = vec![0f32; CHUNK_SIZE * CHANNELS as usize];
+
+    let start = Instant::now();
+    while resampled.fill_sync(&mut block) == block.len() {}
+    start.elapsed()
+}
+
+fn main() {
+    println!(
+        "Resampling {:?} of stereo pink noise in chunks of {CHUNK_SIZE} frames.",
+        AUDIO_DURATION
+    );
+    for (from, to) in CONVERSIONS {
+        for quality in ResamplerQuality::ALL {
+            let elapsed = resample(quality, from, to);
+            println!(
+                "{from:>6} -> {to:>6} Hz  {:<8}  {:>9.2?}  {:>7.1}x real time",
+                quality.name(),
+                elapsed,
+                AUDIO_DURATION.as_secs_f64() / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
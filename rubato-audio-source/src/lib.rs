@@ -1,33 +1,208 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, fmt, str::FromStr};
 
 use insanity_core::audio_source::{AudioSource, SyncAudioSource};
-use log::trace;
-use rubato::{Resampler, SincFixedIn};
+use log::{debug, trace};
+use rubato::{FftFixedInOut, Resampler, SincFixedIn};
+use serde::Deserialize;
+
+/// Trades resampling accuracy for CPU time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResamplerQuality {
+    /// FFT-based, by far the cheapest between common rates like 44.1 and 48 kHz. Delays
+    /// audio by half a chunk more than the sinc presets, and falls back to the balanced
+    /// sinc where the ratio has to change.
+    Fast,
+    /// A short sinc, plenty for voice. The default, and much cheaper than the 256-tap
+    /// sinc always used before there were presets, which is now `High`.
+    #[default]
+    Balanced,
+    /// A long, heavily oversampled sinc, for music or offline work.
+    High,
+}
+
+const BALANCED_SINC: rubato::InterpolationParameters = rubato::InterpolationParameters {
+    sinc_len: 64,
+    f_cutoff: 0.915,
+    interpolation: rubato::InterpolationType::Linear,
+    oversampling_factor: 64,
+    window: rubato::WindowFunction::Blackman2,
+};
+
+const HIGH_SINC: rubato::InterpolationParameters = rubato::InterpolationParameters {
+    sinc_len: 256,
+    f_cutoff: 0.95,
+    interpolation: rubato::InterpolationType::Linear,
+    oversampling_factor: 256,
+    window: rubato::WindowFunction::BlackmanHarris2,
+};
+
+impl ResamplerQuality {
+    pub const ALL: [ResamplerQuality; 3] = [
+        ResamplerQuality::Fast,
+        ResamplerQuality::Balanced,
+        ResamplerQuality::High,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResamplerQuality::Fast => "fast",
+            ResamplerQuality::Balanced => "balanced",
+            ResamplerQuality::High => "high",
+        }
+    }
+}
+
+impl fmt::Display for ResamplerQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ResamplerQuality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ResamplerQuality::ALL
+            .into_iter()
+            .find(|quality| quality.name() == s)
+            .ok_or_else(|| {
+                format!("Unknown resampler quality {s}, expected fast, balanced or high.")
+            })
+    }
+}
+
+/// The resampler behind a quality preset. Both take fixed-size input chunks, but only
+/// the sinc one can change its ratio once made.
+enum Engine {
+    Sinc(SincFixedIn<f32>),
+    /// Always returns the same number of frames per chunk, so reads never come up empty
+    /// while it waits to fill an FFT.
+    Fft(Box<FftFixedInOut<f32>>),
+}
+
+impl Engine {
+    /// Makes the resampler for `quality`, along with how many output frames its first
+    /// real output frame comes after.
+    fn new(
+        quality: ResamplerQuality,
+        adjustable: bool,
+        from: u32,
+        to: u32,
+        chunk_size: usize,
+        channels: usize,
+    ) -> (Engine, usize) {
+        // rubato starts the sinc resampler half a sinc early, so its output is already
+        // lined up with its input.
+        let sinc = |parameters: rubato::InterpolationParameters| {
+            let resampler =
+                SincFixedIn::<f32>::new(to as f64 / from as f64, parameters, chunk_size, channels);
+            (Engine::Sinc(resampler), 0)
+        };
+        match quality {
+            ResamplerQuality::Fast if !adjustable => {
+                let resampler =
+                    FftFixedInOut::<f32>::new(from as usize, to as usize, chunk_size, channels);
+                // Half of one FFT, whose output is a whole chunk.
+                let delay = resampler.nbr_frames_needed() * to as usize / from as usize / 2;
+                (Engine::Fft(Box::new(resampler)), delay)
+            }
+            // Only the sinc resampler can change its ratio.
+            ResamplerQuality::Fast | ResamplerQuality::Balanced => sinc(BALANCED_SINC),
+            ResamplerQuality::High => sinc(HIGH_SINC),
+        }
+    }
+
+    /// Input frames per chunk, which for the FFT resampler may differ from what was asked
+    /// for so that each chunk is whole periods of both rates.
+    fn chunk_size(&self) -> usize {
+        match self {
+            Engine::Sinc(resampler) => resampler.nbr_frames_needed(),
+            Engine::Fft(resampler) => resampler.nbr_frames_needed(),
+        }
+    }
+
+    fn set_ratio_relative(&mut self, relative: f64) {
+        match self {
+            Engine::Sinc(resampler) => {
+                if let Err(e) = resampler.set_resample_ratio_relative(relative) {
+                    debug!("Failed to adjust resample ratio: {:?}", e);
+                }
+            }
+            Engine::Fft(_) => debug!("FFT resampler can't adjust its ratio."),
+        }
+    }
+
+    fn process(&mut self, channels: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        match self {
+            Engine::Sinc(resampler) => resampler.process(channels).unwrap(),
+            Engine::Fft(resampler) => resampler.process(channels).unwrap(),
+        }
+    }
+}
 
 pub struct ResampledAudioSource<R: AudioSource> {
-    resampler: SincFixedIn<f32>,
+    resampler: Engine,
     resampled_buffer: VecDeque<f32>,
     original_samples_buffer: VecDeque<f32>,
     delegate: R,
     sample_rate: u32,
     chunk_size: usize,
+    /// Made with `adjustable`, so even a source at the output rate goes through the
+    /// resampler in case its ratio changes.
+    adjustable: bool,
+    /// Output frames of the resampler's own delay still to be dropped, so that output lines
+    /// up with input instead of starting late.
+    frames_to_skip: usize,
+    /// Frames that have gone in and come out of the resampler, for trimming on flush.
+    frames_in: u64,
+    frames_out: u64,
+    flushed: bool,
+    /// Kept between chunks so reading from the delegate and splitting channels don't
+    /// allocate every time.
+    read_buffer: Vec<f32>,
+    channel_buffers: Vec<Vec<f32>>,
 }
 
 impl<R: AudioSource + Send + Sync> ResampledAudioSource<R> {
-    pub fn new(delegate: R, sample_rate: u32, chunk_size: usize) -> ResampledAudioSource<R> {
-        let params = rubato::InterpolationParameters {
-            sinc_len: 256,
-            f_cutoff: 0.95,
-            interpolation: rubato::InterpolationType::Linear,
-            oversampling_factor: 256,
-            window: rubato::WindowFunction::BlackmanHarris2,
-        };
-        let resampler = SincFixedIn::<f32>::new(
-            sample_rate as f64 / delegate.sample_rate() as f64,
-            params,
+    /// Resamples `delegate` to `sample_rate` in chunks of about `chunk_size` frames.
+    pub fn new(
+        delegate: R,
+        sample_rate: u32,
+        chunk_size: usize,
+        quality: ResamplerQuality,
+    ) -> ResampledAudioSource<R> {
+        Self::with_engine(delegate, sample_rate, chunk_size, quality, false)
+    }
+
+    /// Like `new`, but the ratio can be nudged later with `set_ratio_relative`, e.g. to
+    /// follow a remote clock. The fast preset falls back to a sinc resampler for this.
+    pub fn adjustable(
+        delegate: R,
+        sample_rate: u32,
+        chunk_size: usize,
+        quality: ResamplerQuality,
+    ) -> ResampledAudioSource<R> {
+        Self::with_engine(delegate, sample_rate, chunk_size, quality, true)
+    }
+
+    fn with_engine(
+        delegate: R,
+        sample_rate: u32,
+        chunk_size: usize,
+        quality: ResamplerQuality,
+        adjustable: bool,
+    ) -> ResampledAudioSource<R> {
+        let (resampler, frames_to_skip) = Engine::new(
+            quality,
+            adjustable,
+            delegate.sample_rate(),
+            sample_rate,
             chunk_size,
             delegate.channels() as usize,
         );
+        let chunk_size = resampler.chunk_size();
+        let channels = delegate.channels() as usize;
         ResampledAudioSource {
             resampler,
             resampled_buffer: VecDeque::new(),
@@ -35,69 +210,170 @@ impl<R: AudioSource + Send + Sync> ResampledAudioSource<R> {
             delegate,
             sample_rate,
             chunk_size,
+            adjustable,
+            frames_to_skip,
+            frames_in: 0,
+            frames_out: 0,
+            flushed: false,
+            read_buffer: Vec::with_capacity(chunk_size * channels),
+            channel_buffers: vec![Vec::with_capacity(chunk_size); channels],
         }
     }
 }
 
-fn separate_channels(samples: &[f32], channel_count: usize) -> Vec<Vec<f32>> {
-    let mut channels = Vec::new();
-    for _ in 0..channel_count {
-        channels.push(Vec::new());
+/// Splits whole frames of interleaved samples into one buffer per channel, replacing
+/// what the buffers held.
+fn separate_channels(samples: impl Iterator<Item = f32>, channels: &mut [Vec<f32>]) {
+    for channel in channels.iter_mut() {
+        channel.clear();
     }
-    for (i, sample) in samples.iter().enumerate() {
-        let channel = channels.get_mut(i % channel_count).unwrap();
-        channel.push(*sample);
+    let channel_count = channels.len();
+    for (i, sample) in samples.enumerate() {
+        channels[i % channel_count].push(sample);
     }
-    channels
 }
 
-fn interleave_channels(channels: &[Vec<f32>]) -> Vec<f32> {
-    let mut samples = Vec::new();
-    for i in 0..channels[0].len() {
-        for channel in channels {
-            samples.push(channel[i]);
+impl<R: AudioSource> ResampledAudioSource<R> {
+    /// Whether samples come straight from the delegate, which is at the output rate already.
+    fn passes_through(&self) -> bool {
+        !self.adjustable && self.delegate.sample_rate() == self.sample_rate
+    }
+
+    /// Scales the ratio given at construction by `relative`, from the next chunk on.
+    /// Does nothing unless made with `adjustable`.
+    pub fn set_ratio_relative(&mut self, relative: f64) {
+        self.resampler.set_ratio_relative(relative);
+    }
+
+    /// How many more original samples are needed before the next chunk can be resampled.
+    fn missing_samples(&self) -> usize {
+        let target_samples_count = self.chunk_size * self.delegate.channels() as usize;
+        trace!(
+            "Audio chunk size: {}, channels: {}, target samples count: {}",
+            self.chunk_size,
+            self.delegate.channels(),
+            target_samples_count
+        );
+        target_samples_count.saturating_sub(self.original_samples_buffer.len())
+    }
+
+    /// Resamples the last samples once the delegate has ended for good, so finite sources
+    /// like files come out whole: `input frames * ratio`, rounded, with the resampler's
+    /// delay trimmed off the start. Whatever is buffered is padded with silence to a full
+    /// chunk and pushed through along with the resampler's delay, and the padding's output
+    /// is trimmed off. The rest then comes out of `next`, `fill` and their sync versions.
+    ///
+    /// A trailing partial frame is dropped. Flushing twice, or a source that never needed
+    /// resampling, does nothing.
+    pub fn flush(&mut self) {
+        let channels = self.delegate.channels() as usize;
+        if self.passes_through() || self.flushed {
+            return;
+        }
+        self.flushed = true;
+        let partial = self.original_samples_buffer.len() % channels;
+        if partial > 0 {
+            debug!("Dropping a partial frame of {partial} samples at end of stream.");
+            self.original_samples_buffer
+                .truncate(self.original_samples_buffer.len() - partial);
+        }
+
+        let frames_in = self.frames_in + (self.original_samples_buffer.len() / channels) as u64;
+        let ratio = self.sample_rate as f64 / self.delegate.sample_rate() as f64;
+        let wanted_frames = (frames_in as f64 * ratio).round() as u64;
+        while self.frames_out < wanted_frames {
+            self.resample_buffered();
+        }
+        let excess_samples = (self.frames_out - wanted_frames) as usize * channels;
+        self.resampled_buffer
+            .truncate(self.resampled_buffer.len().saturating_sub(excess_samples));
+    }
+
+    /// Resamples the buffered original samples as one chunk, padded with silence if they
+    /// fall short, so a partial frame can never shift the channels of later frames. The
+    /// output is queued behind any not yet read, always in whole frames.
+    fn resample_buffered(&mut self) {
+        trace!(
+            "Number of samples in original buffer: {}",
+            self.original_samples_buffer.len()
+        );
+        let channel_count = self.delegate.channels() as usize;
+        let chunk_samples = self.chunk_size * channel_count;
+        if self.original_samples_buffer.len() != chunk_samples {
+            debug!(
+                "Padding {} buffered samples to a chunk of {chunk_samples}.",
+                self.original_samples_buffer.len()
+            );
+            self.original_samples_buffer.resize(chunk_samples, 0.0);
+        }
+        separate_channels(
+            self.original_samples_buffer.drain(..),
+            &mut self.channel_buffers,
+        );
+        trace!("Separated into {} channels", self.channel_buffers.len());
+        let resampled_channels = self.resampler.process(&self.channel_buffers);
+        let frames = resampled_channels[0].len();
+        let skipped = std::cmp::min(self.frames_to_skip, frames);
+        self.frames_to_skip -= skipped;
+        self.frames_in += self.chunk_size as u64;
+        self.frames_out += (frames - skipped) as u64;
+        for frame in skipped..frames {
+            for channel in resampled_channels.iter() {
+                self.resampled_buffer.push_back(channel[frame]);
+            }
+        }
+    }
+
+    /// Moves as many resampled samples as fit into `buffer`.
+    fn drain_resampled(&mut self, buffer: &mut [f32]) -> usize {
+        let count = std::cmp::min(buffer.len(), self.resampled_buffer.len());
+        for (slot, sample) in buffer.iter_mut().zip(self.resampled_buffer.drain(..count)) {
+            *slot = sample;
         }
+        count
     }
-    samples
 }
 
 impl<R: AudioSource + Send> AudioSource for ResampledAudioSource<R> {
     async fn next(&mut self) -> Option<f32> {
-        if self.delegate.sample_rate() == self.sample_rate {
+        if self.passes_through() {
             return self.delegate.next().await;
         }
-        if self.resampled_buffer.is_empty() {
+        while self.resampled_buffer.is_empty() {
             // First, try to fill the original_samples buffer with enough samples to resample
-            let target_samples_count = self.chunk_size * self.delegate.channels() as usize;
-            trace!(
-                "Audio chunk size: {}, channels: {}, target samples count: {}",
-                self.chunk_size,
-                self.delegate.channels(),
-                target_samples_count
-            );
-            if self.original_samples_buffer.len() < target_samples_count {
-                for _ in 0..(target_samples_count - self.original_samples_buffer.len()) {
-                    // ? operator returns none if there are not enough samples right now
-                    let next_sample = self.delegate.next().await?;
-                    self.original_samples_buffer.push_back(next_sample);
-                }
+            for _ in 0..self.missing_samples() {
+                // ? operator returns none if there are not enough samples right now
+                let next_sample = self.delegate.next().await?;
+                self.original_samples_buffer.push_back(next_sample);
             }
-
             // There are enough samples, so we can try to resample
-            trace!(
-                "Number of samples in original buffer: {}",
-                self.original_samples_buffer.len()
-            );
-            let samples = self.original_samples_buffer.drain(..).collect::<Vec<f32>>();
-            let channels = separate_channels(&samples, self.delegate.channels() as usize);
-            trace!("Separated into {} channels", channels.len());
-            let resampled_channels = self.resampler.process(&channels).unwrap();
-            let resampled_samples = interleave_channels(&resampled_channels);
-            self.resampled_buffer = resampled_samples.into();
+            self.resample_buffered();
         }
         self.resampled_buffer.pop_front()
     }
 
+    async fn fill(&mut self, buffer: &mut [f32]) -> usize {
+        if self.passes_through() {
+            return self.delegate.fill(buffer).await;
+        }
+        let mut filled = 0;
+        while filled < buffer.len() {
+            if self.resampled_buffer.is_empty() {
+                let missing = self.missing_samples();
+                self.read_buffer.resize(missing, 0.0);
+                let read = self.delegate.fill(&mut self.read_buffer).await;
+                self.original_samples_buffer
+                    .extend(&self.read_buffer[..read]);
+                if read < missing {
+                    break;
+                }
+                self.resample_buffered();
+            }
+            filled += self.drain_resampled(&mut buffer[filled..]);
+        }
+        filled
+    }
+
     fn sample_rate(&self) -> u32 {
         self.sample_rate
     }
@@ -109,38 +385,279 @@ impl<R: AudioSource + Send> AudioSource for ResampledAudioSource<R> {
 
 impl<R: SyncAudioSource + Send> SyncAudioSource for ResampledAudioSource<R> {
     fn next_sync(&mut self) -> Option<f32> {
-        if self.delegate.sample_rate() == self.sample_rate {
+        if self.passes_through() {
             return self.delegate.next_sync();
         }
-        if self.resampled_buffer.is_empty() {
+        while self.resampled_buffer.is_empty() {
             // First, try to fill the original_samples buffer with enough samples to resample
-            let target_samples_count = self.chunk_size * self.delegate.channels() as usize;
-            trace!(
-                "Audio chunk size: {}, channels: {}, target samples count: {}",
-                self.chunk_size,
-                self.delegate.channels(),
-                target_samples_count
-            );
-            if self.original_samples_buffer.len() < target_samples_count {
-                for _ in 0..(target_samples_count - self.original_samples_buffer.len()) {
-                    // ? operator returns none if there are not enough samples right now
-                    let next_sample = self.delegate.next_sync()?;
-                    self.original_samples_buffer.push_back(next_sample);
-                }
+            for _ in 0..self.missing_samples() {
+                // ? operator returns none if there are not enough samples right now
+                let next_sample = self.delegate.next_sync()?;
+                self.original_samples_buffer.push_back(next_sample);
             }
-
             // There are enough samples, so we can try to resample
-            trace!(
-                "Number of samples in original buffer: {}",
-                self.original_samples_buffer.len()
-            );
-            let samples = self.original_samples_buffer.drain(..).collect::<Vec<f32>>();
-            let channels = separate_channels(&samples, self.delegate.channels() as usize);
-            trace!("Separated into {} channels", channels.len());
-            let resampled_channels = self.resampler.process(&channels).unwrap();
-            let resampled_samples = interleave_channels(&resampled_channels);
-            self.resampled_buffer = resampled_samples.into();
+            self.resample_buffered();
         }
         self.resampled_buffer.pop_front()
     }
+
+    fn fill_sync(&mut self, buffer: &mut [f32]) -> usize {
+        if self.passes_through() {
+            return self.delegate.fill_sync(buffer);
+        }
+        let mut filled = 0;
+        while filled < buffer.len() {
+            if self.resampled_buffer.is_empty() {
+                let missing = self.missing_samples();
+                self.read_buffer.resize(missing, 0.0);
+                let read = self.delegate.fill_sync(&mut self.read_buffer);
+                self.original_samples_buffer
+                    .extend(&self.read_buffer[..read]);
+                if read < missing {
+                    break;
+                }
+                self.resample_buffered();
+            }
+            filled += self.drain_resampled(&mut buffer[filled..]);
+        }
+        filled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use insanity_core::generators::{Generator, Impulse, Sine};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// A finite source of interleaved samples.
+    struct Samples {
+        samples: VecDeque<f32>,
+        sample_rate: u32,
+        channels: u16,
+    }
+
+    impl Samples {
+        fn new(samples: Vec<f32>, sample_rate: u32, channels: u16) -> Samples {
+            Samples {
+                samples: samples.into(),
+                sample_rate,
+                channels,
+            }
+        }
+    }
+
+    impl AudioSource for Samples {
+        async fn next(&mut self) -> Option<f32> {
+            self.samples.pop_front()
+        }
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+        fn channels(&self) -> u16 {
+            self.channels
+        }
+    }
+
+    impl SyncAudioSource for Samples {
+        fn next_sync(&mut self) -> Option<f32> {
+            self.samples.pop_front()
+        }
+    }
+
+    /// Reads everything in blocks of `block` samples, flushing at the end.
+    fn resample_all(
+        source: Samples,
+        sample_rate: u32,
+        quality: ResamplerQuality,
+        block: usize,
+    ) -> Vec<f32> {
+        let mut resampled = ResampledAudioSource::new(source, sample_rate, 480, quality);
+        let mut buffer = vec![0f32; block];
+        let mut output = vec![];
+        loop {
+            let filled = resampled.fill_sync(&mut buffer);
+            output.extend(&buffer[..filled]);
+            if filled < block {
+                break;
+            }
+        }
+        resampled.flush();
+        loop {
+            let filled = resampled.fill_sync(&mut buffer);
+            output.extend(&buffer[..filled]);
+            if filled == 0 {
+                break;
+            }
+        }
+        output
+    }
+
+    fn rate() -> impl Strategy<Value = u32> {
+        prop::sample::select(vec![8000, 16000, 22050, 44100, 48000])
+    }
+
+    fn quality() -> impl Strategy<Value = ResamplerQuality> {
+        prop::sample::select(ResamplerQuality::ALL.to_vec())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn flushed_output_is_input_times_ratio(
+            from in rate(),
+            to in rate(),
+            channels in 1u16..=4,
+            frames in 0usize..3000,
+            quality in quality(),
+            block in 1usize..2000,
+        ) {
+            let input = (0..frames * channels as usize).map(|i| (i % 7) as f32 / 7.0).collect();
+            let output = resample_all(Samples::new(input, from, channels), to, quality, block);
+
+            prop_assert_eq!(output.len() % channels as usize, 0);
+            let expected = (frames as f64 * to as f64 / from as f64).round() as usize;
+            prop_assert_eq!(output.len() / channels as usize, expected);
+        }
+
+        #[test]
+        fn channels_stay_in_place(
+            from in prop::sample::select(vec![16000u32, 44100, 48000]),
+            to in prop::sample::select(vec![16000u32, 44100, 48000]),
+            channels in 1u16..=4,
+            frames in 6000usize..9000,
+            quality in quality(),
+            block in 1usize..2000,
+        ) {
+            // Each channel holds its own level, which resampling leaves alone.
+            let level = |channel: usize| (channel + 1) as f32 / 8.0;
+            let input = (0..frames * channels as usize)
+                .map(|i| level(i % channels as usize))
+                .collect();
+            let output = resample_all(Samples::new(input, from, channels), to, quality, block);
+
+            // The edges ring where the signal starts and stops.
+            let edge = 1000 * channels as usize;
+            for (i, sample) in output[edge..output.len() - edge].iter().enumerate() {
+                let channel = (edge + i) % channels as usize;
+                prop_assert!(
+                    (sample - level(channel)).abs() < 0.01,
+                    "channel {} was {} instead of {}", channel, sample, level(channel)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn output_lines_up_with_input() {
+        let conversions = [
+            (44100, 48000),
+            (48000, 44100),
+            (16000, 48000),
+            (48000, 16000),
+        ];
+        for quality in ResamplerQuality::ALL {
+            for (from, to) in conversions {
+                // An impulse every 100 ms, the first one at frame 0.
+                let mut source =
+                    Generator::new(Impulse::every(Duration::from_millis(100)), from, 1)
+                        .with_duration(Duration::from_secs(1));
+                let input = std::iter::from_fn(|| source.next_sync()).collect();
+                let output = resample_all(Samples::new(input, from, 1), to, quality, 480);
+
+                // The resampler's delay is trimmed, so each impulse comes out where it went in.
+                let period = to as usize / 10;
+                for i in 1..10 {
+                    let expected = i * period;
+                    let window = &output[expected - period / 2..expected + period / 2];
+                    let peak = (0..window.len())
+                        .max_by(|a, b| window[*a].total_cmp(&window[*b]))
+                        .unwrap()
+                        + expected
+                        - period / 2;
+                    // Within an input frame, which is several output frames when upsampling.
+                    let tolerance = to.div_ceil(from) as usize;
+                    assert!(
+                        peak.abs_diff(expected) <= tolerance,
+                        "{quality} {from} -> {to}: impulse at {peak} instead of {expected}"
+                    );
+                }
+            }
+        }
+    }
+
+    /// The frequency of a sine, from the interpolated times of its rising zero crossings.
+    fn measure_frequency(samples: &[f32], sample_rate: u32) -> f64 {
+        let crossings: Vec<f64> = samples
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+            .map(|(i, pair)| i as f64 + (pair[0] / (pair[0] - pair[1])) as f64)
+            .collect();
+        let cycles = (crossings.len() - 1) as f64;
+        cycles * sample_rate as f64 / (crossings[crossings.len() - 1] - crossings[0])
+    }
+
+    #[test]
+    fn resampling_keeps_the_frequency() {
+        let conversions = [
+            (44100, 48000),
+            (48000, 44100),
+            (16000, 48000),
+            (48000, 16000),
+        ];
+        for quality in ResamplerQuality::ALL {
+            for (from, to) in conversions {
+                for frequency in [100.0, 1000.0, 5000.0] {
+                    let mut source = Generator::new(Sine::new(frequency), from, 1)
+                        .with_duration(Duration::from_secs(1));
+                    let input = std::iter::from_fn(|| source.next_sync()).collect();
+                    let output = resample_all(Samples::new(input, from, 1), to, quality, 480);
+
+                    // The edges ring where the signal starts and stops.
+                    let edge = to as usize / 20;
+                    let measured = measure_frequency(&output[edge..output.len() - edge], to);
+                    assert!(
+                        (measured - frequency as f64).abs() < 0.1,
+                        "{quality} {from} -> {to}: {frequency} Hz came out at {measured} Hz"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fast_preset_keeps_producing_until_the_source_ends() {
+        let source =
+            Generator::new(Sine::new(440.0), 44100, 2).with_duration(Duration::from_secs(1));
+        let mut resampled = ResampledAudioSource::new(source, 48000, 480, ResamplerQuality::Fast);
+        let chunk_samples = resampled.chunk_size * 2;
+
+        let mut samples = 0;
+        while resampled.next_sync().is_some() {
+            samples += 1;
+        }
+        // Everything but the last, partial chunk comes out.
+        assert!(samples >= (44100 * 2 - chunk_samples) * 48000 / 44100);
+    }
+
+    #[test]
+    fn adjustable_source_follows_its_ratio_at_the_same_rate() {
+        let source =
+            Generator::new(Sine::new(440.0), 48000, 1).with_duration(Duration::from_secs(2));
+        let mut resampled =
+            ResampledAudioSource::adjustable(source, 48000, 480, ResamplerQuality::Balanced);
+        resampled.set_ratio_relative(1.004);
+
+        let mut samples = 0;
+        while resampled.next_sync().is_some() {
+            samples += 1;
+        }
+        // Stretched by 0.4%, less the last partial chunk and the resampler's delay.
+        assert!(samples > 96000 + 96000 * 3 / 1000, "only {samples} samples");
+    }
 }